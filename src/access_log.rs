@@ -0,0 +1,331 @@
+use crate::config::{AccessLogConfig, AccessLogFormat};
+use bytes::Bytes;
+use hyper::header::{HeaderMap, HeaderName, REFERER, USER_AGENT};
+use hyper::http::HeaderValue;
+use hyper::{Request, Version};
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc};
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use webdav_handler::body::Body;
+
+/// Writes a line per request in the Common/Combined Log Format or as JSON.
+///
+/// Unlike the debug log, it is written regardless of the log level. Lines are
+/// written by a thread of its own, so responses never wait for the disk
+pub struct AccessLog {
+    format: AccessLogFormat,
+    lines: mpsc::Sender<String>,
+}
+
+/// The data of a request, collected until the response body is sent
+pub struct AccessLogEntry {
+    log: Arc<AccessLog>,
+    remote_addr: Option<SocketAddr>,
+    user: Option<String>,
+    method: String,
+    uri: String,
+    version: Version,
+    user_agent: Option<String>,
+    referer: Option<String>,
+    depth: Option<String>,
+    destination: Option<String>,
+    time: SystemTime,
+    start: Instant,
+    status: u16,
+    bytes: u64,
+}
+
+/// The response body that writes the access log entry when it is sent or dropped
+pub struct LoggedBody {
+    inner: Body,
+    entry: Option<AccessLogEntry>,
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    time: String,
+    remote_addr: Option<String>,
+    user: Option<&'a str>,
+    method: &'a str,
+    path: &'a str,
+    protocol: String,
+    status: u16,
+    bytes: u64,
+    duration_ms: f64,
+    user_agent: Option<&'a str>,
+    referer: Option<&'a str>,
+    depth: Option<&'a str>,
+    destination: Option<&'a str>,
+}
+
+impl AccessLog {
+    pub fn open(config: &AccessLogConfig) -> Result<Arc<AccessLog>, String> {
+        let out: Box<dyn Write + Send> = if config.path == Path::new("-") {
+            Box::new(io::stdout())
+        } else {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&config.path)
+                .map_err(|e| {
+                    format!(
+                        "Failed to open the access log `{}`: {}",
+                        config.path.display(),
+                        e
+                    )
+                })?;
+            Box::new(file)
+        };
+        let (lines, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || write_lines(BufWriter::new(out), receiver))
+            .map_err(|e| format!("Failed to start the access log writer: {}", e))?;
+        Ok(Arc::new(AccessLog {
+            format: config.format,
+            lines,
+        }))
+    }
+
+    pub fn entry<T>(
+        self: &Arc<Self>,
+        req: &Request<T>,
+        remote_addr: Option<SocketAddr>,
+    ) -> AccessLogEntry {
+        let headers = req.headers();
+        AccessLogEntry {
+            log: self.clone(),
+            remote_addr,
+            user: None,
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            version: req.version(),
+            user_agent: header(headers, USER_AGENT),
+            referer: header(headers, REFERER),
+            depth: header(headers, HeaderName::from_static("depth")),
+            destination: header(headers, HeaderName::from_static("destination")),
+            time: SystemTime::now(),
+            start: Instant::now(),
+            status: 0,
+            bytes: 0,
+        }
+    }
+
+    fn write(&self, line: String) {
+        // Fails only if the writer thread has panicked
+        let _ = self.lines.send(line);
+    }
+}
+
+/// Writes the lines until all senders are dropped. The buffer is flushed once
+/// no more lines are queued
+fn write_lines(mut out: BufWriter<Box<dyn Write + Send>>, lines: mpsc::Receiver<String>) {
+    for line in lines.iter() {
+        let mut write = || {
+            out.write_all(line.as_bytes())?;
+            for line in lines.try_iter() {
+                out.write_all(line.as_bytes())?;
+            }
+            out.flush()
+        };
+        if let Err(e) = write() {
+            log::error!("Failed to write the access log: {}", e);
+        }
+    }
+}
+
+impl AccessLogEntry {
+    pub fn set_user(&mut self, user: &str) {
+        self.user = Some(user.to_string());
+    }
+
+    pub fn set_status(&mut self, status: u16) {
+        self.status = status;
+    }
+
+    fn format(&self) -> String {
+        let dash = |s: &Option<String>| s.clone().unwrap_or_else(|| "-".to_string());
+        let quoted = |s: &Option<String>| match s {
+            Some(s) => format!("\"{}\"", escape(s)),
+            None => "\"-\"".to_string(),
+        };
+        match self.log.format {
+            AccessLogFormat::Common | AccessLogFormat::Combined => {
+                let mut line = format!(
+                    "{} - {} [{}] \"{} {} {:?}\" {} {}",
+                    self.remote_addr
+                        .map(|addr| addr.ip().to_string())
+                        .unwrap_or_else(|| "-".to_string()),
+                    dash(&self.user.as_deref().map(escape)),
+                    clf_time(self.time),
+                    escape(&self.method),
+                    escape(&self.uri),
+                    self.version,
+                    self.status,
+                    if self.bytes == 0 {
+                        "-".to_string()
+                    } else {
+                        self.bytes.to_string()
+                    },
+                );
+                if self.log.format == AccessLogFormat::Combined {
+                    line += &format!(
+                        " {} {} {:.3} {} {}",
+                        quoted(&self.referer),
+                        quoted(&self.user_agent),
+                        self.start.elapsed().as_secs_f64() * 1000.0,
+                        quoted(&self.depth),
+                        quoted(&self.destination)
+                    );
+                }
+                line
+            }
+            AccessLogFormat::Json => {
+                let entry = JsonEntry {
+                    time: rfc3339_time(self.time),
+                    remote_addr: self.remote_addr.map(|addr| addr.ip().to_string()),
+                    user: self.user.as_deref(),
+                    method: &self.method,
+                    path: &self.uri,
+                    protocol: format!("{:?}", self.version),
+                    status: self.status,
+                    bytes: self.bytes,
+                    duration_ms: self.start.elapsed().as_secs_f64() * 1000.0,
+                    user_agent: self.user_agent.as_deref(),
+                    referer: self.referer.as_deref(),
+                    depth: self.depth.as_deref(),
+                    destination: self.destination.as_deref(),
+                };
+                serde_json::to_string(&entry).unwrap()
+            }
+        }
+    }
+}
+
+impl LoggedBody {
+    pub fn new(inner: Body, entry: Option<AccessLogEntry>) -> LoggedBody {
+        LoggedBody { inner, entry }
+    }
+}
+
+impl hyper::body::HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, io::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_data(cx);
+        if let (Poll::Ready(Some(Ok(data))), Some(entry)) = (&poll, &mut self.entry) {
+            entry.bytes += data.len() as u64;
+        }
+        poll
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, io::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    /// The body is dropped after it is sent or when the client disconnects
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            let mut line = entry.format();
+            line.push('\n');
+            entry.log.write(line);
+        }
+    }
+}
+
+fn header(headers: &HeaderMap, name: HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .map(HeaderValue::as_bytes)
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+/// Escapes quotes, backslashes and control characters, like Apache does
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// E.g. `10/Oct/2000:13:55:36 +0000`
+fn clf_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second, _) = utc_parts(time);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+/// E.g. `2000-10-10T13:55:36.123Z`
+fn rfc3339_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second, millis) = utc_parts(time);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, hour, minute, second, millis
+    )
+}
+
+/// Splits the time into year, month, day, hour, minute, second and millisecond in UTC
+fn utc_parts(time: SystemTime) -> (i64, u32, u32, u32, u32, u32, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() as i64;
+    let (days, secs_of_day) = (secs.div_euclid(86400), secs.rem_euclid(86400) as u32);
+    // Converts days since the epoch to a civil date (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
@@ -0,0 +1,178 @@
+use crate::cache::MetadataCache;
+use crate::ipfs_api_ext::IpfsClientExt;
+use crate::ipfs_client::IpfsClient;
+use crate::metadata::{DynamicLsEntry, StaticMetadata};
+use crate::{handle_error, map_path, IpfsOrIpns};
+use futures::{future, stream, FutureExt};
+use ipfs_api_backend_hyper::IpfsApi;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{
+    DavDirEntry, DavFile, DavFileSystem, DavMetaData, FsError, FsFuture, FsStream, OpenOptions,
+    ReadDirMeta,
+};
+
+/// The name of the alias directory in `/ipfs` and `/ipns`
+pub const DIR: &str = "by-name";
+
+/// How long the names are reused. Resolving an alias lists all keys or pins,
+/// and a `PROPFIND` resolves every entry of the directory it lists
+const NAMES_TTL: Duration = Duration::from_secs(5);
+
+/// Alias names and the key IDs or CIDs they refer to
+type Names = Arc<BTreeMap<String, String>>;
+
+/// Human-readable aliases: `/ipns/by-name/<key-name>` refers to
+/// `/ipns/<key-id>` of a key owned by the node, and `/ipfs/by-name/<pin-name>`
+/// refers to `/ipfs/<cid>` of a named pin (`ipfs pin add --name`).
+///
+/// Aliases are resolved by `RootFs` before looking up the namespace, so
+/// everything under an alias is served by `IpfsFs` as the path it refers to.
+/// This file system only serves the `by-name` directory itself. Paths are
+/// full WebDAV paths, e.g. `/ipns/by-name/self`.
+#[derive(Clone)]
+pub struct Aliases {
+    ipfs: IpfsClient,
+    cache: MetadataCache,
+    ty: IpfsOrIpns,
+    names: Arc<Mutex<Option<(Instant, Names)>>>,
+}
+
+impl Aliases {
+    pub fn new(ipfs: IpfsClient, cache: MetadataCache, ty: IpfsOrIpns) -> Self {
+        Aliases {
+            ipfs,
+            cache,
+            ty,
+            names: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// `true` for `/<namespace>/by-name` and `/<namespace>/by-name/`
+    pub fn is_dir(&self, path: &DavPath) -> bool {
+        matches!(self.alias_path(path), Some("" | "/"))
+    }
+
+    /// Pinned content is immutable, and the `by-name` directories only list
+    /// keys and pins, so only the content of `/ipns` aliases is writable
+    pub fn forbids_write(&self, path: &DavPath) -> bool {
+        match self.ty {
+            IpfsOrIpns::Ipfs => self.alias_path(path).is_some(),
+            IpfsOrIpns::Ipns => self.is_dir(path),
+        }
+    }
+
+    /// Maps `/<namespace>/by-name/<name>/...` to `/<namespace>/<id>/...`,
+    /// where `<id>` is the key ID or the CID. `None` if `path` is not under
+    /// an alias
+    pub async fn resolve(&self, path: &DavPath) -> Result<Option<DavPath>, FsError> {
+        let name = match self
+            .alias_path(path)
+            .and_then(|rest| rest.strip_prefix('/'))
+        {
+            Some(rest) if !rest.is_empty() => rest.split('/').next().unwrap_or_default(),
+            _ => return Ok(None),
+        };
+        let names = self.names().await?;
+        let id = names.get(name).ok_or(FsError::NotFound)?;
+        let mut resolved = [self.prefix(), id].concat();
+        // The rest is taken URL-encoded: `/`, the namespace, `by-name`, the name
+        let url = path.as_url_string();
+        if let Some(rest) = url.splitn(5, '/').nth(4) {
+            resolved += "/";
+            resolved += rest;
+        }
+        DavPath::new(&resolved)
+            .map(Some)
+            .map_err(|_| FsError::GeneralFailure)
+    }
+
+    /// The part of `path` after `/<namespace>/by-name`, if it is there
+    fn alias_path<'p>(&self, path: &'p DavPath) -> Option<&'p str> {
+        let path = map_path(path).ok()?;
+        let rest = path.strip_prefix(self.prefix())?.strip_prefix(DIR)?;
+        (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+    }
+
+    fn prefix(&self) -> &'static str {
+        match self.ty {
+            IpfsOrIpns::Ipns => "/ipns/",
+            IpfsOrIpns::Ipfs => "/ipfs/",
+        }
+    }
+
+    async fn names(&self) -> Result<Names, FsError> {
+        if let Some((fetched, names)) = &*self.names.lock().unwrap() {
+            if fetched.elapsed() < NAMES_TTL {
+                return Ok(names.clone());
+            }
+        }
+        let names = Arc::new(self.fetch_names().await?);
+        *self.names.lock().unwrap() = Some((Instant::now(), names.clone()));
+        Ok(names)
+    }
+
+    async fn fetch_names(&self) -> Result<BTreeMap<String, String>, FsError> {
+        let mut names = BTreeMap::new();
+        match self.ty {
+            IpfsOrIpns::Ipns => {
+                let keys = self.ipfs.key_list().await.map_err(handle_error)?;
+                names.extend(keys.keys.into_iter().map(|k| (k.name, k.id)));
+            }
+            IpfsOrIpns::Ipfs => {
+                // Indirect pins have no names. Names are not unique, the
+                // recursive pin with the smallest CID wins
+                for ty in ["recursive", "direct"] {
+                    let pins = self.ipfs.pin_ls_names(ty).await.map_err(handle_error)?;
+                    let pins: BTreeMap<_, _> = pins.keys.into_iter().collect();
+                    for (cid, pin) in pins {
+                        if !pin.name.is_empty() {
+                            names.entry(pin.name).or_insert(cid);
+                        }
+                    }
+                }
+            }
+        }
+        // Such a name can't be a path segment
+        names.retain(|name, _| !name.contains('/'));
+        Ok(names)
+    }
+}
+
+impl DavFileSystem for Aliases {
+    fn open<'a>(&'a self, _: &'a DavPath, _: OpenOptions) -> FsFuture<Box<dyn DavFile>> {
+        Box::pin(future::ready(Err(FsError::Forbidden)))
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        _: &'a DavPath,
+        _: ReadDirMeta,
+    ) -> FsFuture<FsStream<Box<dyn DavDirEntry>>> {
+        async move {
+            let names = self.names().await?;
+            let entries: Vec<_> = names
+                .iter()
+                .map(|(name, id)| {
+                    Box::new(DynamicLsEntry {
+                        ipfs: self.ipfs.clone(),
+                        cache: self.cache.clone(),
+                        ty: self.ty.clone(),
+                        cid: id.clone(),
+                        name: Some(name.clone()),
+                    }) as Box<dyn DavDirEntry>
+                })
+                .collect();
+            Ok(Box::pin(stream::iter(entries)) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, _: &'a DavPath) -> FsFuture<Box<dyn DavMetaData>> {
+        Box::pin(future::ready(Ok(
+            Box::new(StaticMetadata::new_dir()) as Box<dyn DavMetaData>
+        )))
+    }
+}
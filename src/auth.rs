@@ -0,0 +1,640 @@
+use crate::config::{AuthConfig, UserAccess};
+use base64::Engine;
+use hyper::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::{Request, Response, StatusCode};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use webdav_handler::body::Body;
+
+/// How long a Digest nonce stays valid
+const NONCE_LIFETIME_SECS: u64 = 300;
+/// Max number of remembered successful Basic credentials (hash verification, especially
+/// bcrypt, is too slow to be done on every request)
+const VERIFIED_CACHE_SIZE: usize = 1024;
+
+/// Authenticates requests using HTTP Basic and/or Digest authentication
+pub struct Auth {
+    realm: String,
+    /// User name -> password hash from the htpasswd file
+    basic: HashMap<String, String>,
+    /// User name -> `MD5(user:realm:password)` from the htdigest file
+    digest: HashMap<String, String>,
+    rules: HashMap<String, Arc<Access>>,
+    default_rule: Option<Arc<Access>>,
+    nonce_secret: String,
+    /// `Authorization` header value -> user name
+    verified: Mutex<HashMap<String, String>>,
+    /// Digest nonce -> the last `nc` used with it, to reject replayed requests
+    nonce_counts: Mutex<HashMap<String, u64>>,
+}
+
+/// An authenticated user
+pub struct User {
+    pub name: String,
+    pub access: Arc<Access>,
+}
+
+impl Auth {
+    pub fn load(config: &AuthConfig) -> Result<Option<Auth>, String> {
+        if config.htpasswd.is_none() && config.htdigest.is_none() {
+            return Ok(None);
+        }
+        let basic = match &config.htpasswd {
+            Some(path) => read_htpasswd(path)?,
+            None => HashMap::new(),
+        };
+        let digest = match &config.htdigest {
+            Some(path) => read_htdigest(path, &config.realm)?,
+            None => HashMap::new(),
+        };
+        let mut rules: HashMap<_, _> = config
+            .users
+            .iter()
+            .map(|(user, access)| (user.clone(), Arc::new(Access::new(access))))
+            .collect();
+        let default_rule = if config.users.is_empty() {
+            Some(Arc::new(Access::full()))
+        } else {
+            rules.remove("*")
+        };
+        Ok(Some(Auth {
+            realm: config.realm.clone(),
+            basic,
+            digest,
+            rules,
+            default_rule,
+            nonce_secret: format!("{:032x}", rand::random::<u128>()),
+            verified: Mutex::new(HashMap::new()),
+            nonce_counts: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    pub fn has_basic(&self) -> bool {
+        !self.basic.is_empty()
+    }
+
+    /// Returns the authenticated user or a `401 Unauthorized` response
+    pub fn authenticate<T>(&self, req: &Request<T>) -> Result<User, Box<Response<Body>>> {
+        let header = match req.headers().get(AUTHORIZATION).map(|h| h.to_str()) {
+            Some(Ok(header)) => header,
+            _ => return Err(self.unauthorized(false)),
+        };
+        let name = if let Some(credentials) = header.strip_prefix("Basic ") {
+            self.check_basic(header, credentials)
+        } else if let Some(params) = header.strip_prefix("Digest ") {
+            match self.check_digest(req, params) {
+                Ok(name) => Some(name),
+                Err(stale) => return Err(self.unauthorized(stale)),
+            }
+        } else {
+            None
+        };
+        let name = match name {
+            Some(name) => name,
+            None => return Err(self.unauthorized(false)),
+        };
+        let access = match self.rules.get(&name).or(self.default_rule.as_ref()) {
+            Some(access) => access.clone(),
+            None => Arc::new(Access::none()),
+        };
+        Ok(User { name, access })
+    }
+
+    fn check_basic(&self, header: &str, credentials: &str) -> Option<String> {
+        if let Some(name) = self.verified.lock().unwrap().get(header) {
+            return Some(name.clone());
+        }
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(credentials.trim())
+            .ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (name, password) = decoded.split_once(':')?;
+        let hash = self.basic.get(name)?;
+        if !verify_password(hash, password) {
+            log::warn!("Wrong password for user `{}`", name);
+            return None;
+        }
+        let mut verified = self.verified.lock().unwrap();
+        if verified.len() >= VERIFIED_CACHE_SIZE {
+            verified.clear();
+        }
+        verified.insert(header.to_string(), name.to_string());
+        Some(name.to_string())
+    }
+
+    /// Returns `Err(true)` if the nonce is stale or its `nc` was used already,
+    /// i.e. the client should retry with a new nonce and the same credentials.
+    /// Only `qop=auth` is accepted, RFC 2069 responses can't be protected from replay
+    fn check_digest<T>(&self, req: &Request<T>, params: &str) -> Result<String, bool> {
+        let params = parse_auth_params(params);
+        let param = |name: &str| params.get(name).map(String::as_str).ok_or(false);
+        let name = param("username")?;
+        let nonce = param("nonce")?;
+        let uri = param("uri")?;
+        let response = param("response")?;
+        let ha1 = self.digest.get(name).ok_or(false)?;
+        if param("realm")? != self.realm {
+            return Err(false);
+        }
+        let request_uri = req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+        if uri != request_uri {
+            return Err(false);
+        }
+        let ha2 = md5_hex(&format!("{}:{}", req.method(), uri));
+        if param("qop")? != "auth" {
+            return Err(false);
+        }
+        let nc = param("nc")?;
+        let cnonce = param("cnonce")?;
+        let expected = digest_response(ha1, nonce, nc, cnonce, &ha2);
+        if !constant_time_eq(expected.as_bytes(), response.as_bytes()) {
+            log::warn!("Wrong password for user `{}`", name);
+            return Err(false);
+        }
+        match self.check_nonce(nonce) {
+            NonceState::Valid => {}
+            NonceState::Stale => return Err(true),
+            NonceState::Invalid => return Err(false),
+        }
+        let nc = u64::from_str_radix(nc, 16).map_err(|_| false)?;
+        if !self.count_nonce(nonce, nc) {
+            return Err(true);
+        }
+        Ok(name.to_string())
+    }
+
+    /// Records `nc` as the last count of the nonce. Returns `false` if it is not
+    /// greater than the last one, i.e. the request is replayed. Forgets expired nonces
+    fn count_nonce(&self, nonce: &str, nc: u64) -> bool {
+        let now = now_secs();
+        let mut counts = self.nonce_counts.lock().unwrap();
+        counts.retain(|nonce, _| {
+            nonce_timestamp(nonce)
+                .is_some_and(|timestamp| now.saturating_sub(timestamp) <= NONCE_LIFETIME_SECS)
+        });
+        let last = counts.entry(nonce.to_string()).or_insert(0);
+        if nc <= *last {
+            return false;
+        }
+        *last = nc;
+        true
+    }
+
+    fn unauthorized(&self, stale: bool) -> Box<Response<Body>> {
+        let mut resp = Response::builder().status(StatusCode::UNAUTHORIZED);
+        if !self.digest.is_empty() {
+            resp = resp.header(
+                WWW_AUTHENTICATE,
+                format!(
+                    "Digest realm=\"{}\", qop=\"auth\", algorithm=MD5, nonce=\"{}\"{}",
+                    self.realm,
+                    self.new_nonce(),
+                    if stale { ", stale=true" } else { "" }
+                ),
+            );
+        }
+        if !self.basic.is_empty() {
+            resp = resp.header(
+                WWW_AUTHENTICATE,
+                format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm),
+            );
+        }
+        Box::new(resp.body(Body::from("401 Unauthorized\n")).unwrap())
+    }
+
+    /// The random part makes each nonce unique, so a new one is not taken for
+    /// a replay even if it is issued in the same second
+    fn new_nonce(&self) -> String {
+        let data = format!("{:x}.{:016x}", now_secs(), rand::random::<u64>());
+        format!("{}.{}", data, self.nonce_signature(&data))
+    }
+
+    fn nonce_signature(&self, data: &str) -> String {
+        md5_hex(&format!("{}:{}", data, self.nonce_secret))
+    }
+
+    fn check_nonce(&self, nonce: &str) -> NonceState {
+        let (timestamp, (data, signature)) = match (nonce_timestamp(nonce), nonce.rsplit_once('.'))
+        {
+            (Some(timestamp), Some(parts)) => (timestamp, parts),
+            _ => return NonceState::Invalid,
+        };
+        let expected = self.nonce_signature(data);
+        if !constant_time_eq(expected.as_bytes(), signature.as_bytes()) {
+            NonceState::Invalid
+        } else if now_secs().saturating_sub(timestamp) > NONCE_LIFETIME_SECS {
+            NonceState::Stale
+        } else {
+            NonceState::Valid
+        }
+    }
+}
+
+fn nonce_timestamp(nonce: &str) -> Option<u64> {
+    let (timestamp, _) = nonce.split_once('.')?;
+    u64::from_str_radix(timestamp, 16).ok()
+}
+
+/// The expected `response` of Digest authentication with `qop=auth`
+fn digest_response(ha1: &str, nonce: &str, nc: &str, cnonce: &str, ha2: &str) -> String {
+    md5_hex(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2))
+}
+
+enum NonceState {
+    Valid,
+    Stale,
+    Invalid,
+}
+
+/// Paths (in the WebDAV namespace, e.g. `/mfs/team`) a user can read or write.
+/// A rule covers the path itself and everything below it
+#[derive(Debug)]
+pub struct Access {
+    read: Vec<String>,
+    write: Vec<String>,
+}
+
+impl Access {
+    fn new(access: &UserAccess) -> Access {
+        let normalize = |rules: &Vec<String>| -> Vec<String> {
+            rules
+                .iter()
+                .map(|rule| rule.trim_end_matches('/').to_string())
+                .collect()
+        };
+        Access {
+            read: normalize(&access.read),
+            write: normalize(&access.write),
+        }
+    }
+
+    fn full() -> Access {
+        Access {
+            read: vec![],
+            write: vec![String::new()],
+        }
+    }
+
+    fn none() -> Access {
+        Access {
+            read: vec![],
+            write: vec![],
+        }
+    }
+
+    pub fn can_read(&self, path: &[u8]) -> bool {
+        self.can_write(path) || self.read.iter().any(|rule| covers(rule, path))
+    }
+
+    pub fn can_write(&self, path: &[u8]) -> bool {
+        self.write.iter().any(|rule| covers(rule, path))
+    }
+
+    /// `true` if the path is readable or it is a parent directory of a readable path.
+    /// The user can see such directories, but only entries leading to readable paths
+    pub fn can_traverse(&self, path: &[u8]) -> bool {
+        let path = trim_slash(path);
+        self.can_read(path)
+            || self.read.iter().chain(self.write.iter()).any(|rule| {
+                let rule = rule.as_bytes();
+                rule.starts_with(path) && rule.get(path.len()) == Some(&b'/')
+            })
+    }
+}
+
+fn covers(rule: &str, path: &[u8]) -> bool {
+    let rule = rule.as_bytes();
+    let path = trim_slash(path);
+    path.starts_with(rule) && (path.len() == rule.len() || path[rule.len()] == b'/')
+}
+
+fn trim_slash(path: &[u8]) -> &[u8] {
+    path.strip_suffix(b"/").unwrap_or(path)
+}
+
+fn read_htpasswd(path: &Path) -> Result<HashMap<String, String>, String> {
+    let users = read_colon_separated(path, 2)?
+        .into_iter()
+        .map(|mut fields| {
+            let hash = fields.pop().unwrap();
+            let name = fields.pop().unwrap();
+            (name, hash)
+        })
+        .collect::<HashMap<_, _>>();
+    for (name, hash) in &users {
+        if !is_supported_hash(hash) {
+            log::warn!(
+                "Unsupported password hash of user `{}` in `{}`. Supported hashes are \
+                bcrypt, MD5 (`$apr1$`) and SHA-1 (`{{SHA}}`)",
+                name,
+                path.display()
+            );
+        }
+    }
+    Ok(users)
+}
+
+fn read_htdigest(path: &Path, realm: &str) -> Result<HashMap<String, String>, String> {
+    Ok(read_colon_separated(path, 3)?
+        .into_iter()
+        .filter(|fields| fields[1] == realm)
+        .map(|mut fields| {
+            let ha1 = fields.pop().unwrap();
+            fields.pop();
+            let name = fields.pop().unwrap();
+            (name, ha1)
+        })
+        .collect())
+}
+
+fn read_colon_separated(path: &Path, fields: usize) -> Result<Vec<Vec<String>>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read `{}`: {}", path.display(), e))?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            let parsed: Vec<String> = line.trim().splitn(fields, ':').map(String::from).collect();
+            if parsed.len() == fields {
+                Ok(parsed)
+            } else {
+                Err(format!("Malformed line {} in `{}`", i + 1, path.display()))
+            }
+        })
+        .collect()
+}
+
+fn is_supported_hash(hash: &str) -> bool {
+    hash.starts_with("$2y$")
+        || hash.starts_with("$2a$")
+        || hash.starts_with("$2b$")
+        || hash.starts_with("$apr1$")
+        || hash.starts_with("{SHA}")
+}
+
+fn verify_password(hash: &str, password: &str) -> bool {
+    if hash.starts_with("$2y$") || hash.starts_with("$2a$") || hash.starts_with("$2b$") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else if let Some(salt_and_hash) = hash.strip_prefix("$apr1$") {
+        let salt = salt_and_hash.split('$').next().unwrap_or("");
+        constant_time_eq(apr1(password, salt).as_bytes(), hash.as_bytes())
+    } else if let Some(sha) = hash.strip_prefix("{SHA}") {
+        let expected =
+            base64::engine::general_purpose::STANDARD.encode(Sha1::digest(password.as_bytes()));
+        constant_time_eq(expected.as_bytes(), sha.as_bytes())
+    } else {
+        false
+    }
+}
+
+/// Apache-specific MD5-based password hash (`htpasswd -m`)
+fn apr1(password: &str, salt: &str) -> String {
+    const MAGIC: &str = "$apr1$";
+    const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let password = password.as_bytes();
+    let salt = &salt.as_bytes()[..salt.len().min(8)];
+
+    let alternate = Md5::new()
+        .chain_update(password)
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize();
+    let mut ctx = Md5::new()
+        .chain_update(password)
+        .chain_update(MAGIC)
+        .chain_update(salt);
+    for chunk in (0..password.len()).step_by(16) {
+        ctx.update(&alternate[..(password.len() - chunk).min(16)]);
+    }
+    let mut i = password.len();
+    while i > 0 {
+        if i & 1 == 1 {
+            ctx.update([0u8]);
+        } else {
+            ctx.update(&password[..1]);
+        }
+        i >>= 1;
+    }
+    let mut result = ctx.finalize();
+    for i in 0..1000 {
+        let mut ctx = Md5::new();
+        if i & 1 == 1 {
+            ctx.update(password);
+        } else {
+            ctx.update(result);
+        }
+        if i % 3 != 0 {
+            ctx.update(salt);
+        }
+        if i % 7 != 0 {
+            ctx.update(password);
+        }
+        if i & 1 == 1 {
+            ctx.update(result);
+        } else {
+            ctx.update(password);
+        }
+        result = ctx.finalize();
+    }
+
+    let mut out = format!("{}{}$", MAGIC, std::str::from_utf8(salt).unwrap_or(""));
+    let mut to64 = |mut v: u32, n: usize| {
+        for _ in 0..n {
+            out.push(ITOA64[(v & 0x3f) as usize] as char);
+            v >>= 6;
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        to64(
+            (result[a] as u32) << 16 | (result[b] as u32) << 8 | result[c] as u32,
+            4,
+        );
+    }
+    to64(result[11] as u32, 2);
+    out
+}
+
+/// Parses `key="value", key=value` pairs of an `Authorization` header
+fn parse_auth_params(params: &str) -> HashMap<String, String> {
+    let mut result = HashMap::new();
+    let mut rest = params.trim();
+    while let Some((key, value)) = rest.split_once('=') {
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim_start();
+        let (value, tail) = if let Some(quoted) = value.strip_prefix('"') {
+            match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            }
+        } else {
+            match value.find(',') {
+                Some(end) => (value[..end].trim_end(), &value[end..]),
+                None => (value.trim_end(), ""),
+            }
+        };
+        result.insert(key, value.to_string());
+        rest = tail.trim_start().trim_start_matches(',');
+    }
+    result
+}
+
+fn md5_hex(s: &str) -> String {
+    Md5::digest(s.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(read: &[&str], write: &[&str]) -> Access {
+        Access::new(&UserAccess {
+            read: read.iter().map(|rule| rule.to_string()).collect(),
+            write: write.iter().map(|rule| rule.to_string()).collect(),
+        })
+    }
+
+    fn digest_auth() -> Auth {
+        Auth {
+            realm: "testrealm@host.com".to_string(),
+            basic: HashMap::new(),
+            digest: [(
+                "Mufasa".to_string(),
+                md5_hex("Mufasa:testrealm@host.com:Circle Of Life"),
+            )]
+            .into(),
+            rules: HashMap::new(),
+            default_rule: Some(Arc::new(Access::full())),
+            nonce_secret: "secret".to_string(),
+            verified: Mutex::new(HashMap::new()),
+            nonce_counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[test]
+    fn covers_only_whole_segments() {
+        assert!(covers("/mfs/team", b"/mfs/team"));
+        assert!(covers("/mfs/team", b"/mfs/team/"));
+        assert!(covers("/mfs/team", b"/mfs/team/a.txt"));
+        assert!(!covers("/mfs/team", b"/mfs/teams"));
+        assert!(!covers("/mfs/team", b"/mfs"));
+        assert!(covers("", b"/"));
+        assert!(covers("", b"/ipfs/QmFile"));
+    }
+
+    #[test]
+    fn traverses_parents_of_permitted_paths() {
+        let access = access(&["/ipfs"], &["/mfs/team"]);
+        assert!(access.can_traverse(b"/"));
+        assert!(access.can_traverse(b"/mfs"));
+        assert!(access.can_traverse(b"/mfs/"));
+        assert!(access.can_traverse(b"/mfs/team/a.txt"));
+        assert!(!access.can_traverse(b"/mfs/other"));
+        assert!(!access.can_traverse(b"/mfs/te"));
+        assert!(!access.can_traverse(b"/ipns"));
+        assert!(access.can_read(b"/mfs/team/a.txt"));
+        assert!(!access.can_write(b"/ipfs/QmFile"));
+    }
+
+    #[test]
+    fn verifies_password_hashes() {
+        let apr1 = "$apr1$r31....$gnsoqlxyxQQ0Ot5JCwiei.";
+        assert!(verify_password(apr1, "secret"));
+        assert!(!verify_password(apr1, "Secret"));
+
+        let sha = "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=";
+        assert!(verify_password(sha, "secret"));
+        assert!(!verify_password(sha, "secret "));
+
+        let bcrypt = bcrypt::hash("secret", 4).unwrap();
+        assert!(verify_password(&bcrypt, "secret"));
+        assert!(verify_password(
+            &bcrypt.replacen("$2b$", "$2y$", 1),
+            "secret"
+        ));
+        assert!(!verify_password(&bcrypt, "wrong"));
+
+        assert!(!verify_password("secret", "secret"));
+    }
+
+    #[test]
+    fn computes_digest_response() {
+        // The example of RFC 2617, section 3.5
+        let ha1 = md5_hex("Mufasa:testrealm@host.com:Circle Of Life");
+        let ha2 = md5_hex("GET:/dir/index.html");
+        assert_eq!(
+            digest_response(
+                &ha1,
+                "dcd98b7102dd2f0e8b11d0f600bfb0c093",
+                "00000001",
+                "0a4f113b",
+                &ha2
+            ),
+            "6629fae49393a05397450978507c4ef1"
+        );
+    }
+
+    #[test]
+    fn checks_digest_qop_and_nonce_count() {
+        let auth = digest_auth();
+        let nonce = auth.new_nonce();
+        let ha1 = md5_hex("Mufasa:testrealm@host.com:Circle Of Life");
+        let ha2 = md5_hex("GET:/dir/index.html");
+        let params = |nc: &str, response: &str| {
+            format!(
+                "username=\"Mufasa\", realm=\"testrealm@host.com\", nonce=\"{}\", \
+                uri=\"/dir/index.html\", qop=auth, nc={}, cnonce=\"0a4f113b\", response=\"{}\"",
+                nonce, nc, response
+            )
+        };
+        let check = |params: &str| {
+            let req = Request::get("/dir/index.html").body(()).unwrap();
+            auth.check_digest(&req, params)
+        };
+        let response = |nc| digest_response(&ha1, &nonce, nc, "0a4f113b", &ha2);
+
+        assert_eq!(
+            check(&params("00000001", &response("00000001"))),
+            Ok("Mufasa".to_string())
+        );
+        // Replayed
+        assert_eq!(check(&params("00000001", &response("00000001"))), Err(true));
+        assert_eq!(
+            check(&params("00000002", &response("00000002"))),
+            Ok("Mufasa".to_string())
+        );
+        assert_eq!(
+            check(&params("00000003", &response("00000004"))),
+            Err(false)
+        );
+        // RFC 2069, without qop
+        let rfc2069 = md5_hex(&format!("{}:{}:{}", ha1, nonce, ha2));
+        let params = format!(
+            "username=\"Mufasa\", realm=\"testrealm@host.com\", nonce=\"{}\", \
+            uri=\"/dir/index.html\", response=\"{}\"",
+            nonce, rfc2069
+        );
+        assert_eq!(check(&params), Err(false));
+    }
+}
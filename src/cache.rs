@@ -0,0 +1,238 @@
+use crate::metadata::StaticMetadata;
+use crate::props::IpfsProps;
+use hyper::body::Bytes;
+use lru::LruCache;
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use webdav_handler::fs::FsError;
+
+/// Names and metadata of directory entries
+pub type Listing = Arc<Vec<(String, StaticMetadata)>>;
+
+/// An in-process LRU cache of `StaticMetadata`, `ipfs:` properties and
+/// directory listings.
+///
+/// Keys are IPFS paths (`/ipfs/...`, `/ipns/...`) or MFS paths prefixed with
+/// `/mfs`. `/ipfs` content is immutable, so it never expires, `/ipns` and
+/// MFS entries expire after their TTLs. MFS mutations made through this
+/// server invalidate the affected entries immediately.
+#[derive(Clone)]
+pub struct MetadataCache(Option<Arc<CacheInner>>);
+
+struct CacheInner {
+    metadata: Mutex<LruCache<String, Cached<StaticMetadata>>>,
+    ls: Mutex<LruCache<String, Cached<Listing>>>,
+    props: Mutex<LruCache<String, Cached<IpfsProps>>>,
+    ipns_ttl: Duration,
+    mfs_ttl: Duration,
+}
+
+struct Cached<T> {
+    value: T,
+    /// `None` means "never expires"
+    expires_at: Option<Instant>,
+}
+
+impl MetadataCache {
+    /// A cache of `size` entries of each kind. `0` disables caching
+    pub fn new(size: usize, ipns_ttl: Duration, mfs_ttl: Duration) -> MetadataCache {
+        MetadataCache(NonZeroUsize::new(size).map(|size| {
+            Arc::new(CacheInner {
+                metadata: Mutex::new(LruCache::new(size)),
+                ls: Mutex::new(LruCache::new(size)),
+                props: Mutex::new(LruCache::new(size)),
+                ipns_ttl,
+                mfs_ttl,
+            })
+        }))
+    }
+
+    /// Returns the cached metadata of the path or caches the result of `fetch`
+    pub async fn metadata<F>(&self, path: &str, fetch: F) -> Result<StaticMetadata, FsError>
+    where
+        F: Future<Output = Result<StaticMetadata, FsError>>,
+    {
+        match &self.0 {
+            Some(inner) => inner.get_or_fetch(&inner.metadata, path, fetch).await,
+            None => fetch.await,
+        }
+    }
+
+    /// Returns the cached entries of the directory or caches the result of `fetch`
+    pub async fn ls<F>(&self, path: &str, fetch: F) -> Result<Listing, FsError>
+    where
+        F: Future<Output = Result<Vec<(String, StaticMetadata)>, FsError>>,
+    {
+        let fetch = async { fetch.await.map(Arc::new) };
+        match &self.0 {
+            Some(inner) => inner.get_or_fetch(&inner.ls, path, fetch).await,
+            None => fetch.await,
+        }
+    }
+
+    /// Returns the cached `ipfs:` properties of the path or caches the result of `fetch`
+    pub async fn props<F>(&self, path: &str, fetch: F) -> Result<IpfsProps, FsError>
+    where
+        F: Future<Output = Result<IpfsProps, FsError>>,
+    {
+        match &self.0 {
+            Some(inner) => inner.get_or_fetch(&inner.props, path, fetch).await,
+            None => fetch.await,
+        }
+    }
+
+    /// Drops the entries of the path, its descendants and its ancestors (the
+    /// CIDs and listings of the ancestors change as well)
+    pub fn invalidate(&self, path: &str) {
+        let inner = match &self.0 {
+            Some(inner) => inner,
+            None => return,
+        };
+        let path = normalize(path);
+        let affected = |key: &str| is_ancestor(key, path) || is_ancestor(path, key);
+        invalidate_matching(&inner.metadata, affected);
+        invalidate_matching(&inner.ls, affected);
+        invalidate_matching(&inner.props, affected);
+    }
+}
+
+impl CacheInner {
+    async fn get_or_fetch<T, F>(
+        &self,
+        cache: &Mutex<LruCache<String, Cached<T>>>,
+        path: &str,
+        fetch: F,
+    ) -> Result<T, FsError>
+    where
+        T: Clone,
+        F: Future<Output = Result<T, FsError>>,
+    {
+        let key = normalize(path);
+        if let Some(value) = self.get(cache, key) {
+            return Ok(value);
+        }
+        let value = fetch.await?;
+        self.put(cache, key, value.clone());
+        Ok(value)
+    }
+
+    fn get<T: Clone>(&self, cache: &Mutex<LruCache<String, Cached<T>>>, key: &str) -> Option<T> {
+        let mut cache = cache.lock().unwrap();
+        match cache.get(key) {
+            Some(cached) if cached.expires_at.is_none_or(|t| t > Instant::now()) => {
+                Some(cached.value.clone())
+            }
+            Some(_) => {
+                cache.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn put<T>(&self, cache: &Mutex<LruCache<String, Cached<T>>>, key: &str, value: T) {
+        let ttl = if key.starts_with("/ipfs/") {
+            None
+        } else if key.starts_with("/ipns/") {
+            Some(self.ipns_ttl)
+        } else {
+            Some(self.mfs_ttl)
+        };
+        if ttl == Some(Duration::ZERO) {
+            return;
+        }
+        let expires_at = ttl.map(|ttl| Instant::now() + ttl);
+        cache
+            .lock()
+            .unwrap()
+            .put(key.to_string(), Cached { value, expires_at });
+    }
+}
+
+fn invalidate_matching<T>(cache: &Mutex<LruCache<String, Cached<T>>>, f: impl Fn(&str) -> bool) {
+    let mut cache = cache.lock().unwrap();
+    let keys: Vec<String> = cache
+        .iter()
+        .map(|(key, _)| key)
+        .filter(|key| f(key))
+        .cloned()
+        .collect();
+    for key in keys {
+        cache.pop(&key);
+    }
+}
+
+/// `true` if `ancestor` is `path` or one of its parent directories
+fn is_ancestor(ancestor: &str, path: &str) -> bool {
+    match path.strip_prefix(ancestor) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || ancestor.ends_with('/'),
+        None => false,
+    }
+}
+
+/// Strips the trailing slash, so `/mfs/dir/` and `/mfs/dir` share an entry
+fn normalize(path: &str) -> &str {
+    match path.strip_suffix('/') {
+        Some(path) if !path.is_empty() => path,
+        _ => path,
+    }
+}
+
+/// Size of the chunks `ContentCache` stores. Reads are aligned to it
+pub const CONTENT_CHUNK_SIZE: u64 = 256 * 1024;
+
+/// An in-process LRU cache of immutable file content, bounded by the total
+/// size of the chunks. A chunk is identified by the CID of the file and the
+/// offset of the chunk divided by `CONTENT_CHUNK_SIZE`
+#[derive(Clone)]
+pub struct ContentCache(Option<Arc<Mutex<ContentInner>>>);
+
+struct ContentInner {
+    chunks: LruCache<(String, u64), Bytes>,
+    used: usize,
+    capacity: usize,
+}
+
+impl ContentCache {
+    /// A cache of `capacity` bytes. `0` disables caching
+    pub fn new(capacity: usize) -> ContentCache {
+        ContentCache((capacity > 0).then(|| {
+            Arc::new(Mutex::new(ContentInner {
+                chunks: LruCache::unbounded(),
+                used: 0,
+                capacity,
+            }))
+        }))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn get(&self, cid: &str, index: u64) -> Option<Bytes> {
+        let mut inner = self.0.as_ref()?.lock().unwrap();
+        inner.chunks.get(&(cid.to_string(), index)).cloned()
+    }
+
+    pub fn put(&self, cid: &str, index: u64, chunk: Bytes) {
+        let mut inner = match &self.0 {
+            Some(inner) => inner.lock().unwrap(),
+            None => return,
+        };
+        if chunk.len() > inner.capacity {
+            return;
+        }
+        inner.used += chunk.len();
+        if let Some(old) = inner.chunks.put((cid.to_string(), index), chunk) {
+            inner.used -= old.len();
+        }
+        while inner.used > inner.capacity {
+            match inner.chunks.pop_lru() {
+                Some((_, evicted)) => inner.used -= evicted.len(),
+                None => break,
+            }
+        }
+    }
+}
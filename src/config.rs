@@ -0,0 +1,749 @@
+use crate::ipfs_client::IpfsClient;
+use crate::mounts::{overlaps, parse_prefix, parse_target};
+use clap::{Parser, ValueEnum};
+use ipfs_api_backend_hyper::TryFromUri;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// A WebDAV server for IPFS.
+///
+/// Every option can be specified (in order of precedence) on the command line, in an environment
+/// variable or in the TOML configuration file.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Args {
+    /// Path to a TOML configuration file
+    #[arg(short, long, env = "IPFS_WEBDAV_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Validate and print the effective configuration without starting the server
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// WebDAV listen address in `host:port` form [default: 127.0.0.1:4918]
+    #[arg(short, long, env = "IPFS_WEBDAV_LISTEN")]
+    listen: Option<String>,
+
+    /// URL of the IPFS HTTP RPC [default: read from `~/.ipfs/api` or http://localhost:5001]
+    #[arg(short, long, env = "IPFS_WEBDAV_API_ENDPOINT_URL")]
+    api: Option<String>,
+
+    /// Log level: `error`, `warn`, `info`, `debug` or `trace` [default: info]
+    #[arg(long, env = "IPFS_WEBDAV_LOG")]
+    log: Option<String>,
+
+    /// Forbid all modifying WebDAV methods
+    #[arg(long, env = "IPFS_WEBDAV_READ_ONLY", num_args = 0..=1, default_missing_value = "true")]
+    read_only: Option<bool>,
+
+    /// Serve `/ipfs/by-name/<pin-name>` and `/ipns/by-name/<key-name>` aliases
+    #[arg(long, env = "IPFS_WEBDAV_BY_NAME", num_args = 0..=1, default_missing_value = "true")]
+    by_name: Option<bool>,
+
+    /// Serve a namespace path at a WebDAV path prefix, e.g. `/docs=mfs:/team/docs`. Can be repeated [default: /mfs, /ipfs, /ipns and /pins]
+    #[arg(
+        long = "mount",
+        env = "IPFS_WEBDAV_MOUNTS",
+        value_name = "PREFIX=TARGET",
+        value_delimiter = ','
+    )]
+    mounts: Vec<String>,
+
+    /// Serve this MFS directory as the WebDAV root instead of the namespaces [default: disabled]
+    #[arg(long, env = "IPFS_WEBDAV_MFS_ROOT")]
+    mfs_root: Option<String>,
+
+    /// Seconds to wait after the last modification of an IPNS name before publishing it [default: 10]
+    #[arg(long, env = "IPFS_WEBDAV_IPNS_PUBLISH_DELAY")]
+    ipns_publish_delay: Option<u64>,
+
+    /// MFS directory where writable copies of IPNS names are kept [default: /.ipfs-webdav/ipns]
+    #[arg(long, env = "IPFS_WEBDAV_IPNS_STAGING_ROOT")]
+    ipns_staging_root: Option<String>,
+
+    /// Seconds to wait after the last modification of content dropped into `/ipfs` before pinning it [default: 10]
+    #[arg(long, env = "IPFS_WEBDAV_IPFS_PIN_DELAY")]
+    ipfs_pin_delay: Option<u64>,
+
+    /// MFS directory where content dropped into `/ipfs` is kept until it is pinned [default: /.ipfs-webdav/ipfs]
+    #[arg(long, env = "IPFS_WEBDAV_IPFS_STAGING_ROOT")]
+    ipfs_staging_root: Option<String>,
+
+    /// Enable Basic authentication with users from the htpasswd file
+    #[arg(long, env = "IPFS_WEBDAV_HTPASSWD")]
+    htpasswd: Option<PathBuf>,
+
+    /// Enable Digest authentication with users from the htdigest file
+    #[arg(long, env = "IPFS_WEBDAV_HTDIGEST")]
+    htdigest: Option<PathBuf>,
+
+    /// Authentication realm [default: IPFS-WebDAV]
+    #[arg(long, env = "IPFS_WEBDAV_AUTH_REALM")]
+    auth_realm: Option<String>,
+
+    /// Serve HTTPS using the PEM-encoded certificate chain
+    #[arg(long, env = "IPFS_WEBDAV_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM-encoded private key of the certificate
+    #[arg(long, env = "IPFS_WEBDAV_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Serve HTTPS on this address and plain HTTP on `--listen` [default: serve only HTTPS on `--listen`]
+    #[arg(long, env = "IPFS_WEBDAV_TLS_LISTEN")]
+    tls_listen: Option<String>,
+
+    /// Where WebDAV locks are kept [default: `file` if `--lock-file` is specified, otherwise `memory`]
+    #[arg(long, env = "IPFS_WEBDAV_LOCK_STORE")]
+    lock_store: Option<LockStore>,
+
+    /// JSON file to keep WebDAV locks in across restarts
+    #[arg(long, env = "IPFS_WEBDAV_LOCK_FILE")]
+    lock_file: Option<PathBuf>,
+
+    /// Serve Prometheus metrics at `/metrics` on this address [default: disabled]
+    #[arg(long, env = "IPFS_WEBDAV_METRICS_LISTEN")]
+    metrics_listen: Option<String>,
+
+    /// Write an access log line per request to this file, or to stdout if `-` [default: disabled]
+    #[arg(long, env = "IPFS_WEBDAV_ACCESS_LOG")]
+    access_log: Option<PathBuf>,
+
+    /// Format of the access log [default: combined]
+    #[arg(long, env = "IPFS_WEBDAV_ACCESS_LOG_FORMAT")]
+    access_log_format: Option<AccessLogFormat>,
+
+    /// How many metadata entries and directory listings are cached, `0` disables caching [default: 10000]
+    #[arg(long, env = "IPFS_WEBDAV_CACHE_SIZE")]
+    cache_size: Option<usize>,
+
+    /// Seconds to cache metadata under `/ipns` [default: 60]
+    #[arg(long, env = "IPFS_WEBDAV_CACHE_IPNS_TTL")]
+    cache_ipns_ttl: Option<u64>,
+
+    /// Seconds to cache metadata under `/mfs`, in case MFS is modified bypassing the server [default: 10]
+    #[arg(long, env = "IPFS_WEBDAV_CACHE_MFS_TTL")]
+    cache_mfs_ttl: Option<u64>,
+
+    /// MiB of `/ipfs` file content cached in memory for ranged reads, `0` disables caching [default: 64]
+    #[arg(long, env = "IPFS_WEBDAV_CACHE_CONTENT_SIZE")]
+    cache_content_size: Option<u64>,
+
+    /// MiB of a file fetched ahead of the client in concurrent 1 MiB ranged requests, `0` disables read-ahead [default: 4]
+    #[arg(long, env = "IPFS_WEBDAV_READ_AHEAD")]
+    read_ahead: Option<u64>,
+}
+
+/// The effective configuration
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    pub listen: SocketAddr,
+    /// `None` means "read from `~/.ipfs/api` or use the default"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api: Option<String>,
+    pub log: String,
+    pub read_only: bool,
+    pub by_name: bool,
+    /// If specified, this MFS directory is served as `/` bypassing `RootFs`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mfs_root: Option<String>,
+    /// MiB
+    pub read_ahead: u64,
+    /// WebDAV path prefix -> mount target, see `Mounts`
+    pub mounts: BTreeMap<String, String>,
+    pub ipfs: IpfsConfig,
+    pub ipns: IpnsConfig,
+    pub auth: AuthConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+    pub locks: LocksConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<MetricsConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_log: Option<AccessLogConfig>,
+    pub cache: CacheConfig,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct IpfsConfig {
+    pub pin_delay: u64,
+    pub staging_root: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct IpnsConfig {
+    pub publish_delay: u64,
+    pub staging_root: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AuthConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub htpasswd: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub htdigest: Option<PathBuf>,
+    pub realm: String,
+    /// User name (or `*` for any user) -> permissions
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub users: BTreeMap<String, UserAccess>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// If specified, HTTPS is served on this address and plain HTTP on `listen`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listen: Option<SocketAddr>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LocksConfig {
+    pub store: LockStore,
+    /// Always `Some` for `LockStore::File`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MetricsConfig {
+    pub listen: SocketAddr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockStore {
+    /// Locks are lost on restart
+    Memory,
+    /// Locks are kept in `locks.file`
+    File,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CacheConfig {
+    pub size: usize,
+    pub ipns_ttl: u64,
+    pub mfs_ttl: u64,
+    /// MiB
+    pub content_size: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AccessLogConfig {
+    /// `-` means stdout
+    pub path: PathBuf,
+    pub format: AccessLogFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessLogFormat {
+    /// Common Log Format
+    Common,
+    /// Combined Log Format: Common Log Format with `Referer` and `User-Agent`
+    Combined,
+    /// JSON lines with all request details
+    Json,
+}
+
+/// WebDAV paths (e.g. `/mfs/team`) a user can read or write
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserAccess {
+    pub read: Vec<String>,
+    pub write: Vec<String>,
+}
+
+/// A layer of the configuration. Unset options are taken from the next layer
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigLayer {
+    listen: Option<String>,
+    api: Option<String>,
+    log: Option<String>,
+    read_only: Option<bool>,
+    by_name: Option<bool>,
+    mfs_root: Option<String>,
+    read_ahead: Option<u64>,
+    mounts: BTreeMap<String, String>,
+    ipfs: IpfsConfigLayer,
+    ipns: IpnsConfigLayer,
+    auth: AuthConfigLayer,
+    tls: TlsConfigLayer,
+    locks: LocksConfigLayer,
+    metrics: MetricsConfigLayer,
+    access_log: AccessLogConfigLayer,
+    cache: CacheConfigLayer,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct IpfsConfigLayer {
+    pin_delay: Option<u64>,
+    staging_root: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct IpnsConfigLayer {
+    publish_delay: Option<u64>,
+    staging_root: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct AuthConfigLayer {
+    htpasswd: Option<PathBuf>,
+    htdigest: Option<PathBuf>,
+    realm: Option<String>,
+    users: BTreeMap<String, UserAccess>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct TlsConfigLayer {
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    listen: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct LocksConfigLayer {
+    store: Option<LockStore>,
+    file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct MetricsConfigLayer {
+    listen: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct AccessLogConfigLayer {
+    path: Option<PathBuf>,
+    format: Option<AccessLogFormat>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct CacheConfigLayer {
+    size: Option<usize>,
+    ipns_ttl: Option<u64>,
+    mfs_ttl: Option<u64>,
+    content_size: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid {
+        option: &'static str,
+        value: String,
+        reason: String,
+    },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => {
+                write!(f, "Failed to read config file `{}`: {}", path.display(), e)
+            }
+            ConfigError::Parse(path, e) => {
+                write!(f, "Failed to parse config file `{}`: {}", path.display(), e)
+            }
+            ConfigError::Invalid {
+                option,
+                value,
+                reason,
+            } => write!(f, "Invalid value `{}` of `{}`: {}", value, option, reason),
+        }
+    }
+}
+
+impl Config {
+    /// Merges command-line arguments and environment variables (both are
+    /// handled by `clap`) with the config file, then fills in the defaults
+    pub fn load(args: &Args) -> Result<Config, ConfigError> {
+        let file = match &args.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| ConfigError::Read(path.clone(), e))?;
+                toml::from_str(&text).map_err(|e| ConfigError::Parse(path.clone(), e))?
+            }
+            None => ConfigLayer::default(),
+        };
+        let args = ConfigLayer {
+            listen: args.listen.clone(),
+            api: args.api.clone(),
+            log: args.log.clone(),
+            read_only: args.read_only,
+            by_name: args.by_name,
+            mfs_root: args.mfs_root.clone(),
+            read_ahead: args.read_ahead,
+            mounts: args
+                .mounts
+                .iter()
+                .map(|mount| match mount.split_once('=') {
+                    Some((prefix, target)) => Ok((prefix.to_string(), target.to_string())),
+                    None => Err(ConfigError::Invalid {
+                        option: "mount",
+                        value: mount.clone(),
+                        reason: "it must be in `PREFIX=TARGET` form. For example, \
+                            `/docs=mfs:/team/docs`"
+                            .to_string(),
+                    }),
+                })
+                .collect::<Result<_, _>>()?,
+            ipfs: IpfsConfigLayer {
+                pin_delay: args.ipfs_pin_delay,
+                staging_root: args.ipfs_staging_root.clone(),
+            },
+            ipns: IpnsConfigLayer {
+                publish_delay: args.ipns_publish_delay,
+                staging_root: args.ipns_staging_root.clone(),
+            },
+            auth: AuthConfigLayer {
+                htpasswd: args.htpasswd.clone(),
+                htdigest: args.htdigest.clone(),
+                realm: args.auth_realm.clone(),
+                users: BTreeMap::new(),
+            },
+            tls: TlsConfigLayer {
+                cert: args.tls_cert.clone(),
+                key: args.tls_key.clone(),
+                listen: args.tls_listen.clone(),
+            },
+            locks: LocksConfigLayer {
+                store: args.lock_store,
+                file: args.lock_file.clone(),
+            },
+            metrics: MetricsConfigLayer {
+                listen: args.metrics_listen.clone(),
+            },
+            access_log: AccessLogConfigLayer {
+                path: args.access_log.clone(),
+                format: args.access_log_format,
+            },
+            cache: CacheConfigLayer {
+                size: args.cache_size,
+                ipns_ttl: args.cache_ipns_ttl,
+                mfs_ttl: args.cache_mfs_ttl,
+                content_size: args.cache_content_size,
+            },
+        };
+        args.merge(file).resolve()
+    }
+
+    pub fn ipfs_client(&self) -> IpfsClient {
+        match &self.api {
+            // The URL is validated in `ConfigLayer::resolve`
+            Some(api) => IpfsClient(TryFromUri::from_str(api).unwrap()),
+            None => IpfsClient(Default::default()),
+        }
+    }
+}
+
+impl ConfigLayer {
+    fn merge(self, lower: ConfigLayer) -> ConfigLayer {
+        ConfigLayer {
+            listen: self.listen.or(lower.listen),
+            api: self.api.or(lower.api),
+            log: self.log.or(lower.log),
+            read_only: self.read_only.or(lower.read_only),
+            by_name: self.by_name.or(lower.by_name),
+            mfs_root: self.mfs_root.or(lower.mfs_root),
+            read_ahead: self.read_ahead.or(lower.read_ahead),
+            mounts: if self.mounts.is_empty() {
+                lower.mounts
+            } else {
+                self.mounts
+            },
+            ipfs: IpfsConfigLayer {
+                pin_delay: self.ipfs.pin_delay.or(lower.ipfs.pin_delay),
+                staging_root: self.ipfs.staging_root.or(lower.ipfs.staging_root),
+            },
+            ipns: IpnsConfigLayer {
+                publish_delay: self.ipns.publish_delay.or(lower.ipns.publish_delay),
+                staging_root: self.ipns.staging_root.or(lower.ipns.staging_root),
+            },
+            auth: AuthConfigLayer {
+                htpasswd: self.auth.htpasswd.or(lower.auth.htpasswd),
+                htdigest: self.auth.htdigest.or(lower.auth.htdigest),
+                realm: self.auth.realm.or(lower.auth.realm),
+                users: if self.auth.users.is_empty() {
+                    lower.auth.users
+                } else {
+                    self.auth.users
+                },
+            },
+            tls: TlsConfigLayer {
+                cert: self.tls.cert.or(lower.tls.cert),
+                key: self.tls.key.or(lower.tls.key),
+                listen: self.tls.listen.or(lower.tls.listen),
+            },
+            locks: LocksConfigLayer {
+                store: self.locks.store.or(lower.locks.store),
+                file: self.locks.file.or(lower.locks.file),
+            },
+            metrics: MetricsConfigLayer {
+                listen: self.metrics.listen.or(lower.metrics.listen),
+            },
+            access_log: AccessLogConfigLayer {
+                path: self.access_log.path.or(lower.access_log.path),
+                format: self.access_log.format.or(lower.access_log.format),
+            },
+            cache: CacheConfigLayer {
+                size: self.cache.size.or(lower.cache.size),
+                ipns_ttl: self.cache.ipns_ttl.or(lower.cache.ipns_ttl),
+                mfs_ttl: self.cache.mfs_ttl.or(lower.cache.mfs_ttl),
+                content_size: self.cache.content_size.or(lower.cache.content_size),
+            },
+        }
+    }
+
+    fn resolve(self) -> Result<Config, ConfigError> {
+        let listen = match self.listen {
+            Some(listen) => parse_socket_addr("listen", listen)?,
+            None => ([127, 0, 0, 1], 4918).into(),
+        };
+        let tls = match (self.tls.cert, self.tls.key) {
+            (Some(cert), Some(key)) => Some(TlsConfig {
+                cert,
+                key,
+                listen: match self.tls.listen {
+                    Some(listen) => Some(parse_socket_addr("tls.listen", listen)?),
+                    None => None,
+                },
+            }),
+            (None, None) => None,
+            (cert, _) => {
+                return Err(ConfigError::Invalid {
+                    option: if cert.is_some() {
+                        "tls.key"
+                    } else {
+                        "tls.cert"
+                    },
+                    value: String::new(),
+                    reason: "both `tls.cert` and `tls.key` must be specified".to_string(),
+                })
+            }
+        };
+        if let Some(api) = &self.api {
+            ipfs_api_backend_hyper::IpfsClient::from_str(api).map_err(|e| {
+                ConfigError::Invalid {
+                    option: "api",
+                    value: api.clone(),
+                    reason: format!(
+                        "it must be in URL form. For example, `http://localhost:5001`. {}",
+                        e
+                    ),
+                }
+            })?;
+        }
+        let ipfs_staging_root = staging_root(
+            "ipfs.staging-root",
+            self.ipfs.staging_root,
+            "/.ipfs-webdav/ipfs",
+        )?;
+        let ipns_staging_root = staging_root(
+            "ipns.staging-root",
+            self.ipns.staging_root,
+            "/.ipfs-webdav/ipns",
+        )?;
+        if ipfs_staging_root == ipns_staging_root {
+            return Err(ConfigError::Invalid {
+                option: "ipfs.staging-root",
+                value: ipfs_staging_root,
+                reason: "it must differ from `ipns.staging-root`".to_string(),
+            });
+        }
+        for (user, access) in &self.auth.users {
+            if let Some(rule) = access
+                .read
+                .iter()
+                .chain(access.write.iter())
+                .find(|rule| !rule.starts_with('/'))
+            {
+                return Err(ConfigError::Invalid {
+                    option: "auth.users",
+                    value: rule.clone(),
+                    reason: format!(
+                        "permissions of user `{}` must be absolute WebDAV paths",
+                        user
+                    ),
+                });
+            }
+        }
+        let mfs_root = match self.mfs_root {
+            Some(root) => Some(mfs_root(root, &self.mounts, &self.auth.users)?),
+            None => None,
+        };
+        let mounts = mounts(self.mounts)?;
+        let lock_store = match (self.locks.store, &self.locks.file) {
+            (Some(store), _) => store,
+            (None, Some(_)) => LockStore::File,
+            (None, None) => LockStore::Memory,
+        };
+        if lock_store == LockStore::File && self.locks.file.is_none() {
+            return Err(ConfigError::Invalid {
+                option: "locks.store",
+                value: "file".to_string(),
+                reason: "`locks.file` must be specified".to_string(),
+            });
+        }
+        let metrics = match self.metrics.listen {
+            Some(listen) => Some(MetricsConfig {
+                listen: parse_socket_addr("metrics.listen", listen)?,
+            }),
+            None => None,
+        };
+        Ok(Config {
+            listen,
+            api: self.api,
+            log: self.log.unwrap_or_else(|| "info".to_string()),
+            read_only: self.read_only.unwrap_or(false),
+            by_name: self.by_name.unwrap_or(false),
+            mfs_root,
+            read_ahead: self.read_ahead.unwrap_or(4),
+            mounts,
+            ipfs: IpfsConfig {
+                pin_delay: self.ipfs.pin_delay.unwrap_or(10),
+                staging_root: ipfs_staging_root,
+            },
+            ipns: IpnsConfig {
+                publish_delay: self.ipns.publish_delay.unwrap_or(10),
+                staging_root: ipns_staging_root,
+            },
+            auth: AuthConfig {
+                htpasswd: self.auth.htpasswd,
+                htdigest: self.auth.htdigest,
+                realm: self.auth.realm.unwrap_or_else(|| "IPFS-WebDAV".to_string()),
+                users: self.auth.users,
+            },
+            tls,
+            locks: LocksConfig {
+                store: lock_store,
+                file: self.locks.file,
+            },
+            metrics,
+            access_log: self.access_log.path.map(|path| AccessLogConfig {
+                path,
+                format: self.access_log.format.unwrap_or(AccessLogFormat::Combined),
+            }),
+            cache: CacheConfig {
+                size: self.cache.size.unwrap_or(10000),
+                ipns_ttl: self.cache.ipns_ttl.unwrap_or(60),
+                mfs_ttl: self.cache.mfs_ttl.unwrap_or(10),
+                content_size: self.cache.content_size.unwrap_or(64),
+            },
+        })
+    }
+}
+
+fn parse_socket_addr(option: &'static str, value: String) -> Result<SocketAddr, ConfigError> {
+    SocketAddr::from_str(&value).map_err(|_| ConfigError::Invalid {
+        option,
+        value,
+        reason: "it must be in `host:port` form. For example, `127.0.0.1:4918` or \
+            `0.0.0.0:4918`"
+            .to_string(),
+    })
+}
+
+/// Validates `mfs-root`, returning it without a trailing slash (except for
+/// `/`). The mount table and per-user permissions are applied by `RootFs`,
+/// which is bypassed, so they can't be used with it
+fn mfs_root(
+    root: String,
+    mounts: &BTreeMap<String, String>,
+    users: &BTreeMap<String, UserAccess>,
+) -> Result<String, ConfigError> {
+    let invalid = |root: String, reason: &str| ConfigError::Invalid {
+        option: "mfs-root",
+        value: root,
+        reason: reason.to_string(),
+    };
+    if !root.starts_with('/') {
+        return Err(invalid(root, "it must be an absolute MFS path"));
+    }
+    if root
+        .split('/')
+        .any(|segment| segment == "." || segment == "..")
+    {
+        return Err(invalid(root, "`.` and `..` are not allowed"));
+    }
+    if !mounts.is_empty() {
+        return Err(invalid(root, "it can't be used with `mounts`"));
+    }
+    if !users.is_empty() {
+        return Err(invalid(root, "it can't be used with `auth.users`"));
+    }
+    let trimmed = root.trim_end_matches('/');
+    Ok(if trimmed.is_empty() { "/" } else { trimmed }.to_string())
+}
+
+/// Validates the mount table, defaulting to all namespaces at their names
+fn mounts(mounts: BTreeMap<String, String>) -> Result<BTreeMap<String, String>, ConfigError> {
+    if mounts.is_empty() {
+        return Ok(["mfs", "ipfs", "ipns", "pins"]
+            .into_iter()
+            .map(|ns| (["/", ns].concat(), [ns, ":"].concat()))
+            .collect());
+    }
+    let mut valid = BTreeMap::new();
+    for (prefix, target) in mounts {
+        let invalid = |value: &str, reason| ConfigError::Invalid {
+            option: "mounts",
+            value: value.to_string(),
+            reason,
+        };
+        let prefix = parse_prefix(&prefix).map_err(|reason| invalid(&prefix, reason))?;
+        parse_target(&target).map_err(|reason| invalid(&target, reason))?;
+        if let Some(other) = valid
+            .keys()
+            .find(|other: &&String| overlaps(other, &prefix))
+        {
+            return Err(invalid(
+                &prefix,
+                format!("it overlaps with mount point `{}`", other),
+            ));
+        }
+        valid.insert(prefix, target);
+    }
+    Ok(valid)
+}
+
+/// Validates a staging root option, returning it without a trailing slash
+fn staging_root(
+    option: &'static str,
+    value: Option<String>,
+    default: &str,
+) -> Result<String, ConfigError> {
+    let value = value.unwrap_or_else(|| default.to_string());
+    if !value.starts_with('/') || value.len() < 2 {
+        return Err(ConfigError::Invalid {
+            option,
+            value,
+            reason: "it must be an absolute MFS path other than `/`".to_string(),
+        });
+    }
+    Ok(value.trim_end_matches('/').to_string())
+}
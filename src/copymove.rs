@@ -0,0 +1,164 @@
+use crate::rootfs::RootFs;
+use hyper::header::{CONTENT_TYPE, LOCATION};
+use hyper::{Request, Response, StatusCode, Uri};
+use webdav_handler::body::Body;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::FsError;
+
+/// The namespaces a path can belong to
+#[derive(Clone, Copy, PartialEq)]
+enum Namespace {
+    Mfs,
+    Ipfs,
+    Ipns,
+    Other,
+}
+
+/// COPY and MOVE between namespaces. webdav-handler dispatches them on the
+/// destination only, so these rules are applied here:
+///
+/// - `/ipfs` is immutable, MOVE from or into it is refused with `403 Forbidden`
+///   and an explanation, instead of silently copying
+/// - `/ipns` is immutable except for the keys owned by the node, so MOVE from
+///   `/ipns` is only passed on if the destination is in `/ipns` as well
+///   (`IpfsFs::rename` refuses keys that are not owned)
+/// - COPY into `/ipfs/<name>` pins the CID of the source, the pinned node is
+///   reported in `Location`
+/// - uploads to `/ipfs` (see `Uploads`) are mutable until they are pinned, so
+///   COPY into them and MOVE within and between them are passed on
+///
+/// The rules apply to the namespace paths the mount table maps the paths to.
+/// Any other request is returned back to be passed to webdav-handler.
+pub async fn handle(
+    fs: &RootFs,
+    req: Request<hyper::Body>,
+) -> Result<Response<Body>, Request<hyper::Body>> {
+    let is_move = match req.method().as_str() {
+        "MOVE" => true,
+        "COPY" => false,
+        _ => return Err(req),
+    };
+    // Malformed requests are reported by webdav-handler
+    let (from, to) = match paths(&req) {
+        Some(paths) => paths,
+        None => return Err(req),
+    };
+    // Paths outside of the mount points are reported by webdav-handler as well
+    let (from_ns, to_path) = match (fs.mount_path(&from), fs.mount_path(&to)) {
+        (Some(from_path), Some(to_path)) => (namespace(&from_path), to_path),
+        _ => return Err(req),
+    };
+    let to_ns = namespace(&to_path);
+
+    if to_ns == Namespace::Ipfs {
+        let pin_destination = is_pin_destination(&to_path);
+        let into_upload = fs.is_uploading(&to) && !pin_destination;
+        let from_upload = is_move && fs.is_uploading(&from);
+        if (into_upload && !is_move) || (from_upload && (into_upload || pin_destination)) {
+            return Err(req);
+        }
+        if is_move {
+            return Ok(forbidden(
+                "/ipfs is immutable, nothing can be moved into it. \
+                COPY into /ipfs/<name> pins the source instead\n",
+            ));
+        }
+        if !pin_destination {
+            return Ok(forbidden(
+                "/ipfs is immutable, only /ipfs/<name> can be a COPY destination, \
+                which pins the source\n",
+            ));
+        }
+        return Ok(match fs.pin(&from, &to).await {
+            Ok((cid, created)) => pinned_response(fs, &cid, created),
+            Err(e) => status_response(fs_error_status(e)),
+        });
+    }
+    if is_move && from_ns == Namespace::Ipfs {
+        return Ok(forbidden(
+            "/ipfs is immutable, its content can't be moved. Use COPY instead\n",
+        ));
+    }
+    if is_move && from_ns == Namespace::Ipns && to_ns != Namespace::Ipns {
+        return Ok(forbidden(
+            "Content can't be moved out of /ipns. Use COPY and DELETE instead\n",
+        ));
+    }
+    Err(req)
+}
+
+/// `DavPath::new` percent-decodes the paths like webdav-handler does,
+/// unlike `DavPath::from_uri`
+fn paths(req: &Request<hyper::Body>) -> Option<(DavPath, DavPath)> {
+    let from = DavPath::new(req.uri().path()).ok()?;
+    let destination = req.headers().get("Destination")?.to_str().ok()?;
+    let to = DavPath::new(destination.parse::<Uri>().ok()?.path()).ok()?;
+    Some((from, to))
+}
+
+fn namespace(path: &DavPath) -> Namespace {
+    let path = path.as_bytes();
+    let is_in = |prefix: &[u8]| {
+        path.strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(b"/"))
+    };
+    if is_in(b"/mfs") {
+        Namespace::Mfs
+    } else if is_in(b"/ipfs") {
+        Namespace::Ipfs
+    } else if is_in(b"/ipns") {
+        Namespace::Ipns
+    } else {
+        Namespace::Other
+    }
+}
+
+/// `true` for `/ipfs/<name>` and `/ipfs/<name>/`
+fn is_pin_destination(path: &DavPath) -> bool {
+    match path.as_bytes().strip_prefix(b"/ipfs/") {
+        Some(name) => {
+            let name = name.strip_suffix(b"/").unwrap_or(name);
+            !name.is_empty() && !name.contains(&b'/')
+        }
+        None => false,
+    }
+}
+
+pub fn pinned_response(fs: &RootFs, cid: &str, created: bool) -> Response<Body> {
+    let status = if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::NO_CONTENT
+    };
+    Response::builder()
+        .status(status)
+        .header(LOCATION, fs.url(&["/ipfs/", cid].concat()))
+        .body(Body::empty())
+        .unwrap()
+}
+
+pub fn forbidden(reason: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(reason))
+        .unwrap()
+}
+
+/// Like webdav-handler reports `FsError`s. Shared by the requests handled
+/// before it (PROPPATCH, COPY/MOVE and the pin requests)
+pub fn fs_error_status(e: FsError) -> StatusCode {
+    match e {
+        FsError::NotFound => StatusCode::NOT_FOUND,
+        FsError::Exists => StatusCode::CONFLICT,
+        FsError::Forbidden | FsError::NotImplemented => StatusCode::FORBIDDEN,
+        FsError::IsRemote => StatusCode::BAD_GATEWAY,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub fn status_response(status: StatusCode) -> Response<Body> {
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = status;
+    resp
+}
@@ -0,0 +1,522 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::ls::{DavLock, DavLockSystem};
+use xmltree::Element;
+
+/// A lock system that keeps locks in a JSON file, so they survive restarts.
+///
+/// Every change is written to the file immediately, and the file is re-read
+/// before every operation, so changes of other instances sharing the file are
+/// seen. The instances serialize their operations with an advisory lock of
+/// `<path>.lock`. Expired locks are dropped. The semantics of the locks are the same as of
+/// `MemLs`.
+#[derive(Debug, Clone)]
+pub struct FileLs(Arc<Mutex<FileLsInner>>);
+
+#[derive(Debug)]
+struct FileLsInner {
+    path: PathBuf,
+    locks: Vec<DavLock>,
+}
+
+/// The on-disk representation of a `DavLock`
+#[derive(Serialize, Deserialize)]
+struct StoredLock {
+    token: String,
+    path: String,
+    principal: Option<String>,
+    /// XML of the `owner` element
+    owner: Option<String>,
+    /// Seconds since the Unix epoch
+    timeout_at: Option<u64>,
+    /// Seconds
+    timeout: Option<u64>,
+    shared: bool,
+    deep: bool,
+}
+
+impl FileLs {
+    pub fn open(path: PathBuf) -> Result<Box<FileLs>, String> {
+        let mut inner = FileLsInner {
+            path,
+            locks: Vec::new(),
+        };
+        inner.reload().map_err(|e| {
+            format!(
+                "Failed to read the lock file `{}`: {}",
+                inner.path.display(),
+                e
+            )
+        })?;
+        Ok(Box::new(FileLs(Arc::new(Mutex::new(inner)))))
+    }
+
+    /// Runs `f` with up-to-date locks, and saves them if `f` returns `true`.
+    /// Other instances can't change the file meanwhile. The file is small, but
+    /// taking the lock may block, so it's done off the async worker
+    fn with_locks<T>(&self, f: impl FnOnce(&mut Vec<DavLock>) -> (T, bool)) -> T {
+        tokio::task::block_in_place(|| self.with_locks_blocking(f))
+    }
+
+    fn with_locks_blocking<T>(&self, f: impl FnOnce(&mut Vec<DavLock>) -> (T, bool)) -> T {
+        let inner = &mut *self.0.lock().unwrap();
+        let _file_lock = inner.lock_file();
+        // Modification times may be too coarse to notice a write of another
+        // instance, so the file is always re-read
+        if let Err(e) = inner.reload() {
+            log::error!(
+                "Failed to read the lock file `{}`, using the locks from memory: {}",
+                inner.path.display(),
+                e
+            );
+        }
+        let now = SystemTime::now();
+        let len = inner.locks.len();
+        inner
+            .locks
+            .retain(|lock| lock.timeout_at.is_none_or(|timeout_at| timeout_at > now));
+        let expired = inner.locks.len() != len;
+
+        let (result, changed) = f(&mut inner.locks);
+        if changed || expired {
+            if let Err(e) = inner.save() {
+                log::error!(
+                    "Failed to write the lock file `{}`: {}",
+                    inner.path.display(),
+                    e
+                );
+            }
+        }
+        result
+    }
+}
+
+impl FileLsInner {
+    /// Takes an exclusive advisory lock of `<path>.lock` (the file itself is
+    /// replaced on every save), released when the returned file is closed. If
+    /// it can't be taken, the locks are only guarded within this process
+    fn lock_file(&self) -> Option<File> {
+        let mut lock_path = self.path.clone().into_os_string();
+        lock_path.push(".lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&lock_path)
+            .and_then(|file| file.lock().map(|_| file));
+        match file {
+            Ok(file) => Some(file),
+            Err(e) => {
+                log::error!(
+                    "Failed to lock `{}`: {}",
+                    Path::new(&lock_path).display(),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    fn reload(&mut self) -> io::Result<()> {
+        let text = match std::fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.locks.clear();
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let stored: Vec<StoredLock> = serde_json::from_str(&text)?;
+        self.locks = stored
+            .into_iter()
+            .filter_map(StoredLock::into_lock)
+            .collect();
+        Ok(())
+    }
+
+    /// Writes to a temporary file and renames it, so readers never see a
+    /// partially written file
+    fn save(&mut self) -> io::Result<()> {
+        let stored: Vec<StoredLock> = self.locks.iter().map(StoredLock::from_lock).collect();
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(&stored)?)?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+impl StoredLock {
+    fn from_lock(lock: &DavLock) -> StoredLock {
+        let owner = lock.owner.as_ref().and_then(|owner| {
+            let mut xml = Vec::new();
+            owner.write(&mut xml).ok()?;
+            String::from_utf8(xml).ok()
+        });
+        StoredLock {
+            token: lock.token.clone(),
+            path: lock.path.as_url_string(),
+            principal: lock.principal.clone(),
+            owner,
+            timeout_at: lock
+                .timeout_at
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|t| t.as_secs()),
+            timeout: lock.timeout.map(|t| t.as_secs()),
+            shared: lock.shared,
+            deep: lock.deep,
+        }
+    }
+
+    /// Returns `None` if the stored lock is invalid
+    fn into_lock(self) -> Option<DavLock> {
+        let owner = match self.owner {
+            Some(owner) => Some(Element::parse(owner.as_bytes()).ok()?),
+            None => None,
+        };
+        Some(DavLock {
+            token: self.token,
+            path: DavPath::new(&self.path).ok()?,
+            principal: self.principal,
+            owner,
+            timeout_at: self.timeout_at.map(|t| UNIX_EPOCH + Duration::from_secs(t)),
+            timeout: self.timeout.map(Duration::from_secs),
+            shared: self.shared,
+            deep: self.deep,
+        })
+    }
+}
+
+impl DavLockSystem for FileLs {
+    fn lock(
+        &self,
+        path: &DavPath,
+        principal: Option<&str>,
+        owner: Option<&Element>,
+        timeout: Option<Duration>,
+        shared: bool,
+        deep: bool,
+    ) -> Result<DavLock, DavLock> {
+        self.with_locks(|locks| {
+            let conflict = conflict_to_path(locks, path, None, true, &[], shared).or_else(|| {
+                deep.then(|| conflict_from_path(locks, path, None, true, &[], shared))
+                    .flatten()
+            });
+            if let Some(lock) = conflict {
+                return (Err(lock.clone()), false);
+            }
+            let lock = DavLock {
+                token: Uuid::new_v4().to_urn().to_string(),
+                path: path.clone(),
+                principal: principal.map(|s| s.to_string()),
+                owner: owner.cloned(),
+                timeout_at: timeout.map(|d| SystemTime::now() + d),
+                timeout,
+                shared,
+                deep,
+            };
+            locks.push(lock.clone());
+            (Ok(lock), true)
+        })
+    }
+
+    fn unlock(&self, path: &DavPath, token: &str) -> Result<(), ()> {
+        self.with_locks(|locks| match lookup_lock(locks, path, token) {
+            Some(idx) => {
+                locks.remove(idx);
+                (Ok(()), true)
+            }
+            None => (Err(()), false),
+        })
+    }
+
+    fn refresh(
+        &self,
+        path: &DavPath,
+        token: &str,
+        timeout: Option<Duration>,
+    ) -> Result<DavLock, ()> {
+        self.with_locks(|locks| match lookup_lock(locks, path, token) {
+            Some(idx) => {
+                let lock = &mut locks[idx];
+                lock.timeout = timeout;
+                lock.timeout_at = timeout.map(|d| SystemTime::now() + d);
+                (Ok(lock.clone()), true)
+            }
+            None => (Err(()), false),
+        })
+    }
+
+    fn check(
+        &self,
+        path: &DavPath,
+        principal: Option<&str>,
+        ignore_principal: bool,
+        deep: bool,
+        submitted_tokens: Vec<&str>,
+    ) -> Result<(), DavLock> {
+        self.with_locks(|locks| {
+            let conflict = conflict_to_path(
+                locks,
+                path,
+                principal,
+                ignore_principal,
+                &submitted_tokens,
+                false,
+            )
+            .or_else(|| {
+                deep.then(|| {
+                    conflict_from_path(
+                        locks,
+                        path,
+                        principal,
+                        ignore_principal,
+                        &submitted_tokens,
+                        false,
+                    )
+                })
+                .flatten()
+            });
+            (conflict.cloned(), false)
+        })
+        .map_or(Ok(()), Err)
+    }
+
+    fn discover(&self, path: &DavPath) -> Vec<DavLock> {
+        self.with_locks(|locks| {
+            let segs = path_to_segs(path);
+            let found = locks
+                .iter()
+                .filter(|lock| segs.starts_with(&path_to_segs(&lock.path)))
+                .cloned()
+                .collect();
+            (found, false)
+        })
+    }
+
+    fn delete(&self, path: &DavPath) -> Result<(), ()> {
+        self.with_locks(|locks| {
+            let segs = path_to_segs(path);
+            let len = locks.len();
+            locks.retain(|lock| !path_to_segs(&lock.path).starts_with(&segs));
+            (Ok(()), locks.len() != len)
+        })
+    }
+}
+
+/// Returns a lock on the path or its parents that conflicts with the request
+fn conflict_to_path<'a>(
+    locks: &'a [DavLock],
+    path: &DavPath,
+    principal: Option<&str>,
+    ignore_principal: bool,
+    submitted_tokens: &[&str],
+    shared_ok: bool,
+) -> Option<&'a DavLock> {
+    let segs = path_to_segs(path);
+    let mut holds_lock = false;
+    let mut first_lock_seen: Option<&DavLock> = None;
+    for lock in locks {
+        let lock_segs = path_to_segs(&lock.path);
+        if !segs.starts_with(&lock_segs) || (lock_segs.len() < segs.len() && !lock.deep) {
+            continue;
+        }
+        if submitted_tokens.contains(&lock.token.as_str())
+            && (ignore_principal || principal == lock.principal.as_deref())
+        {
+            holds_lock = true;
+        } else if !lock.shared {
+            return Some(lock);
+        } else if !shared_ok {
+            first_lock_seen.get_or_insert(lock);
+        }
+    }
+    first_lock_seen.filter(|_| !holds_lock)
+}
+
+/// Returns a lock on the path or below it that conflicts with the request
+fn conflict_from_path<'a>(
+    locks: &'a [DavLock],
+    path: &DavPath,
+    principal: Option<&str>,
+    ignore_principal: bool,
+    submitted_tokens: &[&str],
+    shared_ok: bool,
+) -> Option<&'a DavLock> {
+    let segs = path_to_segs(path);
+    for lock in locks {
+        if !path_to_segs(&lock.path).starts_with(&segs) || (lock.shared && shared_ok) {
+            continue;
+        }
+        if !submitted_tokens.contains(&lock.token.as_str())
+            || (!ignore_principal && principal != lock.principal.as_deref())
+        {
+            return Some(lock);
+        }
+    }
+    None
+}
+
+/// Finds the lock with the `token` on the path or its parents
+fn lookup_lock(locks: &[DavLock], path: &DavPath, token: &str) -> Option<usize> {
+    let segs = path_to_segs(path);
+    locks
+        .iter()
+        .position(|lock| lock.token == token && segs.starts_with(&path_to_segs(&lock.path)))
+}
+
+fn path_to_segs(path: &DavPath) -> Vec<&[u8]> {
+    path.as_bytes()
+        .split(|&c| c == b'/')
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A lock system on a new file, removed when dropped
+    struct TestLs {
+        ls: Box<FileLs>,
+        path: PathBuf,
+    }
+
+    impl TestLs {
+        fn new() -> TestLs {
+            let path = std::env::temp_dir().join(format!("ipfs-webdav-{}.json", Uuid::new_v4()));
+            TestLs {
+                ls: FileLs::open(path.clone()).unwrap(),
+                path,
+            }
+        }
+    }
+
+    impl Drop for TestLs {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+            let mut lock_path = self.path.clone().into_os_string();
+            lock_path.push(".lock");
+            let _ = std::fs::remove_file(lock_path);
+        }
+    }
+
+    fn path(path: &str) -> DavPath {
+        DavPath::new(path).unwrap()
+    }
+
+    /// The token of the new lock, or of the conflicting one
+    fn lock(ls: &FileLs, p: &str, shared: bool, deep: bool) -> Result<String, String> {
+        ls.lock(&path(p), Some("alice"), None, None, shared, deep)
+            .map(|lock| lock.token)
+            .map_err(|lock| lock.token)
+    }
+
+    #[test]
+    fn exclusive_locks_conflict() {
+        let t = TestLs::new();
+        let first = lock(&t.ls, "/a", false, false).unwrap();
+        assert_eq!(lock(&t.ls, "/a", false, false).unwrap_err(), first);
+        assert_eq!(lock(&t.ls, "/a", true, false).unwrap_err(), first);
+        // A shallow lock doesn't cover the children
+        assert!(lock(&t.ls, "/a/b", false, false).is_ok());
+    }
+
+    #[test]
+    fn shared_locks_coexist() {
+        let t = TestLs::new();
+        let first = lock(&t.ls, "/a", true, false).unwrap();
+        assert!(lock(&t.ls, "/a", true, false).is_ok());
+        assert_eq!(lock(&t.ls, "/a", false, false).unwrap_err(), first);
+    }
+
+    #[test]
+    fn deep_locks_cover_descendants() {
+        let t = TestLs::new();
+        let deep = lock(&t.ls, "/a", false, true).unwrap();
+        assert_eq!(lock(&t.ls, "/a/b/c", false, false).unwrap_err(), deep);
+        assert!(lock(&t.ls, "/ab", false, false).is_ok());
+
+        let t = TestLs::new();
+        let child = lock(&t.ls, "/a/b", false, false).unwrap();
+        assert_eq!(lock(&t.ls, "/a", false, true).unwrap_err(), child);
+        assert!(lock(&t.ls, "/a", false, false).is_ok());
+    }
+
+    #[test]
+    fn check_requires_the_token_and_principal() {
+        let t = TestLs::new();
+        let token = lock(&t.ls, "/a", false, true).unwrap();
+        let token = token.as_str();
+        let check = |p: &str, principal, tokens| {
+            t.ls.check(&path(p), principal, false, false, tokens)
+                .is_ok()
+        };
+        assert!(!check("/a/b", Some("alice"), vec![]));
+        assert!(check("/a/b", Some("alice"), vec![token]));
+        assert!(!check("/a/b", Some("bob"), vec![token]));
+        assert!(check("/b", Some("bob"), vec![]));
+        assert!(t
+            .ls
+            .check(&path("/a"), Some("bob"), true, false, vec![token])
+            .is_ok());
+        // A lock below the path only conflicts with a deep check
+        assert!(t.ls.check(&path("/"), None, false, false, vec![]).is_ok());
+        assert!(t.ls.check(&path("/"), None, false, true, vec![]).is_err());
+    }
+
+    #[test]
+    fn unlock_and_refresh() {
+        let t = TestLs::new();
+        let token = lock(&t.ls, "/a", false, true).unwrap();
+        assert!(t.ls.unlock(&path("/b"), &token).is_err());
+        let refreshed =
+            t.ls.refresh(&path("/a/b"), &token, Some(Duration::from_secs(60)))
+                .unwrap();
+        assert_eq!(refreshed.timeout, Some(Duration::from_secs(60)));
+        assert!(t.ls.unlock(&path("/a"), &token).is_ok());
+        assert!(t.ls.discover(&path("/a")).is_empty());
+    }
+
+    #[test]
+    fn expired_locks_are_dropped() {
+        let t = TestLs::new();
+        t.ls.lock(&path("/a"), None, None, Some(Duration::ZERO), false, false)
+            .unwrap();
+        assert!(t.ls.discover(&path("/a")).is_empty());
+        assert!(lock(&t.ls, "/a", false, false).is_ok());
+    }
+
+    #[test]
+    fn instances_share_the_file() {
+        let t = TestLs::new();
+        let other = FileLs::open(t.path.clone()).unwrap();
+        let owner = Element::parse(r#"<owner xmlns="DAV:">alice</owner>"#.as_bytes()).unwrap();
+        let first =
+            t.ls.lock(
+                &path("/a b"),
+                Some("alice"),
+                Some(&owner),
+                None,
+                false,
+                true,
+            )
+            .unwrap();
+        let discovered = other.discover(&path("/a b/c"));
+        assert_eq!(discovered.len(), 1);
+        assert_eq!(discovered[0].token, first.token);
+        assert_eq!(discovered[0].principal.as_deref(), Some("alice"));
+        assert_eq!(discovered[0].owner, Some(owner));
+        assert!(discovered[0].deep);
+        assert_eq!(lock(&other, "/a b", false, false).unwrap_err(), first.token);
+
+        other.delete(&path("/a b")).unwrap();
+        assert!(lock(&t.ls, "/a b", false, false).is_ok());
+    }
+}
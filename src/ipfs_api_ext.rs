@@ -1,9 +1,13 @@
+use crate::ipfs_client::IpfsClient;
 use bytes::Bytes;
-use futures::{future, FutureExt, Stream};
+use futures::future::BoxFuture;
+use futures::{future, FutureExt, Stream, TryStreamExt};
 use ipfs_api_backend_hyper::request::ApiRequest;
-use ipfs_api_backend_hyper::{Error, IpfsClient};
-use ipfs_api_prelude::Backend;
-use serde::Serialize;
+use ipfs_api_backend_hyper::Error;
+use ipfs_api_prelude::{request, Backend};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub trait IpfsClientExt {
     fn cat_with_options(
@@ -19,6 +23,58 @@ pub trait IpfsClientExt {
         offset: Option<i64>,
         length: Option<i64>,
     ) -> Box<dyn Stream<Item = Result<Bytes, Error>> + Send + Unpin>;
+
+    /// Like `files_stat`, but also returns the UnixFS 1.5 metadata if the node reports it
+    fn files_stat_full<'a>(
+        &'a self,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<FilesStatResponse, Error>>;
+
+    /// Like `ls`, but also returns the UnixFS 1.5 mode of the links if the node reports it
+    fn ls_full<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<LsFullResponse, Error>>;
+
+    /// Like `files_ls` with `long`, but also returns the UnixFS 1.5 mode of the entries if
+    /// the node reports it
+    fn files_ls_full<'a>(
+        &'a self,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<FilesLsFullResponse, Error>>;
+
+    fn files_touch<'a>(
+        &'a self,
+        path: &'a str,
+        mtime: SystemTime,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    fn files_chmod<'a>(&'a self, path: &'a str, mode: u32) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Like `pin_ls` without a key, but also returns the names of the pins
+    fn pin_ls_names<'a>(&'a self, typ: &'a str)
+        -> BoxFuture<'a, Result<PinLsNamesResponse, Error>>;
+
+    fn pin_remote_service_ls(&self) -> BoxFuture<'_, Result<PinRemoteServiceLsResponse, Error>>;
+
+    /// Remote pins of the `service` in any state, optionally only of the `cid`
+    fn pin_remote_ls<'a>(
+        &'a self,
+        service: &'a str,
+        cid: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Vec<RemotePin>, Error>>;
+
+    /// Queues pinning of the `path` by the `service` without waiting for it
+    fn pin_remote_add<'a>(
+        &'a self,
+        path: &'a str,
+        service: &'a str,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<RemotePin, Error>>;
+
+    /// Removes all remote pins of the `cid` from the `service`, in any state
+    fn pin_remote_rm<'a>(
+        &'a self,
+        service: &'a str,
+        cid: &'a str,
+    ) -> BoxFuture<'a, Result<(), Error>>;
 }
 
 impl IpfsClientExt for IpfsClient {
@@ -55,6 +111,112 @@ impl IpfsClientExt for IpfsClient {
             Err(e) => Box::new(future::err(e).into_stream()),
         }
     }
+
+    fn files_stat_full<'a>(
+        &'a self,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<FilesStatResponse, Error>> {
+        self.request(FilesStat { path }, None).boxed()
+    }
+
+    fn ls_full<'a>(&'a self, path: &'a str) -> BoxFuture<'a, Result<LsFullResponse, Error>> {
+        let req = request::Ls {
+            path,
+            ..Default::default()
+        };
+        self.request(req, None).boxed()
+    }
+
+    fn files_ls_full<'a>(
+        &'a self,
+        path: &'a str,
+    ) -> BoxFuture<'a, Result<FilesLsFullResponse, Error>> {
+        let req = request::FilesLs {
+            path: Some(path),
+            long: Some(true),
+            unsorted: None,
+        };
+        self.request(req, None).boxed()
+    }
+
+    fn files_touch<'a>(
+        &'a self,
+        path: &'a str,
+        mtime: SystemTime,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        // Times before the epoch can't be represented in `files/touch`
+        let mtime = mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let req = FilesTouch {
+            path,
+            mtime: mtime.as_secs() as i64,
+            mtime_nsecs: mtime.subsec_nanos(),
+        };
+        self.request_empty(req, None).boxed()
+    }
+
+    fn files_chmod<'a>(&'a self, path: &'a str, mode: u32) -> BoxFuture<'a, Result<(), Error>> {
+        let req = FilesChmod {
+            mode: format!("{:o}", mode),
+            path,
+        };
+        self.request_empty(req, None).boxed()
+    }
+
+    fn pin_ls_names<'a>(
+        &'a self,
+        typ: &'a str,
+    ) -> BoxFuture<'a, Result<PinLsNamesResponse, Error>> {
+        self.request(PinLsNames { typ, names: true }, None).boxed()
+    }
+
+    fn pin_remote_service_ls(&self) -> BoxFuture<'_, Result<PinRemoteServiceLsResponse, Error>> {
+        self.request(PinRemoteServiceLs, None).boxed()
+    }
+
+    fn pin_remote_ls<'a>(
+        &'a self,
+        service: &'a str,
+        cid: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Vec<RemotePin>, Error>> {
+        let req = PinRemoteLs {
+            service,
+            cid,
+            statuses: RemotePinStatuses::ALL,
+        };
+        match self.build_base_request(req, None) {
+            Ok(req) => self.request_stream_json(req).try_collect().boxed(),
+            Err(e) => future::err(e).boxed(),
+        }
+    }
+
+    fn pin_remote_add<'a>(
+        &'a self,
+        path: &'a str,
+        service: &'a str,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<RemotePin, Error>> {
+        let req = PinRemoteAdd {
+            path,
+            service,
+            name,
+            background: true,
+        };
+        self.request(req, None).boxed()
+    }
+
+    fn pin_remote_rm<'a>(
+        &'a self,
+        service: &'a str,
+        cid: &'a str,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let req = PinRemoteRm {
+            service,
+            cid,
+            statuses: RemotePinStatuses::ALL,
+            force: true,
+        };
+        self.request_empty(req, None).boxed()
+    }
 }
 
 #[derive(Serialize)]
@@ -80,3 +242,225 @@ pub struct BlockGet<'a> {
 impl<'a> ApiRequest for BlockGet<'a> {
     const PATH: &'static str = "/block/get";
 }
+
+#[derive(Serialize)]
+pub struct FilesStat<'a> {
+    #[serde(rename = "arg")]
+    pub path: &'a str,
+}
+
+impl<'a> ApiRequest for FilesStat<'a> {
+    const PATH: &'static str = "/files/stat";
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct FilesStatResponse {
+    pub hash: String,
+    pub size: u64,
+    pub cumulative_size: u64,
+    pub blocks: u64,
+    #[serde(rename = "Type")]
+    pub typ: String,
+    #[serde(default)]
+    pub mode: Option<u32>,
+    #[serde(default)]
+    pub mtime: Option<i64>,
+    #[serde(default)]
+    pub mtime_nsecs: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LsFullResponse {
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub objects: Vec<LsFullObject>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LsFullObject {
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub links: Vec<LsFullLink>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LsFullLink {
+    pub name: String,
+    pub hash: String,
+    pub size: u64,
+    #[serde(rename = "Type")]
+    pub typ: u32,
+    #[serde(default)]
+    pub mode: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct FilesLsFullResponse {
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub entries: Vec<FilesLsFullEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct FilesLsFullEntry {
+    pub name: String,
+    #[serde(rename = "Type")]
+    pub typ: u64,
+    pub size: u64,
+    #[serde(default)]
+    pub mode: Option<u32>,
+}
+
+/// IPFS reports empty lists as `null`
+fn null_as_empty<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Ok(Option::deserialize(deserializer)?.unwrap_or_default())
+}
+
+#[derive(Serialize)]
+pub struct FilesTouch<'a> {
+    #[serde(rename = "arg")]
+    pub path: &'a str,
+    pub mtime: i64,
+    #[serde(rename = "mtime-nsecs")]
+    pub mtime_nsecs: u32,
+}
+
+impl<'a> ApiRequest for FilesTouch<'a> {
+    const PATH: &'static str = "/files/touch";
+}
+
+#[derive(Serialize)]
+pub struct FilesChmod<'a> {
+    #[serde(rename = "arg")]
+    pub mode: String,
+    #[serde(rename = "arg")]
+    pub path: &'a str,
+}
+
+impl<'a> ApiRequest for FilesChmod<'a> {
+    const PATH: &'static str = "/files/chmod";
+}
+
+#[derive(Serialize)]
+pub struct PinLsNames<'a> {
+    #[serde(rename = "type")]
+    pub typ: &'a str,
+    pub names: bool,
+}
+
+impl<'a> ApiRequest for PinLsNames<'a> {
+    const PATH: &'static str = "/pin/ls";
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PinLsNamesResponse {
+    #[serde(default)]
+    pub keys: HashMap<String, PinLsNamesEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PinLsNamesEntry {
+    /// Empty for pins without a name
+    #[serde(default)]
+    pub name: String,
+}
+
+#[derive(Serialize)]
+pub struct PinRemoteServiceLs;
+
+impl ApiRequest for PinRemoteServiceLs {
+    const PATH: &'static str = "/pin/remote/service/ls";
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PinRemoteServiceLsResponse {
+    #[serde(default)]
+    pub remote_services: Vec<RemoteService>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct RemoteService {
+    pub service: String,
+}
+
+/// `pin/remote/ls` and `pin/remote/rm` only consider pinned pins unless the
+/// statuses are listed, each in its own `status` parameter
+#[derive(Serialize)]
+pub struct RemotePinStatuses {
+    #[serde(rename = "status")]
+    pub queued: &'static str,
+    #[serde(rename = "status")]
+    pub pinning: &'static str,
+    #[serde(rename = "status")]
+    pub pinned: &'static str,
+    #[serde(rename = "status")]
+    pub failed: &'static str,
+}
+
+impl RemotePinStatuses {
+    const ALL: RemotePinStatuses = RemotePinStatuses {
+        queued: "queued",
+        pinning: "pinning",
+        pinned: "pinned",
+        failed: "failed",
+    };
+}
+
+#[derive(Serialize)]
+pub struct PinRemoteLs<'a> {
+    pub service: &'a str,
+    pub cid: Option<&'a str>,
+    #[serde(flatten)]
+    pub statuses: RemotePinStatuses,
+}
+
+impl<'a> ApiRequest for PinRemoteLs<'a> {
+    const PATH: &'static str = "/pin/remote/ls";
+}
+
+#[derive(Serialize)]
+pub struct PinRemoteAdd<'a> {
+    #[serde(rename = "arg")]
+    pub path: &'a str,
+    pub service: &'a str,
+    pub name: &'a str,
+    pub background: bool,
+}
+
+impl<'a> ApiRequest for PinRemoteAdd<'a> {
+    const PATH: &'static str = "/pin/remote/add";
+}
+
+#[derive(Serialize)]
+pub struct PinRemoteRm<'a> {
+    pub service: &'a str,
+    pub cid: &'a str,
+    #[serde(flatten)]
+    pub statuses: RemotePinStatuses,
+    pub force: bool,
+}
+
+impl<'a> ApiRequest for PinRemoteRm<'a> {
+    const PATH: &'static str = "/pin/remote/rm";
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct RemotePin {
+    /// `queued`, `pinning`, `pinned` or `failed`
+    pub status: String,
+    pub cid: String,
+    #[serde(default)]
+    pub name: String,
+}
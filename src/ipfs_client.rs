@@ -0,0 +1,120 @@
+use crate::metrics::METRICS;
+use async_trait::async_trait;
+use bytes::Bytes;
+use common_multipart_rfc7578::client::multipart;
+use futures::Stream;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::StatusCode;
+use ipfs_api_backend_hyper::Error;
+use ipfs_api_prelude::{ApiRequest, Backend, BoxStream};
+use serde::Deserialize;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+/// The hyper IPFS client that records metrics of every RPC call
+#[derive(Clone)]
+pub struct IpfsClient(pub ipfs_api_backend_hyper::IpfsClient);
+
+#[async_trait]
+impl Backend for IpfsClient {
+    type HttpRequest = <ipfs_api_backend_hyper::IpfsClient as Backend>::HttpRequest;
+    type HttpResponse = <ipfs_api_backend_hyper::IpfsClient as Backend>::HttpResponse;
+    type Error = Error;
+
+    fn build_base_request<Req>(
+        &self,
+        req: Req,
+        form: Option<multipart::Form<'static>>,
+    ) -> Result<Self::HttpRequest, Error>
+    where
+        Req: ApiRequest,
+    {
+        self.0.build_base_request(req, form)
+    }
+
+    fn get_header(res: &Self::HttpResponse, key: HeaderName) -> Option<&HeaderValue> {
+        <ipfs_api_backend_hyper::IpfsClient as Backend>::get_header(res, key)
+    }
+
+    async fn request_raw<Req>(
+        &self,
+        req: Req,
+        form: Option<multipart::Form<'static>>,
+    ) -> Result<(StatusCode, Bytes), Error>
+    where
+        Req: ApiRequest,
+    {
+        let start = Instant::now();
+        let result = self.0.request_raw(req, form).await;
+        let ok = matches!(result, Ok((StatusCode::OK, _)));
+        METRICS.on_ipfs_request(Req::PATH, ok, start.elapsed());
+        result
+    }
+
+    fn response_to_byte_stream(res: Self::HttpResponse) -> BoxStream<Bytes, Error> {
+        <ipfs_api_backend_hyper::IpfsClient as Backend>::response_to_byte_stream(res)
+    }
+
+    fn request_stream<Res, F>(&self, req: Self::HttpRequest, process: F) -> BoxStream<Res, Error>
+    where
+        F: 'static + Send + Fn(Self::HttpResponse) -> BoxStream<Res, Error>,
+    {
+        self.0.request_stream(req, process)
+    }
+
+    fn request_stream_bytes(&self, req: Self::HttpRequest) -> BoxStream<Bytes, Error> {
+        let path = req.uri().path().to_string();
+        TimedStream::boxed(path, self.0.request_stream_bytes(req))
+    }
+
+    fn request_stream_json<Res>(&self, req: Self::HttpRequest) -> BoxStream<Res, Error>
+    where
+        for<'de> Res: 'static + Deserialize<'de> + Send,
+    {
+        let path = req.uri().path().to_string();
+        TimedStream::boxed(path, self.0.request_stream_json(req))
+    }
+
+    fn with_credentials<U, P>(self, username: U, password: P) -> Self
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        IpfsClient(self.0.with_credentials(username, password))
+    }
+}
+
+/// Records the time until the first item of a streaming response.
+///
+/// Only `request_stream_bytes` and `request_stream_json` are timed, because
+/// `request_stream` doesn't require the items to be `'static`
+struct TimedStream<Res> {
+    inner: BoxStream<Res, Error>,
+    /// The RPC path and the start time, until the first item is received
+    timer: Option<(String, Instant)>,
+}
+
+impl<Res: 'static> TimedStream<Res> {
+    fn boxed(path: String, inner: BoxStream<Res, Error>) -> BoxStream<Res, Error> {
+        Box::new(TimedStream {
+            inner,
+            timer: Some((path, Instant::now())),
+        })
+    }
+}
+
+impl<Res> Stream for TimedStream<Res> {
+    type Item = Result<Res, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(item) = &poll {
+            if let Some((path, start)) = self.timer.take() {
+                let ok = !matches!(item, Some(Err(_)));
+                METRICS.on_ipfs_request(&path, ok, start.elapsed());
+            }
+        }
+        poll
+    }
+}
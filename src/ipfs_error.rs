@@ -0,0 +1,207 @@
+use hyper::header::RETRY_AFTER;
+use hyper::{Response, StatusCode};
+use ipfs_api_backend_hyper::Error;
+use std::cell::Cell;
+use std::future::Future;
+use webdav_handler::body::Body;
+use webdav_handler::fs::FsError;
+
+/// Substrings of IPFS API error messages and the errors they are mapped to.
+/// The first match wins
+const MESSAGES: &[(&str, FsError)] = &[
+    ("file does not exist", FsError::NotFound),
+    ("no link named ", FsError::NotFound),
+    ("merkledag: not found", FsError::NotFound),
+    ("not found locally", FsError::NotFound),
+    // IPNS name without a (valid) record
+    ("could not resolve name", FsError::NotFound),
+    // An intermediate path segment is a file. The WebDAV handler reports
+    // `NotFound` of a parent as `409 Conflict`
+    ("not a directory", FsError::NotFound),
+    // `pin/ls` of a path that is not pinned (with the requested type)
+    ("not pinned", FsError::NotFound),
+    // `MKCOL` reports it as `405 Method Not Allowed`, `PUT` as `409 Conflict`
+    ("file already exists", FsError::Exists),
+    // `files/cp` and `files/mv` onto an existing entry
+    ("already has entry", FsError::Exists),
+    ("is a directory", FsError::Exists),
+    ("not a file", FsError::Exists),
+    // `pin/add` of a direct pin onto a recursive one
+    ("already pinned recursively", FsError::Exists),
+    ("permission denied", FsError::Forbidden),
+    ("no key by the given name", FsError::Forbidden),
+    ("no key named", FsError::Forbidden),
+];
+
+/// Messages of errors that mean the IPFS node gave up waiting for the network
+const TIMEOUT_MESSAGES: &[&str] = &["context deadline exceeded", "timeout", "timed out"];
+
+/// How many seconds clients are asked to wait when the IPFS node is unreachable
+const RETRY_AFTER_SECS: u64 = 10;
+
+/// A failure of the IPFS node itself rather than of the request. `FsError`
+/// can't express it, so it is recorded for the request and its
+/// `500 Internal Server Error` response is replaced by `to_response`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Unavailable {
+    /// The RPC API can't be connected to (`503 Service Unavailable`)
+    Down,
+    /// The node timed out fetching the data (`504 Gateway Timeout`)
+    Timeout,
+}
+
+tokio::task_local! {
+    static UNAVAILABLE: Cell<Option<Unavailable>>;
+}
+
+pub fn handle_error(e: Error) -> FsError {
+    match &e {
+        Error::Api(api_error) => {
+            let message = api_error.message.to_lowercase();
+            if let Some((_, fs_error)) = MESSAGES.iter().find(|(m, _)| message.contains(m)) {
+                return *fs_error;
+            }
+            if TIMEOUT_MESSAGES.iter().any(|m| message.contains(m)) {
+                set_unavailable(Unavailable::Timeout);
+            }
+        }
+        Error::Client(client_error) if client_error.is_connect() => {
+            set_unavailable(Unavailable::Down);
+        }
+        Error::Client(client_error) if client_error.is_timeout() => {
+            set_unavailable(Unavailable::Timeout);
+        }
+        _ => {}
+    };
+
+    log::error!("Got an error from IPFS API: {}", e);
+
+    FsError::GeneralFailure
+}
+
+/// Only records errors converted by the request's own task. Background tasks
+/// (e.g. `ReadAhead` and `files/write` ones) run outside of the scope, so they
+/// must hand their `Error` back to the request to be converted there
+fn set_unavailable(unavailable: Unavailable) {
+    // Errors outside of a request (e.g. IPNS publishing) are only logged
+    let _ = UNAVAILABLE.try_with(|cell| cell.set(Some(unavailable)));
+}
+
+/// Records that the IPFS node is still busy with an RPC call the request has
+/// stopped waiting for, so the request fails with `504 Gateway Timeout`
+pub fn timed_out() -> FsError {
+    set_unavailable(Unavailable::Timeout);
+    FsError::GeneralFailure
+}
+
+/// Runs a request handler, replacing its `500 Internal Server Error` or
+/// `502 Bad Gateway` with `503`/`504` if the IPFS node was unreachable or
+/// timed out
+pub async fn handle_unavailable<F>(handler: F) -> Response<Body>
+where
+    F: Future<Output = Response<Body>>,
+{
+    UNAVAILABLE
+        .scope(Cell::new(None), async {
+            let resp = handler.await;
+            match UNAVAILABLE.with(Cell::get) {
+                Some(unavailable)
+                    if resp.status() == StatusCode::INTERNAL_SERVER_ERROR
+                        || resp.status() == StatusCode::BAD_GATEWAY =>
+                {
+                    to_response(unavailable)
+                }
+                _ => resp,
+            }
+        })
+        .await
+}
+
+fn to_response(unavailable: Unavailable) -> Response<Body> {
+    match unavailable {
+        Unavailable::Down => Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(RETRY_AFTER, RETRY_AFTER_SECS)
+            .body(Body::from("503 Service Unavailable\n")),
+        Unavailable::Timeout => Response::builder()
+            .status(StatusCode::GATEWAY_TIMEOUT)
+            .body(Body::from("504 Gateway Timeout\n")),
+    }
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipfs_api_backend_hyper::{IpfsApi, TryFromUri};
+    use ipfs_api_prelude::ApiError;
+
+    fn api_error(message: &str) -> Error {
+        Error::Api(ApiError {
+            message: message.to_string(),
+            code: 0,
+        })
+    }
+
+    fn internal_server_error() -> Response<Body> {
+        Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn maps_messages() {
+        for (message, fs_error) in MESSAGES {
+            let e = api_error(&format!("Some Context: {}", message.to_uppercase()));
+            assert_eq!(handle_error(e), *fs_error, "{}", message);
+        }
+        assert_eq!(
+            handle_error(api_error("unexpected")),
+            FsError::GeneralFailure
+        );
+    }
+
+    #[tokio::test]
+    async fn timeout_is_gateway_timeout() {
+        let resp = handle_unavailable(async {
+            let e = api_error("failed to get block: context deadline exceeded");
+            assert_eq!(handle_error(e), FsError::GeneralFailure);
+            internal_server_error()
+        })
+        .await;
+        assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(resp.headers().get(RETRY_AFTER).is_none());
+    }
+
+    #[tokio::test]
+    async fn connection_refused_is_service_unavailable() {
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let uri = format!("http://127.0.0.1:{}", port);
+        let ipfs = ipfs_api_backend_hyper::IpfsClient::from_str(&uri).unwrap();
+        let resp = handle_unavailable(async {
+            let e = ipfs.version().await.unwrap_err();
+            assert_eq!(handle_error(e), FsError::GeneralFailure);
+            internal_server_error()
+        })
+        .await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[RETRY_AFTER], RETRY_AFTER_SECS.to_string());
+    }
+
+    #[tokio::test]
+    async fn only_replaces_server_errors() {
+        let resp = handle_unavailable(async {
+            handle_error(api_error("context deadline exceeded"));
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap()
+        })
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -1,18 +1,41 @@
+use crate::aliases::{self, Aliases};
+use crate::cache::{ContentCache, MetadataCache};
+use crate::ipfs_api_ext::IpfsClientExt;
+use crate::ipfs_client::IpfsClient;
+use crate::ipns::{IpnsStaging, StagedDavFile};
 use crate::metadata::{DynamicLsEntry, StaticLsEntry, StaticMetadata};
+use crate::mfs::{self, MfsFs};
+use crate::props::{forbid_patch, IpfsProps};
 use crate::read::ReadOnlyDavFile;
-use crate::{handle_error, map_path, stat_metadata, IpfsOrIpns};
-use futures::{future, stream, FutureExt};
-use ipfs_api_backend_hyper::{IpfsApi, IpfsClient};
+use crate::uploads::Uploads;
+use crate::{handle_error, map_path, stat_metadata, IpfsOrIpns, READ_DIR_CONCURRENCY};
+use futures::future::BoxFuture;
+use futures::{future, stream, FutureExt, StreamExt};
+use hyper::StatusCode;
+use ipfs_api_backend_hyper::IpfsApi;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::SystemTime;
 use webdav_handler::davpath::DavPath;
 use webdav_handler::fs::{
-    DavDirEntry, DavFile, DavFileSystem, DavMetaData, FsError, FsFuture, FsStream, OpenOptions,
-    ReadDirMeta,
+    DavDirEntry, DavFile, DavFileSystem, DavMetaData, DavProp, FsError, FsFuture, FsStream,
+    OpenOptions, ReadDirMeta,
 };
 
 #[derive(Clone)]
 pub struct IpfsFs {
     pub ipfs: IpfsClient,
     pub ty: IpfsOrIpns,
+    pub cache: MetadataCache,
+    pub content_cache: ContentCache,
+    /// See `ReadOnlyDavFile::with_read_ahead`
+    pub read_ahead: usize,
+    /// `Some` for a writable `/ipns`
+    pub staging: Option<IpnsStaging>,
+    /// `Some` for a writable `/ipfs`
+    pub uploads: Option<Uploads>,
+    /// `Some` if the `by-name` directory is enabled
+    pub aliases: Option<Aliases>,
 }
 
 impl DavFileSystem for IpfsFs {
@@ -21,13 +44,28 @@ impl DavFileSystem for IpfsFs {
             Box::pin(future::ready(Err(FsError::Forbidden)))
         } else {
             async move {
+                if let (true, Some(uploads)) = (options.write, &self.uploads) {
+                    return uploads.open(path, options).await;
+                }
                 if options.write {
-                    return Err(FsError::Forbidden);
+                    let staging = self.staging.as_ref().ok_or(FsError::Forbidden)?;
+                    let (key, staging_path) = staging.stage(path).await?;
+                    let file = staging.mfs().open(&staging_path, options).await?;
+                    let file = StagedDavFile::new(file, staging.clone(), key);
+                    return Ok(Box::new(file) as Box<dyn DavFile>);
+                }
+                if let Some(staging_path) = self.lookup_staged(path).await? {
+                    return self.staging_mfs().open(&staging_path, options).await;
                 }
                 let ipfs_path = self.to_ipfs_path(path)?;
                 let stat = self.stat_metadata(&ipfs_path).await?;
-                let file =
-                    ReadOnlyDavFile::new_ipfs(self.ipfs.clone(), ipfs_path.to_string(), stat);
+                let file = ReadOnlyDavFile::new_ipfs(
+                    self.ipfs.clone(),
+                    ipfs_path.to_string(),
+                    stat,
+                    self.content_cache.clone(),
+                )
+                .with_read_ahead(self.read_ahead);
                 Ok(Box::new(file) as Box<dyn DavFile>)
             }
             .boxed()
@@ -37,11 +75,11 @@ impl DavFileSystem for IpfsFs {
     fn read_dir<'a>(
         &'a self,
         path: &'a DavPath,
-        _: ReadDirMeta,
+        meta: ReadDirMeta,
     ) -> FsFuture<FsStream<Box<dyn DavDirEntry>>> {
         async move {
             if path.as_bytes() == &[b'/'] {
-                let dirs: Vec<_> = match self.ty {
+                let mut dirs: Vec<_> = match self.ty {
                     IpfsOrIpns::Ipns => {
                         let keys = self.ipfs.key_list().await.map_err(handle_error)?;
                         keys.keys
@@ -49,8 +87,10 @@ impl DavFileSystem for IpfsFs {
                             .map(move |k| {
                                 Box::new(DynamicLsEntry {
                                     ipfs: self.ipfs.clone(),
+                                    cache: self.cache.clone(),
                                     ty: IpfsOrIpns::Ipns,
                                     cid: k.id.to_string(),
+                                    name: None,
                                 }) as Box<dyn DavDirEntry>
                             })
                             .collect()
@@ -73,27 +113,65 @@ impl DavFileSystem for IpfsFs {
                             .map(move |(cid, _)| {
                                 Box::new(DynamicLsEntry {
                                     ipfs: self.ipfs.clone(),
+                                    cache: self.cache.clone(),
                                     ty: IpfsOrIpns::Ipfs,
                                     cid,
+                                    name: None,
                                 }) as Box<dyn DavDirEntry>
                             })
                             .collect()
                     }
                 };
+                if self.aliases.is_some() {
+                    let dir = StaticLsEntry::new_dir(aliases::DIR.to_string());
+                    dirs.insert(0, Box::new(dir) as Box<dyn DavDirEntry>);
+                }
                 let stream = Box::pin(stream::iter(dirs)) as FsStream<Box<dyn DavDirEntry>>;
-                Ok(stream)
+                match &self.uploads {
+                    Some(uploads) => Ok(Box::pin(stream.chain(uploads.read_dir(meta).await))
+                        as FsStream<Box<dyn DavDirEntry>>),
+                    None => Ok(stream),
+                }
             } else {
+                if let Some(staging_path) = self.lookup_staged(path).await? {
+                    return self.staging_mfs().read_dir(&staging_path, meta).await;
+                }
                 let ipfs_path = self.to_ipfs_path(path)?;
-                let ls = self.ipfs.ls(&ipfs_path).await.map_err(handle_error)?;
-                let f = match ls.objects.into_iter().next() {
-                    None => return Err(FsError::NotFound),
-                    Some(f) => f,
+                let ls = async {
+                    let ls = self.ipfs.ls_full(&ipfs_path).await.map_err(handle_error)?;
+                    let f = ls.objects.into_iter().next().ok_or(FsError::NotFound)?;
+                    Ok(f.links
+                        .into_iter()
+                        .map(StaticLsEntry::from_ls_link)
+                        .map(StaticLsEntry::into_parts)
+                        .collect())
                 };
-                let entries = f.links.into_iter().map(move |e| {
-                    Box::new(StaticLsEntry::from_ipfs_file_header(e)) as Box<dyn DavDirEntry>
-                });
+                let entries = self.cache.ls(&ipfs_path, ls).await?.as_ref().clone();
+                if let ReadDirMeta::None = meta {
+                    let entries = entries.into_iter().map(move |(name, metadata)| {
+                        Box::new(StaticLsEntry::new(name, metadata)) as Box<dyn DavDirEntry>
+                    });
+                    return Ok(Box::pin(stream::iter(entries)) as FsStream<Box<dyn DavDirEntry>>);
+                }
 
-                Ok(Box::pin(stream::iter(entries)) as FsStream<Box<dyn DavDirEntry>>)
+                // `ls` doesn't report modification times, so each entry is stat'ed
+                let this = self.clone();
+                let entries = stream::iter(entries)
+                    .map(move |(name, metadata)| {
+                        let this = this.clone();
+                        async move {
+                            let cid = metadata.cid.as_deref().unwrap_or_default();
+                            let metadata = this
+                                .stat_metadata(&["/ipfs/", cid].concat())
+                                .await
+                                .unwrap_or(metadata);
+                            StaticLsEntry::new(name, metadata)
+                        }
+                    })
+                    .buffered(READ_DIR_CONCURRENCY)
+                    .map(|e| Box::new(e) as Box<dyn DavDirEntry>);
+
+                Ok(Box::pin(entries) as FsStream<Box<dyn DavDirEntry>>)
             }
         }
         .boxed()
@@ -106,17 +184,231 @@ impl DavFileSystem for IpfsFs {
             )))
         } else {
             async move {
+                if let Some(staging_path) = self.lookup_staged(path).await? {
+                    return self.staging_mfs().metadata(&staging_path).await;
+                }
                 let ipfs_path = self.to_ipfs_path(path)?;
                 Ok(Box::new(self.stat_metadata(&ipfs_path).await?) as Box<dyn DavMetaData>)
             }
             .boxed()
         };
     }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<()> {
+        async move {
+            self.mutate(path, |mfs, p| {
+                async move { mfs.create_dir(&p).await }.boxed()
+            })
+            .await
+        }
+        .boxed()
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<()> {
+        async move {
+            let op = |mfs: &'a MfsFs, p: DavPath| async move { mfs.remove_dir(&p).await }.boxed();
+            match (&self.uploads, is_key_root(path)?) {
+                (Some(uploads), true) => uploads.remove(path, op).await,
+                (None, true) => Err(FsError::Forbidden),
+                (_, false) => self.mutate(path, op).await,
+            }
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<()> {
+        async move {
+            let op = |mfs: &'a MfsFs, p: DavPath| async move { mfs.remove_file(&p).await }.boxed();
+            match (&self.uploads, is_key_root(path)?) {
+                (Some(uploads), true) => uploads.remove(path, op).await,
+                _ => self.mutate(path, op).await,
+            }
+        }
+        .boxed()
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<()> {
+        async move {
+            let (from_path, from_mfs) = self.source_path(from).await?;
+            self.mutate(to, |mfs, to| {
+                async move { mfs.transfer(&from_path, from_mfs, &to, true).await }.boxed()
+            })
+            .await?;
+            match (&self.uploads, &self.staging) {
+                (Some(uploads), _) => uploads.on_moved(from),
+                // An `/ipns` source is in MFS only if its key is staged
+                (None, Some(staging)) if from_mfs => staging.on_moved(from, to)?,
+                _ => {}
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<()> {
+        async move {
+            let (from_path, from_mfs) = self.source_path(from).await?;
+            self.mutate(to, |mfs, to| {
+                async move { mfs.transfer(&from_path, from_mfs, &to, false).await }.boxed()
+            })
+            .await
+        }
+        .boxed()
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<()> {
+        async move {
+            self.mutate(path, |mfs, p| {
+                async move { mfs.set_modified(&p, tm).await }.boxed()
+            })
+            .await
+        }
+        .boxed()
+    }
+
+    fn have_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+    ) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        Box::pin(future::ready(path.as_bytes() != b"/"))
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        _: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<Vec<(StatusCode, DavProp)>> {
+        Box::pin(future::ready(Ok(forbid_patch(patch))))
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<Vec<DavProp>> {
+        async move {
+            if let Some(staging_path) = self.lookup_staged(path).await? {
+                return self
+                    .staging_mfs()
+                    .get_props(&staging_path, do_content)
+                    .await;
+            }
+            let ipfs_path = self.to_ipfs_path(path)?;
+            Ok(self.ipfs_props(&ipfs_path).await?.to_dav_props(do_content))
+        }
+        .boxed()
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<Vec<u8>> {
+        async move {
+            if let Some(staging_path) = self.lookup_staged(path).await? {
+                return self.staging_mfs().get_prop(&staging_path, prop).await;
+            }
+            let ipfs_path = self.to_ipfs_path(path)?;
+            self.ipfs_props(&ipfs_path).await?.get(&prop)
+        }
+        .boxed()
+    }
 }
 
 impl IpfsFs {
+    /// Drops the cached metadata of `path`, or of its staged copy
+    pub async fn forget(&self, path: &DavPath) {
+        match self.lookup_staged(path).await {
+            Ok(Some(staging_path)) => self.staging_mfs().forget(&staging_path),
+            _ => {
+                if let Ok(ipfs_path) = self.to_ipfs_path(path) {
+                    self.cache.invalidate(&ipfs_path);
+                }
+            }
+        }
+    }
+
+    pub async fn set_executable(&self, path: &DavPath, executable: bool) -> Result<(), FsError> {
+        self.mutate(path, |mfs, p| {
+            async move { mfs.set_executable(&p, executable).await }.boxed()
+        })
+        .await
+    }
+
+    /// Runs a mutating MFS operation on a staged `/ipns` name or an upload
+    /// to `/ipfs`. Everything else is immutable
+    async fn mutate<'a, F>(&'a self, path: &'a DavPath, op: F) -> Result<(), FsError>
+    where
+        F: FnOnce(&'a MfsFs, DavPath) -> BoxFuture<'a, Result<(), FsError>>,
+    {
+        match (&self.uploads, &self.staging) {
+            (Some(uploads), _) => uploads.mutate(path, op).await,
+            (None, Some(staging)) => staging.mutate(path, op).await,
+            (None, None) => Err(FsError::Forbidden),
+        }
+    }
+
+    /// See `IpnsStaging::source_path`
+    async fn source_path(&self, from: &DavPath) -> Result<(String, bool), FsError> {
+        if let Some(uploads) = &self.uploads {
+            if let Some(source) = uploads.source_path(from)? {
+                return Ok(source);
+            }
+        }
+        match &self.staging {
+            Some(staging) => staging.source_path(from).await,
+            None => mfs::source_path(from),
+        }
+    }
+
+    async fn lookup_staged(&self, path: &DavPath) -> Result<Option<DavPath>, FsError> {
+        match (&self.uploads, &self.staging) {
+            (Some(uploads), _) => uploads.lookup(path),
+            (None, Some(staging)) => staging.lookup(path).await,
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Both staged `/ipns` names and uploads are stored in MFS
+    fn staging_mfs(&self) -> &MfsFs {
+        match (&self.uploads, &self.staging) {
+            (Some(uploads), _) => uploads.mfs(),
+            (None, staging) => staging.as_ref().unwrap().mfs(),
+        }
+    }
+
+    async fn ipfs_props(&self, ipfs_path: &str) -> Result<IpfsProps, FsError> {
+        self.cache
+            .props(ipfs_path, self.fetch_ipfs_props(ipfs_path))
+            .await
+    }
+
+    /// `files/stat` also works for `/ipfs` and `/ipns` paths, but only for UnixFS
+    /// nodes, other nodes are described by `block/stat`
+    async fn fetch_ipfs_props(&self, ipfs_path: &str) -> Result<IpfsProps, FsError> {
+        let (stat, metadata) = future::join(
+            self.ipfs.files_stat_full(ipfs_path),
+            self.stat_metadata(ipfs_path),
+        )
+        .await;
+        let metadata = metadata?;
+        if let (Ok(stat), true) = (stat, metadata.is_unixfs) {
+            return Ok(IpfsProps {
+                cid: stat.hash,
+                cumulative_size: Some(stat.cumulative_size),
+                blocks: Some(stat.blocks),
+                is_unixfs: true,
+            });
+        }
+        let stat = self
+            .ipfs
+            .block_stat(ipfs_path)
+            .await
+            .map_err(handle_error)?;
+        Ok(IpfsProps {
+            cid: stat.key,
+            cumulative_size: None,
+            blocks: None,
+            is_unixfs: metadata.is_unixfs,
+        })
+    }
+
     async fn stat_metadata(&self, ipfs_path: &str) -> Result<StaticMetadata, FsError> {
-        stat_metadata(&self.ipfs, ipfs_path).await
+        self.cache
+            .metadata(ipfs_path, stat_metadata(&self.ipfs, ipfs_path))
+            .await
     }
 
     fn to_ipfs_path(&self, path: &DavPath) -> Result<String, FsError> {
@@ -129,3 +421,13 @@ impl IpfsFs {
         Ok(ipfs_path)
     }
 }
+
+/// `true` for `/<key>` and `/<key>/`
+fn is_key_root(path: &DavPath) -> Result<bool, FsError> {
+    let path = map_path(path)?;
+    Ok(path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .count()
+        == 1)
+}
@@ -0,0 +1,337 @@
+use crate::ipfs_client::IpfsClient;
+use crate::mfs::{self, MfsFs};
+use crate::rootfs::in_namespace;
+use crate::{handle_error, map_path};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hyper::body::{Buf, Bytes};
+use ipfs_api_backend_hyper::IpfsApi;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Formatter};
+use std::io::SeekFrom;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tokio::task::JoinHandle;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{DavFile, DavMetaData, FsError, FsFuture};
+
+/// The first retry of a failed publish is after this delay, which doubles on
+/// every further failure
+const PUBLISH_RETRY_DELAY: Duration = Duration::from_secs(10);
+const MAX_PUBLISH_RETRY_DELAY: Duration = Duration::from_secs(600);
+
+/// A writable working copy of IPNS names owned by the local node.
+///
+/// Writes under `/ipns/<own-key-id>/...` are applied to an MFS directory
+/// `<root>/<key-id>` (the "staging" directory), and the staging directory
+/// is published to the key after `publish_delay` passes without further
+/// modifications.
+#[derive(Clone)]
+pub struct IpnsStaging {
+    ipfs: IpfsClient,
+    mfs: MfsFs,
+    root: String,
+    publish_delay: Duration,
+    pending: Arc<Mutex<HashMap<String, JoinHandle<()>>>>,
+    init_lock: Arc<tokio::sync::Mutex<()>>,
+    /// Keys that have a staging directory, listed once and then kept up to
+    /// date, so reads don't need to stat it
+    staged: Arc<OnceCell<Mutex<HashSet<String>>>>,
+}
+
+impl IpnsStaging {
+    pub fn new(mfs: MfsFs, root: String, publish_delay: Duration) -> Self {
+        IpnsStaging {
+            ipfs: mfs.ipfs.clone(),
+            mfs,
+            root,
+            publish_delay,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            init_lock: Arc::new(tokio::sync::Mutex::new(())),
+            staged: Arc::new(OnceCell::new()),
+        }
+    }
+
+    /// Lists the staged keys in advance, so the first request doesn't wait for it
+    pub async fn load(&self) {
+        if let Err(e) = self.staged_keys().await {
+            log::error!(
+                "Failed to list staged IPNS names in `{}`: {:?}",
+                self.root,
+                e
+            );
+        }
+    }
+
+    /// The staged keys, listing them on the first call
+    async fn staged_keys(&self) -> Result<&Mutex<HashSet<String>>, FsError> {
+        self.staged
+            .get_or_try_init(|| async {
+                let keys = match self.ipfs.files_ls(Some(&self.root)).await {
+                    Ok(ls) => ls.entries.into_iter().map(|entry| entry.name).collect(),
+                    Err(e) => match handle_error(e) {
+                        FsError::NotFound => HashSet::new(),
+                        e => return Err(e),
+                    },
+                };
+                Ok(Mutex::new(keys))
+            })
+            .await
+    }
+
+    pub fn mfs(&self) -> &MfsFs {
+        &self.mfs
+    }
+
+    /// Returns the path inside the staging directory if the key referred by
+    /// `path` is already staged, i.e. it is an own key that was written to
+    pub async fn lookup(&self, path: &DavPath) -> Result<Option<DavPath>, FsError> {
+        let key = match split_key(path)? {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        if self.staged_keys().await?.lock().unwrap().contains(key) {
+            Ok(Some(self.to_staging_path(path)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the path inside the staging directory, creating the staging
+    /// directory for the key if needed. Fails with `FsError::Forbidden` if
+    /// the key is not owned by the local node.
+    pub async fn stage(&self, path: &DavPath) -> Result<(String, DavPath), FsError> {
+        let key = match split_key(path)? {
+            Some(key) => key,
+            None => return Err(FsError::Forbidden),
+        };
+        self.init_staging(key).await?;
+        Ok((key.to_string(), self.to_staging_path(path)?))
+    }
+
+    /// Runs a mutating MFS operation on the staging directory and schedules
+    /// publishing if it succeeds
+    pub async fn mutate<'a, F>(&'a self, path: &'a DavPath, op: F) -> Result<(), FsError>
+    where
+        F: FnOnce(&'a MfsFs, DavPath) -> BoxFuture<'a, Result<(), FsError>>,
+    {
+        let (key, staging_path) = self.stage(path).await?;
+        op(&self.mfs, staging_path).await?;
+        self.schedule_publish(&key);
+        Ok(())
+    }
+
+    /// Maps the source of a COPY/MOVE (a full WebDAV path) to a path that
+    /// can be passed to `files/cp` or `files/mv`. The boolean is `true` if
+    /// the returned path refers to MFS (including staged IPNS keys)
+    pub async fn source_path(&self, from: &DavPath) -> Result<(String, bool), FsError> {
+        if let Some(ipns_from) = in_namespace(from, "/ipns") {
+            if let Some(staged) = self.lookup(&ipns_from).await? {
+                let path = map_path(&staged)?.trim_end_matches('/');
+                return Ok((path.to_string(), true));
+            }
+        }
+        mfs::source_path(from)
+    }
+
+    /// Must be called after a staged `from` (a full WebDAV path) is moved to
+    /// `to`. `mutate` only publishes the key of `to`, so the key `from` was
+    /// moved out of is published too if it is another one
+    pub fn on_moved(&self, from: &DavPath, to: &DavPath) -> Result<(), FsError> {
+        let ipns_from = match in_namespace(from, "/ipns") {
+            Some(ipns_from) => ipns_from,
+            None => return Ok(()),
+        };
+        let key = match split_key(&ipns_from)? {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        // The whole staging directory was moved, the key is not staged anymore
+        if map_path(&ipns_from)?.trim_matches('/') == key {
+            if let Some(staged) = self.staged.get() {
+                staged.lock().unwrap().remove(key);
+            }
+        } else if split_key(to)? != Some(key) {
+            self.schedule_publish(key);
+        }
+        Ok(())
+    }
+
+    async fn init_staging(&self, key: &str) -> Result<(), FsError> {
+        let staged = self.staged_keys().await?;
+        if staged.lock().unwrap().contains(key) {
+            return Ok(());
+        }
+        let _guard = self.init_lock.lock().await;
+        let key_root = self.key_root(key);
+        match self.ipfs.files_stat(&key_root).await {
+            Ok(_) => {
+                staged.lock().unwrap().insert(key.to_string());
+                return Ok(());
+            }
+            Err(e) => match handle_error(e) {
+                FsError::NotFound => {}
+                e => return Err(e),
+            },
+        }
+
+        let keys = self.ipfs.key_list().await.map_err(handle_error)?;
+        if !keys.keys.iter().any(|k| k.id == key) {
+            return Err(FsError::Forbidden);
+        }
+
+        self.ipfs
+            .files_mkdir(&self.root, true)
+            .await
+            .map_err(handle_error)?;
+        let ipns_path = ["/ipns/", key].concat();
+        let result = match self.ipfs.name_resolve(Some(&ipns_path), true, false).await {
+            Ok(resolved) => {
+                log::info!("Staging `{}` ({}) for writing", ipns_path, resolved.path);
+                self.ipfs.files_cp(&resolved.path, &key_root).await
+            }
+            Err(e) => {
+                // The key has never been published (or the record has expired)
+                log::info!(
+                    "Failed to resolve `{}`, staging an empty directory: {}",
+                    ipns_path,
+                    e
+                );
+                self.ipfs.files_mkdir(&key_root, false).await
+            }
+        };
+        result.map_err(handle_error)?;
+        staged.lock().unwrap().insert(key.to_string());
+        Ok(())
+    }
+
+    /// Schedules publishing of the staging directory of the `key`. Every call
+    /// postpones publishing for `publish_delay`
+    pub fn schedule_publish(&self, key: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(task) = pending.remove(key) {
+            task.abort();
+        }
+        let task = self.spawn_publish(key.to_string(), self.publish_delay, PUBLISH_RETRY_DELAY);
+        pending.insert(key.to_string(), task);
+    }
+
+    /// Publishes the `key` after `delay`. A failed publish is retried after
+    /// `retry_delay`, unless the key is modified (and so scheduled) meanwhile
+    fn spawn_publish(&self, key: String, delay: Duration, retry_delay: Duration) -> JoinHandle<()> {
+        let this = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            this.pending.lock().unwrap().remove(&key);
+            let e = match this.publish(&key).await {
+                Ok(()) => return,
+                Err(e) => e,
+            };
+            if e == FsError::NotFound {
+                // The staging directory was moved away
+                log::error!("Failed to publish `/ipns/{}`: {:?}", key, e);
+                return;
+            }
+            log::error!(
+                "Failed to publish `/ipns/{}`, retrying in {:?}: {:?}",
+                key,
+                retry_delay,
+                e
+            );
+            let mut pending = this.pending.lock().unwrap();
+            if let Entry::Vacant(entry) = pending.entry(key.clone()) {
+                let next_delay = (retry_delay * 2).min(MAX_PUBLISH_RETRY_DELAY);
+                entry.insert(this.spawn_publish(key, retry_delay, next_delay));
+            }
+        })
+    }
+
+    async fn publish(&self, key: &str) -> Result<(), FsError> {
+        let stat = self
+            .ipfs
+            .files_stat(&self.key_root(key))
+            .await
+            .map_err(handle_error)?;
+        let ipfs_path = ["/ipfs/", &stat.hash].concat();
+        log::info!("Publishing {} to `/ipns/{}`", ipfs_path, key);
+        self.ipfs
+            .name_publish(&ipfs_path, false, None, None, Some(key))
+            .await
+            .map_err(handle_error)?;
+        log::info!("Published {} to `/ipns/{}`", ipfs_path, key);
+        self.mfs.cache.invalidate(&["/ipns/", key].concat());
+        Ok(())
+    }
+
+    fn key_root(&self, key: &str) -> String {
+        [&self.root, "/", key].concat()
+    }
+
+    fn to_staging_path(&self, path: &DavPath) -> Result<DavPath, FsError> {
+        let mut staging_path = self.root.clone();
+        staging_path += &path.as_url_string();
+        DavPath::new(&staging_path).map_err(|_| FsError::GeneralFailure)
+    }
+}
+
+/// Extracts `<key>` from `/<key>/rest`
+fn split_key(path: &DavPath) -> Result<Option<&str>, FsError> {
+    let path = map_path(path)?;
+    Ok(path.split('/').find(|segment| !segment.is_empty()))
+}
+
+/// A file in the staging directory. Schedules publishing on flush
+pub struct StagedDavFile {
+    inner: Box<dyn DavFile>,
+    staging: IpnsStaging,
+    key: String,
+}
+
+impl StagedDavFile {
+    pub fn new(inner: Box<dyn DavFile>, staging: IpnsStaging, key: String) -> Self {
+        StagedDavFile {
+            inner,
+            staging,
+            key,
+        }
+    }
+}
+
+impl Debug for StagedDavFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StagedDavFile")
+    }
+}
+
+impl DavFile for StagedDavFile {
+    fn metadata<'a>(&'a mut self) -> FsFuture<Box<dyn DavMetaData>> {
+        self.inner.metadata()
+    }
+
+    fn write_buf<'a>(&'a mut self, buf: Box<dyn Buf + Send>) -> FsFuture<()> {
+        self.inner.write_buf(buf)
+    }
+
+    fn write_bytes(&mut self, buf: Bytes) -> FsFuture<()> {
+        self.inner.write_bytes(buf)
+    }
+
+    fn read_bytes(&mut self, count: usize) -> FsFuture<Bytes> {
+        self.inner.read_bytes(count)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<u64> {
+        self.inner.seek(pos)
+    }
+
+    fn flush(&mut self) -> FsFuture<()> {
+        async move {
+            self.inner.flush().await?;
+            self.staging.schedule_publish(&self.key);
+            Ok(())
+        }
+        .boxed()
+    }
+}
@@ -1,119 +1,496 @@
+mod access_log;
+mod aliases;
+mod auth;
+mod cache;
+mod config;
+mod copymove;
+mod filels;
 mod ipfs_api_ext;
+mod ipfs_client;
+mod ipfs_error;
 mod ipfs_fs;
+mod ipns;
 mod metadata;
+mod metrics;
 mod mfs;
+mod mounts;
+mod path_lock;
+mod pins;
+mod proppatch;
+mod props;
 mod read;
+mod remote_pins;
 mod request_log;
 mod rootfs;
+mod tls;
+mod uploads;
 mod write;
 
+use crate::access_log::{AccessLog, LoggedBody};
+use crate::aliases::Aliases;
+use crate::auth::Auth;
+use crate::cache::{ContentCache, MetadataCache};
+use crate::config::{Args, Config, LockStore};
+use crate::filels::FileLs;
+use crate::ipfs_client::IpfsClient;
+use crate::ipfs_error::{handle_error, handle_unavailable};
 use crate::ipfs_fs::IpfsFs;
+use crate::ipns::IpnsStaging;
 use crate::metadata::StaticMetadata;
+use crate::metrics::METRICS;
 use crate::mfs::MfsFs;
+use crate::mounts::Mounts;
+use crate::path_lock::PathLocks;
+use crate::pins::PinsFs;
+use crate::remote_pins::RemotePinsFs;
 use crate::request_log::RequestLog;
 use crate::rootfs::RootFs;
+use crate::tls::CertResolver;
+use crate::uploads::Uploads;
+use clap::Parser;
 use futures::TryStreamExt;
-use hyper::Request;
-use ipfs_api_backend_hyper::{Error, IpfsApi, IpfsClient, TryFromUri};
+use hyper::header::{HeaderValue, IF_MATCH, IF_NONE_MATCH, LOCATION};
+use hyper::server::conn::AddrStream;
+use hyper::{Method, Request, Response};
+use ipfs_api_backend_hyper::{Error, IpfsApi};
 use std::convert::Infallible;
 use std::error::Error as _;
+use std::future::Future;
 use std::net::SocketAddr;
-use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
 use unixfs_v1::dagpb::node_data;
 use unixfs_v1::UnixFs;
+use webdav_handler::body::Body;
 use webdav_handler::davpath::DavPath;
-use webdav_handler::fs::FsError;
+use webdav_handler::fs::{DavFileSystem, FsError};
+use webdav_handler::ls::DavLockSystem;
 use webdav_handler::memls::MemLs;
-use webdav_handler::DavHandler;
+use webdav_handler::{DavConfig, DavHandler, DavMethodSet};
 
 #[tokio::main]
 async fn main() {
-    env_logger::Builder::from_env(
-        env_logger::Env::default()
-            .filter_or("IPFS_WEBDAV_LOG", "info")
-            .write_style("IPFS_WEBDAV_LOG_STYLE"),
-    )
-    .init();
-
-    let ipfs = std::env::var("IPFS_WEBDAV_API_ENDPOINT_URL")
-        .ok()
-        .map(|addr| {
-            IpfsClient::from_str(&addr).unwrap_or_else(|e| {
-                log::error!(
-                    "Failed to parse IPFS API endpoint URL `{}` specified by \
-                `IPFS_WEBDAV_API_ENDPOINT_URL` environment variable. It must be \
-                in URL form. For example, `http://localhost:5001`. Error details: {}",
-                    addr,
-                    e
-                );
-                std::process::exit(101)
-            })
+    let args = Args::parse();
+    let config = Config::load(&args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2)
+    });
+
+    if args.check_config {
+        print!("{}", toml::to_string(&config).unwrap());
+        return;
+    }
+
+    env_logger::Builder::new()
+        .parse_filters(&config.log)
+        .parse_write_style(&std::env::var("IPFS_WEBDAV_LOG_STYLE").unwrap_or_default())
+        .init();
+
+    let ipfs = config.ipfs_client();
+    let publish_delay = Duration::from_secs(config.ipns.publish_delay);
+    let pin_delay = Duration::from_secs(config.ipfs.pin_delay);
+
+    let auth = Auth::load(&config.auth)
+        .unwrap_or_else(|e| {
+            log::error!("{}", e);
+            std::process::exit(2)
         })
-        .unwrap_or_else(|| IpfsClient::default());
-
-    let addr: SocketAddr = std::env::var("IPFS_WEBDAV_LISTEN")
-        .ok()
-        .map(|addr| {
-            SocketAddr::from_str(&addr).unwrap_or_else(|_| {
-                log::error!(
-                    "Failed to parse listen address `{}` specified by \
-                `IPFS_WEBDAV_LISTEN` environment variable. It must be in `host:port` \
-                form. For example, `localhost:4918` or `0.0.0.0:4918`",
-                    addr
-                );
-                std::process::exit(101)
-            })
+        .map(Arc::new);
+    let plain_http = config.tls.as_ref().is_none_or(|tls| tls.listen.is_some());
+    if plain_http && auth.as_ref().is_some_and(|auth| auth.has_basic()) {
+        log::warn!(
+            "Basic authentication is enabled on a plain HTTP listener, so passwords \
+            are sent in clear text"
+        );
+    }
+
+    let access_log = config.access_log.as_ref().map(|access_log| {
+        AccessLog::open(access_log).unwrap_or_else(|e| {
+            log::error!("{}", e);
+            std::process::exit(2)
         })
-        .unwrap_or_else(|| ([127, 0, 0, 1], 4918).into());
+    });
+
+    let cache = MetadataCache::new(
+        config.cache.size,
+        Duration::from_secs(config.cache.ipns_ttl),
+        Duration::from_secs(config.cache.mfs_ttl),
+    );
+    let content_cache =
+        ContentCache::new((config.cache.content_size as usize).saturating_mul(1024 * 1024));
+    let read_ahead = config.read_ahead as usize;
+    let mfs = MfsFs {
+        ipfs: ipfs.clone(),
+        cache: cache.clone(),
+        read_ahead,
+        root: String::new(),
+    };
+    let mfs_root = config.mfs_root.as_ref().map(|root| MfsFs {
+        root: root.trim_end_matches('/').to_string(),
+        ..mfs.clone()
+    });
+    let uploads = Uploads::new(mfs.clone(), config.ipfs.staging_root.clone(), pin_delay);
+    if !config.read_only {
+        let uploads = uploads.clone();
+        tokio::spawn(async move { uploads.recover().await });
+    }
+    let aliases = |ty| {
+        config
+            .by_name
+            .then(|| Aliases::new(ipfs.clone(), cache.clone(), ty))
+    };
+    let (ipfs_aliases, ipns_aliases) = (aliases(IpfsOrIpns::Ipfs), aliases(IpfsOrIpns::Ipns));
+    let ipfs_fs = IpfsFs {
+        ipfs: ipfs.clone(),
+        ty: IpfsOrIpns::Ipfs,
+        cache: cache.clone(),
+        content_cache: content_cache.clone(),
+        read_ahead,
+        staging: None,
+        uploads: Some(uploads),
+        aliases: ipfs_aliases,
+    };
+    let staging = IpnsStaging::new(mfs.clone(), config.ipns.staging_root.clone(), publish_delay);
+    {
+        let staging = staging.clone();
+        tokio::spawn(async move { staging.load().await });
+    }
+    let root_fs = RootFs {
+        mfs: mfs.clone(),
+        ipfs: ipfs_fs.clone(),
+        ipns: IpfsFs {
+            ipfs,
+            ty: IpfsOrIpns::Ipns,
+            cache,
+            content_cache,
+            read_ahead,
+            staging: Some(staging),
+            uploads: None,
+            aliases: ipns_aliases,
+        },
+        pins: PinsFs {
+            remote: RemotePinsFs {
+                ipfs: ipfs_fs.ipfs.clone(),
+            },
+            ipfs: ipfs_fs,
+        },
+        mounts: Mounts::new(&config.mounts),
+        access: None,
+    };
+
+    let locksystem: Box<dyn DavLockSystem> = match config.locks.store {
+        LockStore::Memory => MemLs::new(),
+        LockStore::File => {
+            let path = config.locks.file.clone().unwrap();
+            FileLs::open(path).unwrap_or_else(|e| {
+                log::error!("{}", e);
+                std::process::exit(2)
+            })
+        }
+    };
 
+    let filesystem: Box<dyn DavFileSystem> = match &mfs_root {
+        Some(mfs_root) => Box::new(mfs_root.clone()),
+        None => Box::new(root_fs.clone()),
+    };
     let dav_server = DavHandler::builder()
         .autoindex(true)
-        .filesystem(Box::new(RootFs {
-            mfs: MfsFs { ipfs: ipfs.clone() },
-            ipfs: IpfsFs {
-                ipfs: ipfs.clone(),
-                ty: IpfsOrIpns::Ipfs,
-            },
-            ipns: IpfsFs {
-                ipfs,
-                ty: IpfsOrIpns::Ipns,
-            },
-        }))
-        .locksystem(MemLs::new())
+        .filesystem(filesystem)
+        .locksystem(locksystem.clone())
+        .methods(if config.read_only {
+            DavMethodSet::WEBDAV_RO
+        } else {
+            DavMethodSet::WEBDAV_RW
+        })
         .build_handler();
 
-    let make_service = hyper::service::make_service_fn(move |_| {
-        let dav_server = dav_server.clone();
-        async move {
-            let func = move |req: Request<_>| {
-                let log = RequestLog::on_request(&req);
-                let dav_server = dav_server.clone();
-                async move {
-                    let resp = dav_server.handle(req).await;
-                    log.on_response(&resp);
-                    Ok::<_, Infallible>(resp)
-                }
-            };
-            Ok::<_, Infallible>(hyper::service::service_fn(func))
-        }
+    let service = WebDavService {
+        dav_server,
+        locksystem,
+        auth,
+        root_fs,
+        mfs_root,
+        read_only: config.read_only,
+        put_locks: PathLocks::default(),
+        access_log,
+    };
+    let (http_addr, https_addr) = match &config.tls {
+        None => (Some(config.listen), None),
+        Some(tls) => match tls.listen {
+            Some(https_addr) => (Some(config.listen), Some(https_addr)),
+            None => (None, Some(config.listen)),
+        },
+    };
+
+    let http_server = http_addr.map(|addr| {
+        let service = service.clone();
+        let make_service = hyper::service::make_service_fn(move |conn: &AddrStream| {
+            let service = service.clone();
+            let remote_addr = conn.remote_addr();
+            async move { Ok::<_, Infallible>(service.into_service_fn(Some(remote_addr))) }
+        });
+        let server = hyper::Server::try_bind(&addr).unwrap_or_else(|e| {
+            match e.source() {
+                None => log::error!("Error binding to {}: {}", addr, e),
+                Some(s) => log::error!("Error binding to {}: {}", addr, s),
+            }
+            std::process::exit(101)
+        });
+        log::info!("Starting WebDAV server at http://{}", addr);
+        server.serve(make_service)
     });
 
-    let server = hyper::Server::try_bind(&addr).unwrap_or_else(|e| {
-        match e.source() {
-            None => log::error!("Error binding to {}: {}", addr, e),
-            Some(s) => log::error!("Error binding to {}: {}", addr, s),
+    let https_server = match (https_addr, &config.tls) {
+        (Some(addr), Some(tls)) => {
+            let resolver =
+                CertResolver::load(tls.cert.clone(), tls.key.clone()).unwrap_or_else(|e| {
+                    log::error!("{}", e);
+                    std::process::exit(2)
+                });
+            resolver.watch();
+            let incoming = tls::incoming(addr, resolver.acceptor())
+                .await
+                .unwrap_or_else(|e| {
+                    log::error!("Error binding to {}: {}", addr, e);
+                    std::process::exit(101)
+                });
+            let make_service =
+                hyper::service::make_service_fn(move |conn: &TlsStream<TcpStream>| {
+                    let service = service.clone();
+                    let remote_addr = conn.get_ref().0.peer_addr().ok();
+                    async move { Ok::<_, Infallible>(service.into_service_fn(remote_addr)) }
+                });
+            log::info!("Starting WebDAV server at https://{}", addr);
+            Some(
+                hyper::Server::builder(hyper::server::accept::from_stream(incoming))
+                    .serve(make_service),
+            )
         }
-        std::process::exit(101)
+        _ => None,
+    };
+
+    let metrics_server = config.metrics.as_ref().map(|metrics| {
+        let addr = metrics.listen;
+        let make_service = hyper::service::make_service_fn(|_| async {
+            Ok::<_, Infallible>(hyper::service::service_fn(|req| async move {
+                Ok::<_, Infallible>(METRICS.handle(&req))
+            }))
+        });
+        let server = hyper::Server::try_bind(&addr).unwrap_or_else(|e| {
+            log::error!("Error binding to {}: {}", addr, e);
+            std::process::exit(101)
+        });
+        log::info!("Serving metrics at http://{}/metrics", addr);
+        server.serve(make_service)
     });
 
-    log::info!("Starting WebDAV server at http://{}", addr);
+    let http_server = async move {
+        if let Some(server) = http_server {
+            let _ = server.await.map_err(|e| log::error!("server error: {}", e));
+        }
+    };
+    let https_server = async move {
+        if let Some(server) = https_server {
+            let _ = server.await.map_err(|e| log::error!("server error: {}", e));
+        }
+    };
+    let metrics_server = async move {
+        if let Some(server) = metrics_server {
+            let _ = server
+                .await
+                .map_err(|e| log::error!("metrics server error: {}", e));
+        }
+    };
+    futures::future::join3(http_server, https_server, metrics_server).await;
+}
 
-    let _ = server
-        .serve(make_service)
-        .await
-        .map_err(|e| log::error!("server error: {}", e));
+/// Authenticates a request and passes it to the WebDAV handler
+#[derive(Clone)]
+struct WebDavService {
+    dav_server: DavHandler,
+    /// The lock system of `dav_server`, for the requests handled before it
+    locksystem: Box<dyn DavLockSystem>,
+    auth: Option<Arc<Auth>>,
+    root_fs: RootFs,
+    /// Serves `mfs-root` bypassing `root_fs`, see `Config::mfs_root`
+    mfs_root: Option<MfsFs>,
+    read_only: bool,
+    put_locks: PathLocks,
+    access_log: Option<Arc<AccessLog>>,
+}
+
+impl WebDavService {
+    async fn handle(
+        &self,
+        req: Request<hyper::Body>,
+        remote_addr: Option<SocketAddr>,
+    ) -> Response<LoggedBody> {
+        let namespace = match &self.mfs_root {
+            Some(_) => "mfs",
+            None => metrics::namespace_label(&self.root_fs.mounts, req.uri().path()),
+        };
+        let mut log =
+            RequestLog::on_request(&req, remote_addr, self.access_log.as_ref(), namespace);
+        let resp = match self.auth.as_ref().map(|auth| auth.authenticate(&req)) {
+            None => handle_unavailable(self.handle_dav(req, &self.root_fs, None)).await,
+            Some(Ok(user)) => {
+                log.on_authenticated(&user.name);
+                let root_fs = self.root_fs.with_access(user.access);
+                handle_unavailable(self.handle_dav(req, &root_fs, Some(user.name))).await
+            }
+            Some(Err(resp)) => *resp,
+        };
+        log.on_response(resp)
+    }
+
+    async fn handle_dav(
+        &self,
+        req: Request<hyper::Body>,
+        root_fs: &RootFs,
+        principal: Option<String>,
+    ) -> Response<Body> {
+        let (ls, user) = (&*self.locksystem, principal.as_deref());
+        let req = if self.read_only {
+            req
+        } else if let Some(mfs_root) = &self.mfs_root {
+            match proppatch::handle(mfs_root, ls, user, req).await {
+                Ok(resp) => return resp,
+                Err(req) => req,
+            }
+        } else {
+            if let Err(e) = check_lock_access(root_fs, ls, user, &req) {
+                return copymove::status_response(copymove::fs_error_status(e));
+            }
+            let req = match proppatch::handle(root_fs, ls, user, req).await {
+                Ok(resp) => return resp,
+                Err(req) => req,
+            };
+            let req = match remote_pins::handle(root_fs, req).await {
+                Ok(resp) => return resp,
+                Err(req) => req,
+            };
+            let req = match pins::handle(root_fs, req).await {
+                Ok(resp) => return resp,
+                Err(req) => req,
+            };
+            match copymove::handle(root_fs, req).await {
+                Ok(resp) => return resp,
+                Err(req) => req,
+            }
+        };
+        let upload_path = match req.method().as_str() {
+            "PUT" | "MKCOL" => DavPath::new(req.uri().path()).ok(),
+            _ => None,
+        };
+        let _put_guard = if req.method() == Method::PUT {
+            // Keyed by the namespace path, so PUTs of a file through different
+            // mount points are serialized too
+            let path = upload_path
+                .as_ref()
+                .map(|path| root_fs.mount_path(path).unwrap_or_else(|| path.clone()))
+                .map(|path| path.as_url_string())
+                .unwrap_or_else(|| req.uri().path().to_string());
+            let guard = self.put_locks.lock(&path).await;
+            // `If-Match`/`If-None-Match` must be checked against the current
+            // metadata, not against a cached copy
+            let headers = req.headers();
+            if headers.contains_key(IF_MATCH) || headers.contains_key(IF_NONE_MATCH) {
+                if let Some(path) = &upload_path {
+                    match &self.mfs_root {
+                        Some(mfs_root) => mfs_root.forget(path),
+                        None => root_fs.forget(path).await,
+                    }
+                }
+            }
+            Some(guard)
+        } else {
+            None
+        };
+        let mut resp = match principal {
+            None => self.dav_server.handle(req).await,
+            Some(principal) => {
+                // `mfs-root` can't be used with per-user permissions, so
+                // `root_fs` has full access then
+                let filesystem: Box<dyn DavFileSystem> = match &self.mfs_root {
+                    Some(mfs_root) => Box::new(mfs_root.clone()),
+                    None => Box::new(root_fs.clone()),
+                };
+                let config = DavConfig::new().filesystem(filesystem).principal(principal);
+                self.dav_server.handle_with(config, req).await
+            }
+        };
+        // Content PUT or MKCOL'd into `/ipfs/<name>` is pinned, tell the
+        // client its CID
+        let uploaded = resp.status().is_success() && self.mfs_root.is_none();
+        if let Some(path) = upload_path.filter(|_| uploaded) {
+            if let Some(cid) = root_fs.upload_cid(&path).await {
+                let location = root_fs.url(&["/ipfs/", &cid].concat());
+                if let Ok(location) = HeaderValue::from_str(&location) {
+                    resp.headers_mut().insert(LOCATION, location);
+                }
+            }
+        }
+        resp
+    }
+
+    fn into_service_fn(
+        self,
+        remote_addr: Option<SocketAddr>,
+    ) -> impl hyper::service::Service<
+        Request<hyper::Body>,
+        Response = Response<LoggedBody>,
+        Error = Infallible,
+        Future = impl Future<Output = Result<Response<LoggedBody>, Infallible>> + Send,
+    > + Send {
+        hyper::service::service_fn(move |req| {
+            let service = self.clone();
+            async move { Ok::<_, Infallible>(service.handle(req, remote_addr).await) }
+        })
+    }
 }
 
+/// LOCK of an existing resource and UNLOCK don't reach `root_fs`, so the user
+/// must be able to write the path here. Users can always release their own locks
+fn check_lock_access(
+    root_fs: &RootFs,
+    locksystem: &dyn DavLockSystem,
+    principal: Option<&str>,
+    req: &Request<hyper::Body>,
+) -> Result<(), FsError> {
+    let unlock = match req.method().as_str() {
+        "LOCK" => false,
+        "UNLOCK" => true,
+        _ => return Ok(()),
+    };
+    // webdav-handler rejects invalid paths itself
+    let path = match DavPath::new(req.uri().path()) {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+    if unlock {
+        let token = req
+            .headers()
+            .get("Lock-Token")
+            .and_then(|token| token.to_str().ok())
+            .map(|token| token.trim().trim_matches(|c| c == '<' || c == '>'));
+        let owned = token.is_some_and(|token| {
+            locksystem
+                .discover(&path)
+                .iter()
+                .any(|lock| lock.token == token && lock.principal.as_deref() == principal)
+        });
+        if owned {
+            return Ok(());
+        }
+    }
+    root_fs.check_write(&path)
+}
+
+/// How many directory entries are stat'ed concurrently while listing a directory
+const READ_DIR_CONCURRENCY: usize = 16;
+
 #[derive(Clone)]
 pub enum IpfsOrIpns {
     Ipns,
@@ -121,44 +498,40 @@ pub enum IpfsOrIpns {
 }
 
 async fn stat_metadata(ipfs: &IpfsClient, ipfs_path: &str) -> Result<StaticMetadata, FsError> {
-    let response = ipfs
+    let block = ipfs
         .block_get(ipfs_path)
         .map_ok(|chunk| chunk.to_vec())
-        .try_concat()
-        .await;
-    let block_bytes = response.map_err(handle_error)?;
+        .try_concat();
+    let (block_bytes, cid) = futures::future::try_join(block, resolve_cid(ipfs, ipfs_path))
+        .await
+        .map_err(handle_error)?;
     let unixfs_data_result =
         node_data(&block_bytes).and_then(|block_data| UnixFs::try_from(block_data));
-    let data = match unixfs_data_result {
-        Ok(data) => data,
-        Err(_) => {
-            return Ok(StaticMetadata {
-                len: block_bytes.len() as u64,
-                is_dir: false,
-                is_unixfs: false,
-            })
-        }
+    let mut metadata = match unixfs_data_result {
+        Ok(data) => StaticMetadata::from_unixfs_data(&data),
+        Err(_) => StaticMetadata {
+            len: block_bytes.len() as u64,
+            is_dir: false,
+            is_unixfs: false,
+            modified: None,
+            mode: None,
+            cid: None,
+        },
     };
-    Ok(StaticMetadata::from_unixfs_data(&data))
+    metadata.cid = Some(cid);
+    Ok(metadata)
 }
 
-fn map_path(path: &DavPath) -> Result<&str, FsError> {
-    std::str::from_utf8(path.as_bytes()).map_err(|_| FsError::GeneralFailure)
-}
-
-fn handle_error(e: Error) -> FsError {
-    match &e {
-        Error::Api(e) => {
-            if e.code == 0
-                && (e.message == "file does not exist" || e.message.starts_with("no link named "))
-            {
-                return FsError::NotFound;
-            }
+/// Returns the CID of the node an IPFS path refers to
+async fn resolve_cid(ipfs: &IpfsClient, ipfs_path: &str) -> Result<String, Error> {
+    match ipfs_path.strip_prefix("/ipfs/") {
+        Some(cid) if !cid.trim_end_matches('/').contains('/') => {
+            Ok(cid.trim_end_matches('/').to_string())
         }
-        _ => {}
-    };
-
-    log::error!("Got an error from IPFS API: {}", e);
+        _ => Ok(ipfs.block_stat(ipfs_path).await?.key),
+    }
+}
 
-    return FsError::GeneralFailure;
+fn map_path(path: &DavPath) -> Result<&str, FsError> {
+    std::str::from_utf8(path.as_bytes()).map_err(|_| FsError::GeneralFailure)
 }
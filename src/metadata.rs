@@ -1,17 +1,24 @@
+use crate::cache::MetadataCache;
+use crate::ipfs_api_ext::{FilesLsFullEntry, FilesStatResponse, LsFullLink};
+use crate::ipfs_client::IpfsClient;
 use crate::{stat_metadata, IpfsOrIpns};
 use futures::FutureExt;
-use ipfs_api_backend_hyper::IpfsClient;
-use ipfs_api_prelude::response::{FilesEntry, FilesStatResponse, IpfsFileHeader};
 use std::fmt::Debug;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use unixfs_v1::{UnixFs, UnixFsType};
 use webdav_handler::fs::{DavDirEntry, DavMetaData, FsFuture, FsResult};
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub struct StaticMetadata {
     pub len: u64,
     pub is_dir: bool,
     pub is_unixfs: bool,
+    /// UnixFS 1.5 `mtime`, if the node has one
+    pub modified: Option<SystemTime>,
+    /// UnixFS 1.5 `mode` (permission bits only), if the node has one
+    pub mode: Option<u32>,
+    /// Used as a strong ETag
+    pub cid: Option<String>,
 }
 
 impl StaticMetadata {
@@ -20,6 +27,9 @@ impl StaticMetadata {
             len: 0,
             is_dir: true,
             is_unixfs: true,
+            modified: None,
+            mode: None,
+            cid: None,
         }
     }
 
@@ -28,15 +38,25 @@ impl StaticMetadata {
             len,
             is_dir: false,
             is_unixfs: true,
+            modified: None,
+            mode: None,
+            cid: None,
         }
     }
 
     pub fn from_unixfs_data(data: &UnixFs) -> StaticMetadata {
-        if data.Type == UnixFsType::Directory || data.Type == UnixFsType::HAMTShard {
-            StaticMetadata::new_dir()
-        } else {
-            StaticMetadata::new_file(data.filesize.unwrap())
-        }
+        let mut metadata =
+            if data.Type == UnixFsType::Directory || data.Type == UnixFsType::HAMTShard {
+                StaticMetadata::new_dir()
+            } else {
+                StaticMetadata::new_file(data.filesize.unwrap())
+            };
+        metadata.modified = data
+            .mtime
+            .as_ref()
+            .and_then(|mtime| unix_time(mtime.Seconds, mtime.FractionalNanoseconds.unwrap_or(0)));
+        metadata.mode = data.mode.map(|mode| mode & MODE_PERMISSIONS);
+        metadata
     }
 
     pub fn from_files_stat_response(stat: FilesStatResponse) -> StaticMetadata {
@@ -44,8 +64,30 @@ impl StaticMetadata {
             len: stat.size,
             is_dir: stat.typ == "directory",
             is_unixfs: true,
+            modified: stat
+                .mtime
+                .and_then(|secs| unix_time(secs, stat.mtime_nsecs.unwrap_or(0))),
+            mode: stat.mode.map(|mode| mode & MODE_PERMISSIONS),
+            cid: Some(stat.hash),
         }
     }
+
+    /// Permission bits, defaulting to `0755` for directories and `0644` for
+    /// files as the UnixFS spec says
+    pub fn mode(&self) -> u32 {
+        self.mode.unwrap_or(if self.is_dir { 0o755 } else { 0o644 })
+    }
+}
+
+/// The `mode` also may contain file type bits (e.g. IPFS reports Go's
+/// `os.FileMode`), only permission bits are kept
+const MODE_PERMISSIONS: u32 = 0o7777;
+
+/// Converts a UnixFS timestamp. Timestamps before the epoch are not supported
+/// by the WebDAV handler, so they are treated as missing
+fn unix_time(secs: i64, nanos: u32) -> Option<SystemTime> {
+    let secs = u64::try_from(secs).ok()?;
+    SystemTime::UNIX_EPOCH.checked_add(Duration::new(secs, nanos.min(999_999_999)))
 }
 
 impl DavMetaData for StaticMetadata {
@@ -54,7 +96,7 @@ impl DavMetaData for StaticMetadata {
     }
 
     fn modified(&self) -> FsResult<SystemTime> {
-        Ok(SystemTime::UNIX_EPOCH)
+        Ok(self.modified.unwrap_or(SystemTime::UNIX_EPOCH))
     }
 
     fn is_dir(&self) -> bool {
@@ -62,39 +104,51 @@ impl DavMetaData for StaticMetadata {
     }
 
     fn executable(&self) -> FsResult<bool> {
-        Ok(true)
+        Ok(self.mode() & 0o111 != 0)
+    }
+
+    fn etag(&self) -> Option<String> {
+        self.cid.clone()
     }
 }
 
 pub struct StaticLsEntry {
     name: String,
-    len: u64,
-    is_dir: bool,
+    metadata: StaticMetadata,
 }
 
 impl StaticLsEntry {
+    pub fn new(name: String, metadata: StaticMetadata) -> Self {
+        StaticLsEntry { name, metadata }
+    }
+
     pub fn new_dir(name: String) -> Self {
-        StaticLsEntry {
-            name,
-            len: 0,
-            is_dir: true,
-        }
+        StaticLsEntry::new(name, StaticMetadata::new_dir())
     }
 
-    pub fn from_files_entry(entry: FilesEntry) -> Self {
-        StaticLsEntry {
-            name: entry.name,
-            len: entry.size,
-            is_dir: entry.typ == 1,
-        }
+    pub fn from_files_entry(entry: FilesLsFullEntry) -> Self {
+        let mut metadata = if entry.typ == 1 {
+            StaticMetadata::new_dir()
+        } else {
+            StaticMetadata::new_file(entry.size)
+        };
+        metadata.mode = entry.mode.map(|mode| mode & MODE_PERMISSIONS);
+        StaticLsEntry::new(entry.name, metadata)
     }
 
-    pub fn from_ipfs_file_header(entry: IpfsFileHeader) -> Self {
-        StaticLsEntry {
-            name: entry.name,
-            len: entry.size,
-            is_dir: entry.typ == 1,
-        }
+    pub fn from_ls_link(entry: LsFullLink) -> Self {
+        let mut metadata = if entry.typ == 1 {
+            StaticMetadata::new_dir()
+        } else {
+            StaticMetadata::new_file(entry.size)
+        };
+        metadata.mode = entry.mode.map(|mode| mode & MODE_PERMISSIONS);
+        metadata.cid = Some(entry.hash);
+        StaticLsEntry::new(entry.name, metadata)
+    }
+
+    pub fn into_parts(self) -> (String, StaticMetadata) {
+        (self.name, self.metadata)
     }
 }
 
@@ -104,30 +158,23 @@ impl DavDirEntry for StaticLsEntry {
     }
 
     fn metadata<'a>(&'a self) -> FsFuture<Box<dyn DavMetaData>> {
-        async {
-            // let mut path = "/ipns/";
-            // path += name;
-            // self.ipfs.ls(&path).await;
-            // let stat = ipfs.files_stat(std::str::from_utf8(path.as_bytes()).unwrap()).await.map_err(|e| FsError::GeneralFailure)?;
-            Ok(Box::new(StaticMetadata {
-                len: self.len,
-                is_dir: self.is_dir,
-                is_unixfs: true,
-            }) as Box<dyn DavMetaData>)
-        }
-        .boxed()
+        let metadata = Box::new(self.metadata.clone()) as Box<dyn DavMetaData>;
+        Box::pin(futures::future::ready(Ok(metadata)))
     }
 }
 
 pub struct DynamicLsEntry {
     pub ipfs: IpfsClient,
+    pub cache: MetadataCache,
     pub ty: IpfsOrIpns,
     pub cid: String,
+    /// Listed instead of the CID (or the key ID), see `Aliases`
+    pub name: Option<String>,
 }
 
 impl DavDirEntry for DynamicLsEntry {
     fn name(&self) -> Vec<u8> {
-        self.cid.clone().into_bytes()
+        self.name.as_ref().unwrap_or(&self.cid).clone().into_bytes()
     }
 
     fn metadata<'a>(&'a self) -> FsFuture<Box<dyn DavMetaData>> {
@@ -137,7 +184,10 @@ impl DavDirEntry for DynamicLsEntry {
                 IpfsOrIpns::Ipfs => "/ipfs/",
             };
             let ipfs_path: String = [prefix, &self.cid].into_iter().collect();
-            let metadata = stat_metadata(&self.ipfs, &ipfs_path).await?;
+            let metadata = self
+                .cache
+                .metadata(&ipfs_path, stat_metadata(&self.ipfs, &ipfs_path))
+                .await?;
             Ok(Box::new(metadata) as Box<dyn DavMetaData>)
         }
         .boxed()
@@ -0,0 +1,177 @@
+use crate::mounts::{Mounted, Mounts};
+use crate::rootfs::in_namespace;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Method, Request, Response, StatusCode};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::sync::LazyLock;
+use std::time::Duration;
+use webdav_handler::davpath::DavPath;
+
+/// Prometheus metrics of the process, exported at `/metrics` of `metrics.listen`
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    ipfs_requests: IntCounterVec,
+    ipfs_request_duration: HistogramVec,
+    read_bytes: IntCounter,
+    written_bytes: IntCounter,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("ipfs_webdav".to_string()), None).unwrap();
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "WebDAV requests"),
+            &["method", "status", "namespace"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time until the response headers of a WebDAV request are sent",
+            ),
+            &["method", "status", "namespace"],
+        )
+        .unwrap();
+        let ipfs_requests = IntCounterVec::new(
+            Opts::new("ipfs_rpc_requests_total", "Calls of the IPFS RPC"),
+            &["path", "result"],
+        )
+        .unwrap();
+        let ipfs_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "ipfs_rpc_duration_seconds",
+                "Time until the IPFS RPC responds. For streaming calls like `cat`, \
+                time until the first chunk",
+            ),
+            &["path"],
+        )
+        .unwrap();
+        let read_bytes = IntCounter::new("file_read_bytes_total", "Bytes read from files").unwrap();
+        let written_bytes =
+            IntCounter::new("file_written_bytes_total", "Bytes written to files").unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry
+            .register(Box::new(request_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(ipfs_requests.clone())).unwrap();
+        registry
+            .register(Box::new(ipfs_request_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(read_bytes.clone())).unwrap();
+        registry.register(Box::new(written_bytes.clone())).unwrap();
+
+        Metrics {
+            registry,
+            requests,
+            request_duration,
+            ipfs_requests,
+            ipfs_request_duration,
+            read_bytes,
+            written_bytes,
+        }
+    }
+
+    /// `namespace` is the label of the request path, see `namespace_label`
+    pub fn on_request(
+        &self,
+        method: &Method,
+        namespace: &str,
+        status: StatusCode,
+        elapsed: Duration,
+    ) {
+        let labels = [method_label(method), status.as_str(), namespace];
+        self.requests.with_label_values(&labels).inc();
+        self.request_duration
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// `path` is the path of the RPC URL, e.g. `/api/v0/files/stat` or `/files/stat`
+    pub fn on_ipfs_request(&self, path: &str, ok: bool, elapsed: Duration) {
+        let path = path.strip_prefix("/api/v0").unwrap_or(path);
+        let path = path.trim_start_matches('/');
+        let result = if ok { "ok" } else { "error" };
+        self.ipfs_requests.with_label_values(&[path, result]).inc();
+        self.ipfs_request_duration
+            .with_label_values(&[path])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn on_read(&self, len: usize) {
+        self.read_bytes.inc_by(len as u64);
+    }
+
+    pub fn on_write(&self, len: usize) {
+        self.written_bytes.inc_by(len as u64);
+    }
+
+    /// Serves the metrics in the Prometheus text format
+    pub fn handle(&self, req: &Request<hyper::Body>) -> Response<hyper::Body> {
+        if req.uri().path() != "/metrics" {
+            let mut resp = Response::new(hyper::Body::empty());
+            *resp.status_mut() = StatusCode::NOT_FOUND;
+            return resp;
+        }
+        let encoder = TextEncoder::new();
+        let mut buf = Vec::new();
+        encoder.encode(&self.registry.gather(), &mut buf).unwrap();
+        let mut resp = Response::new(hyper::Body::from(buf));
+        resp.headers_mut()
+            .insert(CONTENT_TYPE, encoder.format_type().parse().unwrap());
+        resp
+    }
+}
+
+/// Unknown methods are merged to keep the number of label values bounded
+fn method_label(method: &Method) -> &str {
+    match method.as_str() {
+        "GET" | "HEAD" | "PUT" | "POST" | "DELETE" | "OPTIONS" | "PROPFIND" | "PROPPATCH"
+        | "MKCOL" | "COPY" | "MOVE" | "LOCK" | "UNLOCK" => method.as_str(),
+        _ => "other",
+    }
+}
+
+/// The namespace serving the request path, e.g. `mfs` for `/docs/a` if `/docs`
+/// is mounted from `mfs:/team/docs`. Directories above mount points are `root`
+pub fn namespace_label(mounts: &Mounts, path: &str) -> &'static str {
+    let path = match DavPath::new(path) {
+        Ok(path) => path,
+        Err(_) => return "other",
+    };
+    let path = match mounts.resolve(&path) {
+        Some(Mounted::Path(path)) => path,
+        Some(Mounted::Dir(_)) => return "root",
+        None => return "other",
+    };
+    ["mfs", "ipfs", "ipns", "pins"]
+        .into_iter()
+        .find(|namespace| in_namespace(&path, &["/", namespace].concat()).is_some())
+        .unwrap_or("other")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_paths_by_mounted_namespace() {
+        let mounts = Mounts::new(
+            &[("/docs", "mfs:/team/docs"), ("/a/site", "ipns:k51web")]
+                .iter()
+                .map(|(prefix, target)| (prefix.to_string(), target.to_string()))
+                .collect(),
+        );
+        assert_eq!(namespace_label(&mounts, "/docs/a.txt"), "mfs");
+        assert_eq!(namespace_label(&mounts, "/a/site"), "ipns");
+        assert_eq!(namespace_label(&mounts, "/"), "root");
+        assert_eq!(namespace_label(&mounts, "/a"), "root");
+        assert_eq!(namespace_label(&mounts, "/mfs"), "other");
+    }
+}
@@ -1,35 +1,54 @@
+use crate::cache::MetadataCache;
+use crate::ipfs_api_ext::IpfsClientExt;
+use crate::ipfs_client::IpfsClient;
 use crate::metadata::{StaticLsEntry, StaticMetadata};
+use crate::pins;
+use crate::props::{forbid_patch, IpfsProps};
 use crate::read::ReadOnlyDavFile;
+use crate::rootfs::in_namespace;
 use crate::write::WriteOnlyDavFile;
-use crate::{handle_error, map_path};
-use futures::{stream, FutureExt};
-use ipfs_api_backend_hyper::{request, IpfsApi, IpfsClient};
+use crate::{handle_error, map_path, READ_DIR_CONCURRENCY};
+use futures::{future, stream, FutureExt, StreamExt};
+use hyper::StatusCode;
+use ipfs_api_backend_hyper::{Error, IpfsApi};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::SystemTime;
 use webdav_handler::davpath::DavPath;
 use webdav_handler::fs::{
-    DavDirEntry, DavFile, DavFileSystem, DavMetaData, FsError, FsFuture, FsStream, OpenOptions,
-    ReadDirMeta,
+    DavDirEntry, DavFile, DavFileSystem, DavMetaData, DavProp, FsError, FsFuture, FsStream,
+    OpenOptions, ReadDirMeta,
 };
 
 #[derive(Clone)]
 pub struct MfsFs {
     pub ipfs: IpfsClient,
+    pub cache: MetadataCache,
+    /// See `ReadOnlyDavFile::with_read_ahead`
+    pub read_ahead: usize,
+    /// The MFS directory served as `/`, without a trailing slash. Empty for
+    /// the MFS root
+    pub root: String,
 }
 
 impl DavFileSystem for MfsFs {
     fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<Box<dyn DavFile>> {
         async move {
-            let path = map_path(path)?;
+            let path = &self.mfs_path(path)?;
             if options.write {
                 let file = WriteOnlyDavFile::new(
                     self.ipfs.clone(),
                     path.to_string(),
                     options.create || options.create_new,
                     options.truncate,
+                    self.cache.clone(),
+                    cache_key(path),
                 );
                 Ok(Box::new(file) as Box<dyn DavFile>)
             } else {
                 let stat = self.stat_metadata(path).await?;
-                let file = ReadOnlyDavFile::new_mfs(self.ipfs.clone(), path.to_string(), stat);
+                let file = ReadOnlyDavFile::new_mfs(self.ipfs.clone(), path.to_string(), stat)
+                    .with_read_ahead(self.read_ahead);
                 Ok(Box::new(file) as Box<dyn DavFile>)
             }
         }
@@ -39,33 +58,51 @@ impl DavFileSystem for MfsFs {
     fn read_dir<'a>(
         &'a self,
         path: &'a DavPath,
-        _: ReadDirMeta,
+        meta: ReadDirMeta,
     ) -> FsFuture<FsStream<Box<dyn DavDirEntry>>> {
         async move {
-            let path = map_path(path)?;
-            let ls = self
-                .ipfs
-                .files_ls_with_options(request::FilesLs {
-                    path: Some(path),
-                    long: Some(true),
-                    unsorted: None,
-                })
-                .await
-                .map_err(handle_error)?;
+            let path = &self.mfs_path(path)?;
+            let ls = async {
+                let ls = self.ipfs.files_ls_full(path).await.map_err(handle_error)?;
+                Ok(ls
+                    .entries
+                    .into_iter()
+                    .map(StaticLsEntry::from_files_entry)
+                    .map(StaticLsEntry::into_parts)
+                    .collect())
+            };
+            let entries = self.cache.ls(&cache_key(path), ls).await?.as_ref().clone();
+
+            if let ReadDirMeta::None = meta {
+                let dirs = entries.into_iter().map(move |(name, metadata)| {
+                    Box::new(StaticLsEntry::new(name, metadata)) as Box<dyn DavDirEntry>
+                });
+                return Ok(Box::pin(stream::iter(dirs)) as FsStream<Box<dyn DavDirEntry>>);
+            }
 
-            let dirs = ls
-                .entries
-                .into_iter()
-                .map(move |e| Box::new(StaticLsEntry::from_files_entry(e)) as Box<dyn DavDirEntry>);
+            // `files/ls` doesn't report modification times, so each entry is stat'ed
+            let this = self.clone();
+            let dir = path.trim_end_matches('/').to_string();
+            let dirs = stream::iter(entries)
+                .map(move |(name, metadata)| {
+                    let this = this.clone();
+                    let entry_path = [&dir, "/", &name].concat();
+                    async move {
+                        let metadata = this.stat_metadata(&entry_path).await.unwrap_or(metadata);
+                        StaticLsEntry::new(name, metadata)
+                    }
+                })
+                .buffered(READ_DIR_CONCURRENCY)
+                .map(|e| Box::new(e) as Box<dyn DavDirEntry>);
 
-            Ok(Box::pin(stream::iter(dirs)) as FsStream<Box<dyn DavDirEntry>>)
+            Ok(Box::pin(dirs) as FsStream<Box<dyn DavDirEntry>>)
         }
         .boxed()
     }
 
     fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<Box<dyn DavMetaData>> {
         async {
-            let path = map_path(path)?;
+            let path = &self.mfs_path(path)?;
             Ok(Box::new(self.stat_metadata(path).await?) as Box<dyn DavMetaData>)
         }
         .boxed()
@@ -73,106 +110,267 @@ impl DavFileSystem for MfsFs {
 
     fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<()> {
         async {
-            let path = map_path(path)?;
-            // TODO Exists, NotFound
-            self.ipfs
-                .files_mkdir(path, false)
-                .await
-                .map_err(handle_error)?;
-            Ok(())
+            let path = &self.mfs_path(path)?;
+            let result = self.ipfs.files_mkdir(path, false).await;
+            self.invalidate(path);
+            match result {
+                Ok(_) => Ok(()),
+                Err(e) => Err(self.mkdir_error(path, e).await),
+            }
         }
         .boxed()
     }
 
     fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<()> {
         async {
-            let path = map_path(path)?;
-            self.ipfs.files_rm(path, true).await.map_err(handle_error)?;
-            Ok(())
+            let path = &self.mfs_path(path)?;
+            let result = self.ipfs.files_rm(path, true).await;
+            self.invalidate(path);
+            result.map_err(handle_error)
         }
         .boxed()
     }
 
     fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<()> {
         async {
-            let path = map_path(path)?;
-            self.ipfs
-                .files_rm(path, false)
-                .await
-                .map_err(handle_error)?;
-            Ok(())
+            let path = &self.mfs_path(path)?;
+            let result = self.ipfs.files_rm(path, false).await;
+            self.invalidate(path);
+            result.map_err(handle_error)
         }
         .boxed()
     }
 
+    /// `RootFs` passes COPY and MOVE into `/mfs` to `transfer`, so `from` is
+    /// in this file system, which is served as the WebDAV root
     fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<()> {
         async {
-            if from.as_bytes() == &[b'/'] {
-                Err(FsError::Forbidden)
-            } else if from.as_bytes().starts_with(b"/mfs") {
-                let mut from = from.clone();
-                from.set_prefix("/mfs").unwrap();
-                let mut from = map_path(&from)?;
-                if from.ends_with("/") {
-                    from = &from[0..from.len() - 1];
-                }
-                self.ipfs
-                    .files_mv(from, map_path(to)?)
-                    .await
-                    .map_err(handle_error)
-            } else if from.as_bytes().starts_with(b"/ipfs") || from.as_bytes().starts_with(b"/ipns")
-            {
-                let mut from = map_path(from)?;
-                if from.ends_with("/") {
-                    from = &from[0..from.len() - 1];
-                }
-                self.ipfs
-                    .files_cp(from, map_path(to)?)
-                    .await
-                    .map_err(handle_error)
-            } else {
-                Err(FsError::Forbidden)
-            }
+            let from = self.own_source_path(from)?;
+            self.transfer(&from, true, to, true).await
         }
         .boxed()
     }
 
     fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<()> {
         async {
-            if from.as_bytes() == &[b'/'] {
-                Err(FsError::Forbidden)
-            } else if from.as_bytes().starts_with(b"/mfs") {
-                let mut from = from.clone();
-                from.set_prefix("/mfs").unwrap();
-                let mut from = map_path(&from)?;
-                if from.ends_with("/") {
-                    from = &from[0..from.len() - 1];
-                }
-                self.ipfs
-                    .files_cp(from, map_path(to)?)
-                    .await
-                    .map_err(handle_error)
-            } else if from.as_bytes().starts_with(b"/ipfs") || from.as_bytes().starts_with(b"/ipns")
-            {
-                let mut from = map_path(from)?;
-                if from.ends_with("/") {
-                    from = &from[0..from.len() - 1];
-                }
-                self.ipfs
-                    .files_cp(from, map_path(to)?)
-                    .await
-                    .map_err(handle_error)
-            } else {
-                Err(FsError::Forbidden)
-            }
+            let from = self.own_source_path(from)?;
+            self.transfer(&from, true, to, false).await
+        }
+        .boxed()
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<()> {
+        async move {
+            let path = &self.mfs_path(path)?;
+            let result = self.ipfs.files_touch(path, tm).await;
+            self.invalidate(path);
+            result.map_err(handle_error)
+        }
+        .boxed()
+    }
+
+    fn have_props<'a>(&'a self, _: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        Box::pin(future::ready(true))
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        _: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<Vec<(StatusCode, DavProp)>> {
+        Box::pin(future::ready(Ok(forbid_patch(patch))))
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<Vec<DavProp>> {
+        async move {
+            let path = &self.mfs_path(path)?;
+            Ok(self.ipfs_props(path).await?.to_dav_props(do_content))
+        }
+        .boxed()
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<Vec<u8>> {
+        async move {
+            let path = &self.mfs_path(path)?;
+            self.ipfs_props(path).await?.get(&prop)
         }
         .boxed()
     }
 }
 
 impl MfsFs {
+    /// Sets or clears all execute bits, like `chmod +x`/`chmod -x` does
+    /// Drops the cached metadata of `path`, so it is stat'ed again
+    pub fn forget(&self, path: &DavPath) {
+        if let Ok(path) = self.mfs_path(path) {
+            self.invalidate(&path);
+        }
+    }
+
+    pub async fn set_executable(&self, path: &DavPath, executable: bool) -> Result<(), FsError> {
+        let path = &self.mfs_path(path)?;
+        let mode = self.stat_metadata(path).await?.mode();
+        let mode = if executable {
+            mode | 0o111
+        } else {
+            mode & !0o111
+        };
+        let result = self.ipfs.files_chmod(path, mode).await;
+        self.invalidate(path);
+        result.map_err(handle_error)
+    }
+
+    /// Copies or moves `from` (a path returned by `source_path`) to `to`.
+    /// Only MFS content can be moved, `/ipfs` and `/ipns` are immutable
+    pub async fn transfer(
+        &self,
+        from: &str,
+        from_mfs: bool,
+        to: &DavPath,
+        is_move: bool,
+    ) -> Result<(), FsError> {
+        if map_path(to)?.trim_end_matches('/').is_empty() || (is_move && !from_mfs) {
+            return Err(FsError::Forbidden);
+        }
+        let to = &self.mfs_path(to)?;
+        let to = to.trim_end_matches('/');
+        let result = if is_move {
+            self.replacing(to, || self.ipfs.files_mv(from, to)).await
+        } else {
+            self.replacing(to, || self.ipfs.files_cp(from, to)).await
+        };
+        if is_move {
+            self.invalidate(from);
+        }
+        self.invalidate(to);
+        match result {
+            // The IPFS node failed to fetch the immutable content
+            Err(FsError::GeneralFailure) if !from_mfs => Err(FsError::IsRemote),
+            result => result,
+        }
+    }
+
+    /// Runs `files/cp` or `files/mv`, which refuse to replace an existing
+    /// entry. The WebDAV handler has already answered `Overwrite: F` with
+    /// `412` and removed an existing destination collection, so an entry
+    /// still in the way is removed and the operation is retried. Only the
+    /// "already has entry" error is retried: other errors mapped to `Exists`
+    /// (e.g. "is a directory") don't mean `to` is what is in the way
+    pub async fn replacing<F, Fut>(&self, to: &str, op: F) -> Result<(), FsError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<(), Error>>,
+    {
+        match op().await {
+            Err(Error::Api(e)) if e.message.contains("already has entry") => {
+                self.ipfs.files_rm(to, true).await.map_err(handle_error)?;
+                op().await.map_err(handle_error)
+            }
+            result => result.map_err(handle_error),
+        }
+    }
+
+    /// `files/mkdir` without `parents` fails atomically if the directory exists
+    /// or the parent is missing, but its messages differ between IPFS versions,
+    /// so the reason is found out by stat'ing. `Exists` and `NotFound` are
+    /// reported by `MKCOL` as `405` and `409` as RFC 4918 requires
+    async fn mkdir_error(&self, path: &str, e: Error) -> FsError {
+        let path = path.trim_end_matches('/');
+        if path.is_empty() || self.ipfs.files_stat(path).await.is_ok() {
+            return FsError::Exists;
+        }
+        let parent = match path.rfind('/') {
+            Some(0) => "/",
+            Some(i) => &path[..i],
+            None => return handle_error(e),
+        };
+        let parent_error = match self.ipfs.files_stat(parent).await {
+            Ok(stat) if stat.typ != "directory" => FsError::NotFound,
+            Ok(_) => FsError::GeneralFailure,
+            Err(parent_error) => handle_error(parent_error),
+        };
+        match parent_error {
+            FsError::NotFound => FsError::NotFound,
+            _ => handle_error(e),
+        }
+    }
+
+    /// The MFS path of `path`, which is relative to `root`
+    fn mfs_path(&self, path: &DavPath) -> Result<String, FsError> {
+        let path = map_path(path)?;
+        // webdav-handler resolves `..`, this is a safeguard against escaping `root`
+        if path.split('/').any(|segment| segment == "..") {
+            return Err(FsError::Forbidden);
+        }
+        Ok([self.root.as_str(), path].concat())
+    }
+
+    /// The MFS path of a COPY/MOVE source in this file system. Like the MFS
+    /// root in `source_path`, `/` can't be copied
+    fn own_source_path(&self, from: &DavPath) -> Result<String, FsError> {
+        if map_path(from)?.trim_end_matches('/').is_empty() {
+            return Err(FsError::Forbidden);
+        }
+        Ok(self.mfs_path(from)?.trim_end_matches('/').to_string())
+    }
+
+    /// Must be called after every mutation of `path`
+    fn invalidate(&self, path: &str) {
+        self.cache.invalidate(&cache_key(path));
+    }
+
+    async fn ipfs_props(&self, path: &str) -> Result<IpfsProps, FsError> {
+        let props = async {
+            let stat = self
+                .ipfs
+                .files_stat_full(path)
+                .await
+                .map_err(handle_error)?;
+            Ok(IpfsProps {
+                cid: stat.hash,
+                cumulative_size: Some(stat.cumulative_size),
+                blocks: Some(stat.blocks),
+                is_unixfs: true,
+            })
+        };
+        self.cache.props(&cache_key(path), props).await
+    }
+
     async fn stat_metadata(&self, path: &str) -> Result<StaticMetadata, FsError> {
-        let stat = self.ipfs.files_stat(path).await.map_err(handle_error)?;
-        Ok(StaticMetadata::from_files_stat_response(stat))
+        let stat = async {
+            let stat = self
+                .ipfs
+                .files_stat_full(path)
+                .await
+                .map_err(handle_error)?;
+            Ok(StaticMetadata::from_files_stat_response(stat))
+        };
+        self.cache.metadata(&cache_key(path), stat).await
     }
 }
+
+/// Maps the source of a COPY/MOVE (a full WebDAV path) to a path that can be
+/// passed to `files/cp` or `files/mv`. The boolean is `true` if the returned
+/// path refers to MFS. See `IpnsStaging::source_path` for staged IPNS names
+pub fn source_path(from: &DavPath) -> Result<(String, bool), FsError> {
+    let (path, is_mfs) = if let Some(from) = in_namespace(from, "/mfs") {
+        (map_path(&from)?.to_string(), true)
+    } else if in_namespace(from, "/ipfs").is_some() || in_namespace(from, "/ipns").is_some() {
+        (map_path(from)?.to_string(), false)
+    } else if let Some(ipfs_path) = pins::to_ipfs_path(from) {
+        (ipfs_path, false)
+    } else {
+        return Err(FsError::Forbidden);
+    };
+    let path = path.trim_end_matches('/');
+    // Neither the MFS root nor the `/ipfs` and `/ipns` roots can be copied
+    if path.is_empty() || path == "/ipfs" || path == "/ipns" {
+        return Err(FsError::Forbidden);
+    }
+    Ok((path.to_string(), is_mfs))
+}
+
+/// MFS paths are cached under `/mfs`, so they don't clash with IPFS paths
+fn cache_key(path: &str) -> String {
+    ["/mfs", path].concat()
+}
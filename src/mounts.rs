@@ -0,0 +1,260 @@
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use webdav_handler::davpath::DavPath;
+
+/// The namespaces a mount can refer to
+const NAMESPACES: [&str; 4] = ["mfs", "ipfs", "ipns", "pins"];
+
+/// Everything but the unreserved characters and `/` is encoded
+const PATH: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// A WebDAV path prefix served by a namespace, e.g. `/docs` by `/mfs/team/docs`
+struct Mount {
+    /// Without a trailing slash
+    prefix: String,
+    /// A namespace path without a trailing slash
+    target: String,
+}
+
+/// What a path is mounted as
+pub enum Mounted {
+    /// The namespace path serving it, e.g. `/mfs/team/docs/a` for `/docs/a`
+    Path(DavPath),
+    /// A directory above mount points (e.g. `/`), listing the names and the
+    /// paths of their next segments
+    Dir(Vec<(String, DavPath)>),
+}
+
+/// The mount table: WebDAV path prefixes and the namespace paths they are
+/// served by. `RootFs` maps a path with it before looking up the namespace,
+/// the same way `/ipfs` and `/ipns` aliases are resolved. Paths outside of
+/// the mount points and the directories above them don't exist.
+#[derive(Clone)]
+pub struct Mounts {
+    mounts: Arc<Vec<Mount>>,
+}
+
+impl Mounts {
+    /// `mounts` maps prefixes to targets, both validated by `parse_prefix` and
+    /// `parse_target`
+    pub fn new(mounts: &BTreeMap<String, String>) -> Self {
+        let mounts = mounts
+            .iter()
+            .map(|(prefix, target)| Mount {
+                prefix: prefix.clone(),
+                target: parse_target(target).unwrap(),
+            })
+            .collect();
+        Mounts {
+            mounts: Arc::new(mounts),
+        }
+    }
+
+    pub fn resolve(&self, path: &DavPath) -> Option<Mounted> {
+        let path = path.as_bytes();
+        for mount in self.mounts.iter() {
+            if let Some(rest) = strip_dir(path, mount.prefix.as_bytes()) {
+                let mut target = mount.target.as_bytes().to_vec();
+                target.extend_from_slice(rest);
+                let url = percent_encoding::percent_encode(&target, PATH).to_string();
+                return DavPath::new(&url).ok().map(Mounted::Path);
+            }
+        }
+        let dir = path.strip_suffix(b"/").unwrap_or(path);
+        let entries: BTreeMap<_, _> = self
+            .mounts
+            .iter()
+            .filter_map(|mount| strip_dir(mount.prefix.as_bytes(), dir))
+            .filter_map(|rest| rest.split(|&b| b == b'/').nth(1))
+            .filter_map(|name| {
+                let child = [dir, b"/", name].concat();
+                let url = percent_encoding::percent_encode(&child, PATH).to_string();
+                let path = DavPath::new(&url).ok()?;
+                Some((String::from_utf8_lossy(name).into_owned(), path))
+            })
+            .collect();
+        (dir.is_empty() || !entries.is_empty()).then(|| Mounted::Dir(entries.into_iter().collect()))
+    }
+
+    /// The reverse of `resolve`: the URL a URL-encoded namespace path, e.g.
+    /// `/ipfs/<cid>`, is served at. `None` if it is not mounted
+    pub fn url(&self, path: &str) -> Option<String> {
+        let decoded = percent_encoding::percent_decode_str(path).collect::<Vec<_>>();
+        self.mounts
+            .iter()
+            .filter_map(|mount| {
+                let rest = strip_dir(&decoded, mount.target.as_bytes())?;
+                Some((mount, rest))
+            })
+            .max_by_key(|(mount, _)| mount.target.len())
+            .map(|(mount, rest)| {
+                let mut url = mount.prefix.as_bytes().to_vec();
+                url.extend_from_slice(rest);
+                percent_encoding::percent_encode(&url, PATH).to_string()
+            })
+    }
+}
+
+/// The part of `path` after `dir`, if `path` is `dir` or is inside of it
+fn strip_dir<'p>(path: &'p [u8], dir: &[u8]) -> Option<&'p [u8]> {
+    path.strip_prefix(dir)
+        .filter(|rest| rest.is_empty() || rest.starts_with(b"/"))
+}
+
+/// `true` if one of the mount points (without trailing slashes) is the other
+/// one or is inside of it
+pub fn overlaps(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    strip_dir(a, b).is_some() || strip_dir(b, a).is_some()
+}
+
+/// Validates a mount point, returning it without a trailing slash
+pub fn parse_prefix(prefix: &str) -> Result<String, String> {
+    if !prefix.starts_with('/') {
+        return Err("mount points must be absolute WebDAV paths".to_string());
+    }
+    let segments = segments(prefix)?;
+    if segments.is_empty() {
+        return Err("`/` lists the mount points and can't be mounted".to_string());
+    }
+    Ok(["/", &segments.join("/")].concat())
+}
+
+/// Parses a mount target: a namespace followed by a path in it, e.g.
+/// `mfs:/team/docs`, `ipfs:<cid>`, `ipns:<name>` or `pins:`. Returns the
+/// namespace path, e.g. `/mfs/team/docs`
+pub fn parse_target(target: &str) -> Result<String, String> {
+    let (namespace, path) = target
+        .split_once(':')
+        .filter(|(namespace, _)| NAMESPACES.contains(namespace))
+        .ok_or_else(|| {
+            "targets must start with `mfs:`, `ipfs:`, `ipns:` or `pins:`. For example, \
+            `mfs:/team/docs` or `ipfs:<cid>`"
+                .to_string()
+        })?;
+    let mut namespace_path = ["/", namespace].concat();
+    for segment in segments(path)? {
+        namespace_path += "/";
+        namespace_path += segment;
+    }
+    Ok(namespace_path)
+}
+
+fn segments(path: &str) -> Result<Vec<&str>, String> {
+    let segments: Vec<_> = path.split('/').filter(|s| !s.is_empty()).collect();
+    if segments.iter().any(|s| *s == "." || *s == "..") {
+        return Err("`.` and `..` are not allowed".to_string());
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mount_table(mounts: &[(&str, &str)]) -> Mounts {
+        Mounts::new(
+            &mounts
+                .iter()
+                .map(|(prefix, target)| (prefix.to_string(), target.to_string()))
+                .collect::<BTreeMap<_, _>>(),
+        )
+    }
+
+    fn resolve(mounts: &Mounts, path: &str) -> Option<String> {
+        match mounts.resolve(&DavPath::new(path).unwrap())? {
+            Mounted::Path(path) => Some(path.as_url_string()),
+            Mounted::Dir(entries) => {
+                let names: Vec<_> = entries.into_iter().map(|(name, _)| name).collect();
+                Some(format!("dir {}", names.join(",")))
+            }
+        }
+    }
+
+    #[test]
+    fn resolves_paths_under_mount_points() {
+        let mounts = mount_table(&[
+            ("/docs", "mfs:/team/docs"),
+            ("/a/b/site", "ipns:k51web"),
+            ("/ipfs", "ipfs:"),
+        ]);
+        assert_eq!(resolve(&mounts, "/docs").as_deref(), Some("/mfs/team/docs"));
+        assert_eq!(
+            resolve(&mounts, "/docs/").as_deref(),
+            Some("/mfs/team/docs/")
+        );
+        assert_eq!(
+            resolve(&mounts, "/docs/a%20b.txt").as_deref(),
+            Some("/mfs/team/docs/a%20b.txt")
+        );
+        assert_eq!(
+            resolve(&mounts, "/ipfs/QmFile").as_deref(),
+            Some("/ipfs/QmFile")
+        );
+        assert_eq!(
+            resolve(&mounts, "/a/b/site/x").as_deref(),
+            Some("/ipns/k51web/x")
+        );
+        // Only whole segments match
+        assert_eq!(resolve(&mounts, "/docsx"), None);
+        assert_eq!(resolve(&mounts, "/mfs"), None);
+    }
+
+    #[test]
+    fn lists_directories_above_mount_points() {
+        let mounts = mount_table(&[("/docs", "mfs:/team/docs"), ("/a/b/site", "ipns:k51web")]);
+        assert_eq!(resolve(&mounts, "/").as_deref(), Some("dir a,docs"));
+        assert_eq!(resolve(&mounts, "/a").as_deref(), Some("dir b"));
+        assert_eq!(resolve(&mounts, "/a/b/").as_deref(), Some("dir site"));
+        assert_eq!(resolve(&mounts, "/a/c"), None);
+        assert_eq!(resolve(&mount_table(&[]), "/").as_deref(), Some("dir "));
+    }
+
+    #[test]
+    fn maps_namespace_paths_back_to_urls() {
+        let mounts = mount_table(&[
+            ("/docs", "mfs:/team/docs"),
+            ("/team", "mfs:/team"),
+            ("/cids", "ipfs:"),
+        ]);
+        assert_eq!(mounts.url("/ipfs/QmFile").as_deref(), Some("/cids/QmFile"));
+        // The most specific mount wins
+        assert_eq!(mounts.url("/mfs/team/docs/a").as_deref(), Some("/docs/a"));
+        assert_eq!(mounts.url("/mfs/team/b").as_deref(), Some("/team/b"));
+        assert_eq!(
+            mounts.url("/mfs/team/a%20b").as_deref(),
+            Some("/team/a%20b")
+        );
+        assert_eq!(mounts.url("/mfs/teams"), None);
+        assert_eq!(mounts.url("/ipns/k51web"), None);
+    }
+
+    #[test]
+    fn detects_overlapping_mount_points() {
+        assert!(overlaps("/docs", "/docs"));
+        assert!(overlaps("/docs", "/docs/a"));
+        assert!(overlaps("/docs/a", "/docs"));
+        assert!(!overlaps("/docs", "/docsx"));
+        assert!(!overlaps("/a/b", "/a/c"));
+    }
+
+    #[test]
+    fn parses_prefixes_and_targets() {
+        assert_eq!(parse_prefix("/docs/").unwrap(), "/docs");
+        assert_eq!(parse_prefix("//a//b").unwrap(), "/a/b");
+        assert!(parse_prefix("docs").is_err());
+        assert!(parse_prefix("/").is_err());
+        assert!(parse_prefix("/a/../b").is_err());
+        assert_eq!(parse_target("mfs:/team/docs/").unwrap(), "/mfs/team/docs");
+        assert_eq!(parse_target("pins:").unwrap(), "/pins");
+        assert_eq!(parse_target("ipfs:QmFile").unwrap(), "/ipfs/QmFile");
+        assert!(parse_target("files:/x").is_err());
+        assert!(parse_target("mfs:/a/./b").is_err());
+    }
+}
@@ -0,0 +1,428 @@
+use crate::copymove::{forbidden, fs_error_status, pinned_response, status_response};
+use crate::ipfs_client::IpfsClient;
+use crate::ipfs_error::timed_out;
+use crate::ipfs_fs::IpfsFs;
+use crate::metadata::{DynamicLsEntry, StaticLsEntry, StaticMetadata};
+use crate::props::forbid_patch;
+use crate::remote_pins::{RemotePinsFs, REMOTE};
+use crate::rootfs::RootFs;
+use crate::{handle_error, map_path, IpfsOrIpns};
+use cid::Cid;
+use futures::{future, stream, FutureExt};
+use hyper::{Request, Response, StatusCode, Uri};
+use ipfs_api_backend_hyper::IpfsApi;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use webdav_handler::body::Body;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{
+    DavDirEntry, DavFile, DavFileSystem, DavMetaData, DavProp, FsError, FsFuture, FsStream,
+    OpenOptions, ReadDirMeta,
+};
+
+/// How long a request waits for `pin/add`, see `pin_add`
+const PIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The directories of `/pins`
+#[derive(Clone, Copy, PartialEq)]
+pub enum PinType {
+    Recursive,
+    Direct,
+    /// Blocks pinned by a recursive pin. They can't be pinned or unpinned
+    Indirect,
+}
+
+impl PinType {
+    const ALL: [PinType; 3] = [PinType::Recursive, PinType::Direct, PinType::Indirect];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            PinType::Recursive => "recursive",
+            PinType::Direct => "direct",
+            PinType::Indirect => "indirect",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<PinType> {
+        PinType::ALL.into_iter().find(|ty| ty.as_str() == name)
+    }
+}
+
+/// Pins of the IPFS node by type. `/<type>/<cid>` exists for every pin of
+/// the type and serves the pinned content like `/ipfs/<cid>` does. Remote
+/// pins are in `/remote`, see `RemotePinsFs`.
+///
+/// The filesystem is read-only, pins are added and removed by `handle`
+#[derive(Clone)]
+pub struct PinsFs {
+    /// Serves the pinned content
+    pub ipfs: IpfsFs,
+    pub remote: RemotePinsFs,
+}
+
+enum PinsPath {
+    Root,
+    Type(PinType),
+    /// A pin and a path inside the pinned content (`/<cid>/...`)
+    Pin(PinType, String, DavPath),
+    /// A path inside `/remote`, relative to it
+    Remote(DavPath),
+}
+
+impl DavFileSystem for PinsFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<Box<dyn DavFile>> {
+        async move {
+            match parse(path)? {
+                PinsPath::Pin(ty, cid, content) if !options.write => {
+                    self.check_pinned(ty, &cid).await?;
+                    self.ipfs.open(&content, options).await
+                }
+                PinsPath::Remote(path) => self.remote.open(&path, options).await,
+                _ => Err(FsError::Forbidden),
+            }
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<FsStream<Box<dyn DavDirEntry>>> {
+        async move {
+            match parse(path)? {
+                PinsPath::Root => {
+                    let names = PinType::ALL.into_iter().map(PinType::as_str);
+                    let dirs = names.chain([REMOTE]).map(|name| {
+                        Box::new(StaticLsEntry::new_dir(name.to_string())) as Box<dyn DavDirEntry>
+                    });
+                    Ok(Box::pin(stream::iter(dirs)) as FsStream<Box<dyn DavDirEntry>>)
+                }
+                PinsPath::Type(ty) => {
+                    let pins = self
+                        .ipfs
+                        .ipfs
+                        .pin_ls(None, Some(ty.as_str()))
+                        .await
+                        .map_err(handle_error)?;
+                    let entries: Vec<_> = pins
+                        .keys
+                        .into_keys()
+                        .map(|cid| {
+                            Box::new(DynamicLsEntry {
+                                ipfs: self.ipfs.ipfs.clone(),
+                                cache: self.ipfs.cache.clone(),
+                                ty: IpfsOrIpns::Ipfs,
+                                cid,
+                                name: None,
+                            }) as Box<dyn DavDirEntry>
+                        })
+                        .collect();
+                    Ok(Box::pin(stream::iter(entries)) as FsStream<Box<dyn DavDirEntry>>)
+                }
+                PinsPath::Pin(ty, cid, content) => {
+                    self.check_pinned(ty, &cid).await?;
+                    self.ipfs.read_dir(&content, meta).await
+                }
+                PinsPath::Remote(path) => self.remote.read_dir(&path, meta).await,
+            }
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<Box<dyn DavMetaData>> {
+        async move {
+            match parse(path)? {
+                PinsPath::Root | PinsPath::Type(_) => {
+                    Ok(Box::new(StaticMetadata::new_dir()) as Box<dyn DavMetaData>)
+                }
+                PinsPath::Pin(ty, cid, content) => {
+                    self.check_pinned(ty, &cid).await?;
+                    self.ipfs.metadata(&content).await
+                }
+                PinsPath::Remote(path) => self.remote.metadata(&path).await,
+            }
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, _: &'a DavPath) -> FsFuture<()> {
+        Box::pin(future::ready(Err(FsError::Forbidden)))
+    }
+
+    fn have_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+    ) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        async move {
+            match parse(path) {
+                Ok(PinsPath::Pin(..)) => true,
+                Ok(PinsPath::Remote(path)) => self.remote.have_props(&path).await,
+                _ => false,
+            }
+        }
+        .boxed()
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        _: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<Vec<(StatusCode, DavProp)>> {
+        Box::pin(future::ready(Ok(forbid_patch(patch))))
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<Vec<DavProp>> {
+        async move {
+            match parse(path)? {
+                PinsPath::Pin(_, _, content) => self.ipfs.get_props(&content, do_content).await,
+                PinsPath::Remote(path) => self.remote.get_props(&path, do_content).await,
+                _ => Err(FsError::Forbidden),
+            }
+        }
+        .boxed()
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<Vec<u8>> {
+        async move {
+            match parse(path)? {
+                PinsPath::Pin(_, _, content) => self.ipfs.get_prop(&content, prop).await,
+                PinsPath::Remote(path) => self.remote.get_prop(&path, prop).await,
+                _ => Err(FsError::Forbidden),
+            }
+        }
+        .boxed()
+    }
+}
+
+impl PinsFs {
+    /// Pins `cid`. Returns `false` if it was already pinned with this type
+    pub async fn pin(&self, ty: PinType, cid: &str) -> Result<bool, FsError> {
+        if ty == PinType::Indirect {
+            return Err(FsError::Forbidden);
+        }
+        if self.is_pinned(ty, cid).await? {
+            return Ok(false);
+        }
+        let ipfs_path = ["/ipfs/", cid].concat();
+        log::info!("Pinning {} ({})", ipfs_path, ty.as_str());
+        pin_add(&self.ipfs.ipfs, &ipfs_path, ty == PinType::Recursive).await?;
+        Ok(true)
+    }
+
+    pub async fn unpin(&self, ty: PinType, cid: &str) -> Result<(), FsError> {
+        if ty == PinType::Indirect {
+            return Err(FsError::Forbidden);
+        }
+        self.check_pinned(ty, cid).await?;
+        let ipfs_path = ["/ipfs/", cid].concat();
+        log::info!("Unpinning {} ({})", ipfs_path, ty.as_str());
+        self.ipfs
+            .ipfs
+            .pin_rm(&ipfs_path, ty == PinType::Recursive)
+            .await
+            .map_err(handle_error)?;
+        Ok(())
+    }
+
+    /// Turns a direct pin into a recursive one or vice versa
+    pub async fn change_type(&self, cid: &str, from: PinType, to: PinType) -> Result<(), FsError> {
+        if from == to || from == PinType::Indirect || to == PinType::Indirect {
+            return Err(FsError::Forbidden);
+        }
+        self.check_pinned(from, cid).await?;
+        if to == PinType::Direct {
+            // A recursive pin is not replaced by `pin/add`
+            self.unpin(from, cid).await?;
+        }
+        // A direct pin is replaced by a recursive one
+        self.pin(to, cid).await.map(|_| ())
+    }
+
+    async fn is_pinned(&self, ty: PinType, cid: &str) -> Result<bool, FsError> {
+        let ipfs_path = ["/ipfs/", cid].concat();
+        match self
+            .ipfs
+            .ipfs
+            .pin_ls(Some(&ipfs_path), Some(ty.as_str()))
+            .await
+        {
+            Ok(pins) => Ok(!pins.keys.is_empty()),
+            Err(e) => match handle_error(e) {
+                FsError::NotFound => Ok(false),
+                e => Err(e),
+            },
+        }
+    }
+
+    async fn check_pinned(&self, ty: PinType, cid: &str) -> Result<(), FsError> {
+        match self.is_pinned(ty, cid).await? {
+            true => Ok(()),
+            false => Err(FsError::NotFound),
+        }
+    }
+}
+
+fn parse(path: &DavPath) -> Result<PinsPath, FsError> {
+    let mut segments = map_path(path)?.split('/').filter(|s| !s.is_empty());
+    let ty = match segments.next() {
+        Some(REMOTE) => {
+            let mut remote = path.clone();
+            remote
+                .set_prefix(&[path.prefix(), "/", REMOTE].concat())
+                .map_err(|_| FsError::GeneralFailure)?;
+            return Ok(PinsPath::Remote(remote));
+        }
+        Some(name) => PinType::from_name(name).ok_or(FsError::NotFound)?,
+        None => return Ok(PinsPath::Root),
+    };
+    let cid = match segments.next() {
+        Some(cid) => cid.to_string(),
+        None => return Ok(PinsPath::Type(ty)),
+    };
+    let mut content = path.clone();
+    content
+        .set_prefix(&[path.prefix(), "/", ty.as_str()].concat())
+        .map_err(|_| FsError::GeneralFailure)?;
+    Ok(PinsPath::Pin(ty, cid, content))
+}
+
+/// Extracts the type and the CID from `/pins/<type>/<cid>`
+pub fn pin_entry(path: &DavPath) -> Option<(PinType, String)> {
+    let path = std::str::from_utf8(path.as_bytes()).ok()?;
+    let rest = path.strip_prefix("/pins/")?;
+    let (ty, cid) = rest.strip_suffix('/').unwrap_or(rest).split_once('/')?;
+    if cid.is_empty() || cid.contains('/') {
+        return None;
+    }
+    Some((PinType::from_name(ty)?, cid.to_string()))
+}
+
+/// Maps `/pins/<type>/<cid>/...` to `/ipfs/<cid>/...`, for COPY from `/pins`
+pub fn to_ipfs_path(path: &DavPath) -> Option<String> {
+    let path = std::str::from_utf8(path.as_bytes()).ok()?;
+    let rest = path.strip_prefix("/pins/")?;
+    let (ty, content) = rest.split_once('/')?;
+    PinType::from_name(ty)?;
+    Some(["/ipfs/", content].concat())
+}
+
+const INDIRECT: &str = "Indirect pins are added and removed with the recursive pins \
+    referring to them\n";
+
+/// Pin management under `/pins`, which webdav-handler can't express:
+///
+/// - PUT of `/pins/<type>/<cid>` (the body is ignored) and COPY to it (the
+///   source is ignored) pin `<cid>`, reporting it in `Location`
+/// - DELETE of `/pins/<type>/<cid>` unpins `<cid>`
+/// - MOVE between `/pins/recursive/<cid>` and `/pins/direct/<cid>` changes
+///   the type of the pin
+///
+/// Other modifications of `/pins` are refused with `403 Forbidden`. COPY from
+/// `/pins` and requests outside of it are returned back to be passed to
+/// webdav-handler. Paths are checked as the mount table maps them.
+pub async fn handle(
+    fs: &RootFs,
+    req: Request<hyper::Body>,
+) -> Result<Response<Body>, Request<hyper::Body>> {
+    let path = match DavPath::new(req.uri().path()) {
+        Ok(path) => path,
+        Err(_) => return Err(req),
+    };
+    let mounted = fs.mount_path(&path);
+    let in_pins = mounted.as_ref().is_some_and(is_in_pins);
+    let entry = mounted.as_ref().and_then(pin_entry);
+    let to = destination(&req).and_then(|to| Some((fs.mount_path(&to)?, to)));
+    let resp = match (req.method().as_str(), to) {
+        ("PUT", _) if in_pins => add_pin(fs, &path, entry).await,
+        ("DELETE", _) if in_pins => match entry {
+            Some((PinType::Indirect, _)) => forbidden(INDIRECT),
+            Some(_) => match fs.remove_pin(&path).await {
+                Ok(()) => status_response(StatusCode::NO_CONTENT),
+                Err(e) => status_response(fs_error_status(e)),
+            },
+            None => forbidden("Only pins (/pins/<type>/<cid>) can be deleted\n"),
+        },
+        ("COPY", Some((to_mounted, to))) if is_in_pins(&to_mounted) => {
+            add_pin(fs, &to, pin_entry(&to_mounted)).await
+        }
+        ("MOVE", Some((to_mounted, to))) if is_in_pins(&to_mounted) => {
+            match (entry, pin_entry(&to_mounted)) {
+                (Some((PinType::Indirect, _)), _) | (_, Some((PinType::Indirect, _))) => {
+                    forbidden(INDIRECT)
+                }
+                (Some((_, from_cid)), Some((_, to_cid))) if from_cid == to_cid => {
+                    match fs.move_pin(&path, &to).await {
+                        Ok(()) => pinned_response(fs, &to_cid, true),
+                        Err(e) => status_response(fs_error_status(e)),
+                    }
+                }
+                _ => forbidden(
+                    "Pins can only be moved between /pins/recursive/<cid> and \
+                    /pins/direct/<cid>. COPY into /pins/<type>/<cid> pins <cid>\n",
+                ),
+            }
+        }
+        ("MOVE", Some(_)) if in_pins => {
+            forbidden("Pins can't be moved out of /pins. Use COPY and DELETE instead\n")
+        }
+        _ => return Err(req),
+    };
+    Ok(resp)
+}
+
+/// Runs `pin/add` for a request. Fetching the content may take long, so the
+/// request fails with `504 Gateway Timeout` after `PIN_TIMEOUT`, while the
+/// node keeps pinning in the background
+pub async fn pin_add(ipfs: &IpfsClient, ipfs_path: &str, recursive: bool) -> Result<(), FsError> {
+    let mut task = {
+        let (ipfs, ipfs_path) = (ipfs.clone(), ipfs_path.to_string());
+        tokio::spawn(async move { ipfs.pin_add(&ipfs_path, recursive).await.map(|_| ()) })
+    };
+    match tokio::time::timeout(PIN_TIMEOUT, &mut task).await {
+        Ok(Ok(result)) => result.map_err(handle_error),
+        Ok(Err(_)) => Err(FsError::GeneralFailure),
+        Err(_) => {
+            log::warn!("Still pinning {}, continuing in the background", ipfs_path);
+            let ipfs_path = ipfs_path.to_string();
+            tokio::spawn(async move {
+                match task.await {
+                    Ok(Ok(())) => log::info!("Pinned {}", ipfs_path),
+                    Ok(Err(e)) => log::error!("Failed to pin {}: {}", ipfs_path, e),
+                    Err(_) => {}
+                }
+            });
+            Err(timed_out())
+        }
+    }
+}
+
+/// `entry` is the `pin_entry` of the mounted `path`
+async fn add_pin(fs: &RootFs, path: &DavPath, entry: Option<(PinType, String)>) -> Response<Body> {
+    let cid = match entry {
+        Some((PinType::Indirect, _)) => return forbidden(INDIRECT),
+        Some((_, cid)) => cid,
+        None => {
+            return forbidden("Only /pins/<type>/<cid> can be created, which pins <cid>\n");
+        }
+    };
+    if Cid::try_from(cid.as_str()).is_err() {
+        return forbidden("The name of a pin must be a CID\n");
+    }
+    match fs.add_pin(path).await {
+        Ok(created) => pinned_response(fs, &cid, created),
+        Err(e) => status_response(fs_error_status(e)),
+    }
+}
+
+/// The percent-decoded `Destination`, see `copymove::paths`
+pub fn destination(req: &Request<hyper::Body>) -> Option<DavPath> {
+    let destination = req.headers().get("Destination")?.to_str().ok()?;
+    DavPath::new(destination.parse::<Uri>().ok()?.path()).ok()
+}
+
+fn is_in_pins(path: &DavPath) -> bool {
+    let path = path.as_bytes();
+    path == b"/pins" || path.starts_with(b"/pins/")
+}
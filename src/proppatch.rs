@@ -0,0 +1,361 @@
+use crate::copymove::{fs_error_status, status_response};
+use crate::mfs::MfsFs;
+use crate::rootfs::RootFs;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use hyper::body::HttpBody;
+use hyper::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Request, Response, StatusCode};
+use std::io::Cursor;
+use std::time::SystemTime;
+use webdav_handler::body::Body;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{DavFileSystem, DavMetaData, FsError};
+use webdav_handler::ls::DavLockSystem;
+use xmltree::{Element, XMLNode};
+
+const NS_DAV: &str = "DAV:";
+const NS_APACHE: &str = "http://apache.org/dav/props/";
+const NS_MS: &str = "urn:schemas-microsoft-com:";
+
+/// The same limit as webdav-handler's. Larger bodies are passed to it as is
+const MAX_BODY_SIZE: usize = 65536;
+
+/// A file system PROPPATCH is applied to: `RootFs`, or `MfsFs` serving
+/// `mfs-root`
+#[async_trait]
+pub trait PatchFs: DavFileSystem {
+    /// Changes the Apache `executable` property
+    async fn set_executable(&self, path: &DavPath, executable: bool) -> Result<(), FsError>;
+}
+
+#[async_trait]
+impl PatchFs for RootFs {
+    async fn set_executable(&self, path: &DavPath, executable: bool) -> Result<(), FsError> {
+        RootFs::set_executable(self, path, executable).await
+    }
+}
+
+#[async_trait]
+impl PatchFs for MfsFs {
+    async fn set_executable(&self, path: &DavPath, executable: bool) -> Result<(), FsError> {
+        MfsFs::set_executable(self, path, executable).await
+    }
+}
+
+/// A live property change that webdav-handler can't apply
+#[derive(Clone, Copy)]
+enum Change {
+    Modified(SystemTime),
+    Executable(bool),
+    /// Acknowledged without doing anything, like webdav-handler does
+    Ignored,
+}
+
+/// webdav-handler always answers `403 Forbidden` to a PROPPATCH of
+/// `getlastmodified` and the Apache `executable` property, and pretends to
+/// store `Win32LastModifiedTime`, so these requests are applied here.
+///
+/// Only requests that consist solely of such properties are handled, anything
+/// else is returned back to be passed to webdav-handler. Like webdav-handler,
+/// a resource locked by someone else can only be changed with the lock token
+/// in the `If` header, otherwise the answer is `423 Locked`.
+pub async fn handle(
+    fs: &impl PatchFs,
+    locksystem: &dyn DavLockSystem,
+    principal: Option<&str>,
+    req: Request<hyper::Body>,
+) -> Result<Response<Body>, Request<hyper::Body>> {
+    if req.method().as_str() != "PROPPATCH" {
+        return Err(req);
+    }
+    let too_large = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<usize>().ok())
+        .is_some_and(|len| len > MAX_BODY_SIZE);
+    if too_large {
+        return Err(req);
+    }
+    let (parts, body) = req.into_parts();
+    let body = match read_body(body).await {
+        Ok(body) => body,
+        Err(status) => return Ok(status_response(status)),
+    };
+    let changes = match parse(&body) {
+        Some(changes) => changes,
+        None => return Err(Request::from_parts(parts, hyper::Body::from(body))),
+    };
+    let path = match DavPath::new(parts.uri.path()) {
+        Ok(path) => path,
+        Err(_) => return Ok(status_response(StatusCode::BAD_REQUEST)),
+    };
+    let tokens = if_tokens(&parts.headers);
+    let tokens = tokens.iter().map(String::as_str).collect();
+    if locksystem
+        .check(&path, principal, false, false, tokens)
+        .is_err()
+    {
+        return Ok(status_response(StatusCode::LOCKED));
+    }
+
+    if changes.iter().any(|(_, change)| change.is_err()) {
+        // PROPPATCH is atomic, nothing is applied if a value is invalid
+        let results: Vec<_> = changes
+            .into_iter()
+            .map(|(prop, change)| (prop, change.err().unwrap_or(StatusCode::FAILED_DEPENDENCY)))
+            .collect();
+        return Ok(multistatus(&path, &results));
+    }
+    let changes: Vec<_> = changes
+        .into_iter()
+        .filter_map(|(prop, change)| Some((prop, change.ok()?)))
+        .collect();
+    let original = match fs.metadata(&path).await {
+        Ok(original) => original,
+        Err(e) => return Ok(status_response(fs_error_status(e))),
+    };
+
+    let mut failed = None;
+    for (i, (_, change)) in changes.iter().enumerate() {
+        if let Err(e) = apply(fs, &path, *change).await {
+            failed = Some((i, e));
+            break;
+        }
+    }
+    let results: Vec<_> = match failed {
+        None => changes
+            .into_iter()
+            .map(|(prop, _)| (prop, StatusCode::OK))
+            .collect(),
+        Some((failed, e)) => {
+            // The changes applied before the failure are reverted
+            for (_, change) in changes[..failed].iter().rev() {
+                let reverted = match revert(*change, original.as_ref()) {
+                    Some(revert) => apply(fs, &path, revert).await,
+                    None => Ok(()),
+                };
+                if let Err(e) = reverted {
+                    log::error!("Failed to revert a PROPPATCH of {:?}: {:?}", path, e);
+                }
+            }
+            changes
+                .into_iter()
+                .enumerate()
+                .map(|(i, (prop, _))| match i == failed {
+                    true => (prop, fs_error_status(e)),
+                    false => (prop, StatusCode::FAILED_DEPENDENCY),
+                })
+                .collect()
+        }
+    };
+    Ok(multistatus(&path, &results))
+}
+
+async fn apply(fs: &impl PatchFs, path: &DavPath, change: Change) -> Result<(), FsError> {
+    match change {
+        Change::Modified(tm) => fs.set_modified(path, tm).await,
+        Change::Executable(executable) => fs.set_executable(path, executable).await,
+        Change::Ignored => Ok(()),
+    }
+}
+
+/// The change that restores what `change` changes
+fn revert(change: Change, original: &dyn DavMetaData) -> Option<Change> {
+    match change {
+        Change::Modified(_) => original.modified().ok().map(Change::Modified),
+        Change::Executable(_) => original.executable().ok().map(Change::Executable),
+        Change::Ignored => None,
+    }
+}
+
+/// Reads a body of up to `MAX_BODY_SIZE` bytes
+async fn read_body(mut body: hyper::Body) -> Result<Bytes, StatusCode> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if buf.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.freeze())
+}
+
+/// The lock tokens submitted in the `If` header (RFC 4918, section 10.4):
+/// the Coded-URLs in its lists that are not negated with `Not`. Resource tags
+/// and ETags are skipped
+fn if_tokens(headers: &HeaderMap) -> Vec<String> {
+    let mut tokens = Vec::new();
+    for value in headers.get_all("If") {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        let mut in_list = false;
+        let mut negated = false;
+        let mut rest = value;
+        while let Some(c) = rest.chars().next() {
+            match c {
+                '(' => in_list = true,
+                ')' => in_list = false,
+                '<' | '[' => {
+                    let end = if c == '<' { '>' } else { ']' };
+                    let len = rest.find(end).unwrap_or(rest.len());
+                    if c == '<' && in_list && !negated {
+                        tokens.push(rest[1..len].to_string());
+                    }
+                    negated = false;
+                    rest = &rest[len..];
+                }
+                _ if rest.starts_with("Not") => {
+                    negated = true;
+                    rest = &rest[3..];
+                    continue;
+                }
+                _ => {}
+            }
+            rest = rest.get(c.len_utf8()..).unwrap_or_default();
+        }
+    }
+    tokens
+}
+
+/// Returns `None` if the request contains anything besides the properties
+/// handled here
+fn parse(body: &[u8]) -> Option<Vec<(Element, Result<Change, StatusCode>)>> {
+    let root = Element::parse(Cursor::new(body)).ok()?;
+    if root.name != "propertyupdate" || root.namespace.as_deref() != Some(NS_DAV) {
+        return None;
+    }
+    let mut changes = Vec::new();
+    for op in child_elements(&root) {
+        if op.name != "set" || op.namespace.as_deref() != Some(NS_DAV) {
+            return None;
+        }
+        for prop in child_elements(op) {
+            if prop.name != "prop" || prop.namespace.as_deref() != Some(NS_DAV) {
+                return None;
+            }
+            for prop in child_elements(prop) {
+                changes.push((prop.clone(), parse_change(prop)?));
+            }
+        }
+    }
+    if changes
+        .iter()
+        .all(|(_, change)| matches!(change, Ok(Change::Ignored)))
+    {
+        return None;
+    }
+    Some(changes)
+}
+
+fn parse_change(prop: &Element) -> Option<Result<Change, StatusCode>> {
+    let text = prop.get_text();
+    let time = || {
+        text.as_deref()
+            .and_then(|text| httpdate::parse_http_date(text.trim()).ok())
+            .map(Change::Modified)
+            .ok_or(StatusCode::CONFLICT)
+    };
+    match (prop.namespace.as_deref()?, prop.name.as_str()) {
+        (NS_DAV, "getlastmodified") => Some(time()),
+        (NS_MS, "Win32LastModifiedTime") => Some(time()),
+        (NS_APACHE, "executable") => Some(match text.as_deref().map(str::trim) {
+            Some("T") => Ok(Change::Executable(true)),
+            Some("F") => Ok(Change::Executable(false)),
+            _ => Err(StatusCode::CONFLICT),
+        }),
+        (NS_MS, "Win32CreationTime" | "Win32LastAccessTime" | "Win32FileAttributes") => {
+            Some(Ok(Change::Ignored))
+        }
+        _ => None,
+    }
+}
+
+fn child_elements(element: &Element) -> impl Iterator<Item = &Element> {
+    element.children.iter().filter_map(|node| match node {
+        XMLNode::Element(e) => Some(e),
+        _ => None,
+    })
+}
+
+fn multistatus(path: &DavPath, results: &[(Element, StatusCode)]) -> Response<Body> {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+        <D:multistatus xmlns:D=\"DAV:\"><D:response>",
+    );
+    xml += &format!("<D:href>{}</D:href>", escape(&path.as_url_string()));
+    for (prop, status) in results {
+        let name = match prop.namespace.as_deref() {
+            Some(NS_DAV) => format!("<D:{}/>", prop.name),
+            Some(ns) => format!("<{} xmlns=\"{}\"/>", prop.name, escape(ns)),
+            None => format!("<{}/>", prop.name),
+        };
+        xml += &format!(
+            "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 {}</D:status></D:propstat>",
+            name, status
+        );
+    }
+    xml += "</D:response></D:multistatus>\n";
+
+    let mut resp = Response::new(Body::from(xml));
+    *resp.status_mut() = StatusCode::MULTI_STATUS;
+    resp.headers_mut().insert(
+        CONTENT_TYPE,
+        "application/xml; charset=utf-8".parse().unwrap(),
+    );
+    resp
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(value: &str) -> Vec<String> {
+        let mut headers = HeaderMap::new();
+        headers.insert("If", value.parse().unwrap());
+        if_tokens(&headers)
+    }
+
+    #[test]
+    fn parses_if_tokens() {
+        assert_eq!(tokens("(<urn:uuid:a>)"), ["urn:uuid:a"]);
+        assert_eq!(
+            tokens("<http://host/a> (<urn:uuid:a> [\"etag\"]) (Not <urn:uuid:b>) (<urn:uuid:c>)"),
+            ["urn:uuid:a", "urn:uuid:c"]
+        );
+        assert_eq!(tokens("([\"etag\"])"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn parses_only_handled_properties() {
+        let body = |props: &str| {
+            format!(
+                "<propertyupdate xmlns=\"DAV:\" xmlns:A=\"{}\" xmlns:M=\"{}\">\
+                <set><prop>{}</prop></set></propertyupdate>",
+                NS_APACHE, NS_MS, props
+            )
+        };
+        let changes = parse(body("<A:executable>T</A:executable>").as_bytes()).unwrap();
+        assert!(matches!(changes[..], [(_, Ok(Change::Executable(true)))]));
+        let changes = parse(body("<getlastmodified>yesterday</getlastmodified>").as_bytes());
+        assert!(matches!(
+            changes.unwrap()[..],
+            [(_, Err(StatusCode::CONFLICT))]
+        ));
+        // Left to webdav-handler
+        assert!(parse(body("<A:executable>T</A:executable><displayname/>").as_bytes()).is_none());
+        assert!(
+            parse(body("<M:Win32FileAttributes>0</M:Win32FileAttributes>").as_bytes()).is_none()
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use cid::Cid;
+use hyper::StatusCode;
+use webdav_handler::fs::{DavProp, FsError, FsResult};
+
+/// Namespace of the read-only properties describing the IPFS node behind a path
+pub const NS_IPFS: &str = "ipfs:";
+const PREFIX_IPFS: &str = "ipfs";
+
+/// Values of the `ipfs:` properties
+#[derive(Clone)]
+pub struct IpfsProps {
+    pub cid: String,
+    /// `None` if the node is not a UnixFS node
+    pub cumulative_size: Option<u64>,
+    pub blocks: Option<u64>,
+    pub is_unixfs: bool,
+}
+
+impl IpfsProps {
+    pub fn to_dav_props(&self, do_content: bool) -> Vec<DavProp> {
+        self.values()
+            .into_iter()
+            .map(|(name, value)| to_dav_prop(name, &value, do_content))
+            .collect()
+    }
+
+    pub fn get(&self, prop: &DavProp) -> FsResult<Vec<u8>> {
+        if prop.namespace.as_deref() != Some(NS_IPFS) {
+            return Err(FsError::NotFound);
+        }
+        self.values()
+            .into_iter()
+            .find(|(name, _)| *name == prop.name)
+            .map(|(name, value)| to_xml(name, &value))
+            .ok_or(FsError::NotFound)
+    }
+
+    fn values(&self) -> Vec<(&'static str, String)> {
+        let mut values = vec![("cid", self.cid.clone())];
+        if let Some(codec) = codec_name(&self.cid) {
+            values.push(("codec", codec));
+        }
+        if let Some(cumulative_size) = self.cumulative_size {
+            values.push(("cumulative-size", cumulative_size.to_string()));
+        }
+        if let Some(blocks) = self.blocks {
+            values.push(("blocks", blocks.to_string()));
+        }
+        values.push(("is-unixfs", self.is_unixfs.to_string()));
+        values
+    }
+}
+
+/// Dead properties can't be stored, and the `ipfs:` ones are computed
+pub fn forbid_patch(patch: Vec<(bool, DavProp)>) -> Vec<(StatusCode, DavProp)> {
+    patch
+        .into_iter()
+        .map(|(_, prop)| (StatusCode::FORBIDDEN, prop))
+        .collect()
+}
+
+/// An `ipfs:` property, with the value if `do_content`
+pub fn to_dav_prop(name: &str, value: &str, do_content: bool) -> DavProp {
+    DavProp {
+        name: name.to_string(),
+        prefix: Some(PREFIX_IPFS.to_string()),
+        namespace: Some(NS_IPFS.to_string()),
+        xml: do_content.then(|| to_xml(name, value)),
+    }
+}
+
+pub fn to_xml(name: &str, value: &str) -> Vec<u8> {
+    // Remote pin names are arbitrary text
+    let value = value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    format!(
+        "<{prefix}:{name} xmlns:{prefix}=\"{ns}\">{value}</{prefix}:{name}>",
+        prefix = PREFIX_IPFS,
+        ns = NS_IPFS,
+    )
+    .into_bytes()
+}
+
+/// The multicodec name of the CID, e.g. `dag-pb`
+fn codec_name(cid: &str) -> Option<String> {
+    let codec = Cid::try_from(cid).ok()?.codec();
+    Some(match codec {
+        0x51 => "cbor".to_string(),
+        0x55 => "raw".to_string(),
+        0x70 => "dag-pb".to_string(),
+        0x71 => "dag-cbor".to_string(),
+        0x72 => "libp2p-key".to_string(),
+        0x78 => "git-raw".to_string(),
+        0x0129 => "dag-json".to_string(),
+        0x0200 => "json".to_string(),
+        _ => format!("0x{:x}", codec),
+    })
+}
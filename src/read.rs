@@ -1,26 +1,80 @@
+use crate::cache::{ContentCache, CONTENT_CHUNK_SIZE};
 use crate::ipfs_api_ext::IpfsClientExt;
+use crate::ipfs_client::IpfsClient;
+use crate::metrics::METRICS;
 use crate::{handle_error, StaticMetadata};
-use futures::{future, FutureExt, Stream, StreamExt};
+use bytes::BytesMut;
+use futures::{future, stream, FutureExt, Stream, StreamExt, TryStreamExt};
 use hyper::body::{Buf, Bytes};
-use ipfs_api_backend_hyper::{request, Error, IpfsApi, IpfsClient};
+use ipfs_api_backend_hyper::{request, Error, IpfsApi};
 use std::fmt::{Debug, Formatter};
+use std::io;
 use std::io::SeekFrom;
-use tokio::sync::Mutex;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
 use webdav_handler::fs::{DavFile, DavMetaData, FsError, FsFuture};
 
+type ByteStream = Box<dyn Stream<Item = Result<Bytes, Error>> + Send + Unpin>;
+
+/// Opens a stream of the file content starting at `offset` of at most `length` bytes
+type StreamSupplier = fn(
+    ipfs: &IpfsClient,
+    metadata: &StaticMetadata,
+    path: &str,
+    offset: Option<i64>,
+    length: Option<i64>,
+) -> ByteStream;
+
+/// Size of the ranged requests made by read-ahead
+const READ_AHEAD_SEGMENT_SIZE: u64 = 1024 * 1024;
+
 pub struct ReadOnlyDavFile {
     ipfs: IpfsClient,
     path: String,
     metadata: StaticMetadata,
-    stream_supplier: fn(
-        ipfs: &IpfsClient,
-        metadata: &StaticMetadata,
-        path: &str,
-        seek: Option<i64>,
-    ) -> Box<dyn Stream<Item = Result<Bytes, Error>> + Send + Unpin>,
-    seek: Option<i64>,
-    stream: Mutex<Option<Box<dyn Stream<Item = Result<Bytes, Error>> + Send + Unpin>>>,
+    stream_supplier: StreamSupplier,
+    /// Position of the next byte returned by `read_bytes`
+    pos: u64,
+    stream: Mutex<Option<ByteStream>>,
+    /// Position of the next byte of `stream`
+    stream_pos: u64,
+    /// Bytes that are not returned yet, starting at `pos`
     rest: Option<Bytes>,
+    /// `Some` if the content is immutable and is cached
+    content: Option<CachedContent>,
+    /// How many `READ_AHEAD_SEGMENT_SIZE` segments are fetched concurrently,
+    /// `0` means the file is read with a single request
+    read_ahead: usize,
+}
+
+/// Collects the bytes received from the stream into chunks of `ContentCache`.
+/// The stream is started at a chunk boundary, so every chunk is complete
+struct CachedContent {
+    cache: ContentCache,
+    cid: String,
+    chunk: BytesMut,
+    chunk_start: u64,
+}
+
+impl CachedContent {
+    /// Appends bytes received from the stream, putting complete chunks to the
+    /// cache. The last chunk of a file of `len` bytes may be shorter
+    fn fill(&mut self, mut b: &[u8], len: u64) {
+        while !b.is_empty() {
+            let n = b.len().min(CONTENT_CHUNK_SIZE as usize - self.chunk.len());
+            self.chunk.extend_from_slice(&b[..n]);
+            b = &b[n..];
+            let end = self.chunk_start + self.chunk.len() as u64;
+            if self.chunk.len() as u64 == CONTENT_CHUNK_SIZE || end == len {
+                let chunk = self.chunk.split().freeze();
+                self.cache
+                    .put(&self.cid, self.chunk_start / CONTENT_CHUNK_SIZE, chunk);
+                self.chunk_start = end;
+            }
+        }
+    }
 }
 
 impl ReadOnlyDavFile {
@@ -28,42 +82,213 @@ impl ReadOnlyDavFile {
         ipfs: IpfsClient,
         path: String,
         metadata: StaticMetadata,
-        stream_supplier: fn(
-            ipfs: &IpfsClient,
-            metadata: &StaticMetadata,
-            path: &str,
-            seek: Option<i64>,
-        ) -> Box<dyn Stream<Item = Result<Bytes, Error>> + Send + Unpin>,
+        stream_supplier: StreamSupplier,
     ) -> Self {
         ReadOnlyDavFile {
             ipfs,
             path,
             metadata,
             stream_supplier,
-            seek: None,
+            pos: 0,
             stream: Mutex::new(None),
+            stream_pos: 0,
             rest: None,
+            content: None,
+            read_ahead: 0,
         }
     }
 
+    /// Files with a known CID are read by the CID taken at open, so the
+    /// ranges fetched ahead all belong to the same version even if the file
+    /// is written meanwhile
     pub fn new_mfs(ipfs: IpfsClient, path: String, metadata: StaticMetadata) -> Self {
-        Self::new(ipfs, path, metadata, |ipfs, _, path, seek| {
-            ipfs.files_read_with_options(request::FilesRead {
-                path: &path,
-                offset: seek,
-                ..request::FilesRead::default()
-            })
-        })
-    }
-
-    pub fn new_ipfs(ipfs: IpfsClient, path: String, metadata: StaticMetadata) -> Self {
-        Self::new(ipfs, path, metadata, |ipfs, metadata, path, seek| {
-            if metadata.is_unixfs {
-                ipfs.cat_with_options(path, seek, None)
-            } else {
-                ipfs.block_get_with_options(path, seek, None)
+        let path = match &metadata.cid {
+            Some(cid) => ["/ipfs/", cid].concat(),
+            None => path,
+        };
+        Self::new(
+            ipfs,
+            path,
+            metadata,
+            |ipfs, metadata, path, offset, length| {
+                if metadata.cid.is_some() {
+                    ipfs.cat_with_options(path, offset, length)
+                } else {
+                    ipfs.files_read_with_options(request::FilesRead {
+                        path,
+                        offset,
+                        count: length,
+                    })
+                }
+            },
+        )
+    }
+
+    /// Content of files with a known CID is cached in `cache`. Such files
+    /// are read by the CID, so the content always matches the cache key even
+    /// if an `/ipns` name is republished meanwhile
+    pub fn new_ipfs(
+        ipfs: IpfsClient,
+        path: String,
+        metadata: StaticMetadata,
+        cache: ContentCache,
+    ) -> Self {
+        let content = match &metadata.cid {
+            Some(cid) if cache.is_enabled() => Some(CachedContent {
+                cache,
+                cid: cid.clone(),
+                chunk: BytesMut::new(),
+                chunk_start: 0,
+            }),
+            _ => None,
+        };
+        let path = match &content {
+            Some(content) => ["/ipfs/", &content.cid].concat(),
+            None => path,
+        };
+        let mut file = Self::new(
+            ipfs,
+            path,
+            metadata,
+            |ipfs, metadata, path, offset, length| {
+                if metadata.is_unixfs {
+                    ipfs.cat_with_options(path, offset, length)
+                } else {
+                    ipfs.block_get_with_options(path, offset, length)
+                }
+            },
+        );
+        file.content = content;
+        file
+    }
+
+    /// Fetches up to `segments` MiB ahead of the client
+    pub fn with_read_ahead(mut self, segments: usize) -> Self {
+        self.read_ahead = segments;
+        self
+    }
+
+    /// Returns the rest of the cached chunk containing `pos`, if any
+    fn read_cached(&mut self) -> Option<Bytes> {
+        let content = self.content.as_mut()?;
+        let chunk = content
+            .cache
+            .get(&content.cid, self.pos / CONTENT_CHUNK_SIZE)?;
+        let offset = (self.pos % CONTENT_CHUNK_SIZE) as usize;
+        if offset >= chunk.len() {
+            return None;
+        }
+        // The stream is behind `pos` now
+        *self.stream.get_mut() = None;
+        Some(chunk.slice(offset..))
+    }
+
+    /// Returns the next bytes of the stream starting at `pos`, (re)opening it
+    /// if needed. Cached files are streamed from the start of the chunk
+    /// containing `pos`, the bytes before `pos` only fill the cache
+    async fn read_stream(&mut self) -> Result<Bytes, FsError> {
+        loop {
+            if self.stream.get_mut().is_none() {
+                self.stream_pos = match &mut self.content {
+                    Some(content) => {
+                        content.chunk.clear();
+                        content.chunk_start = self.pos - self.pos % CONTENT_CHUNK_SIZE;
+                        content.chunk_start
+                    }
+                    None => self.pos,
+                };
+                let stream = self.open_stream(self.stream_pos);
+                *self.stream.get_mut() = Some(stream);
+            }
+            let stream = self.stream.get_mut().as_mut().unwrap();
+            let b = match stream.next().await {
+                Some(next) => next.map_err(handle_error)?,
+                None => return Err(FsError::GeneralFailure),
+            };
+            let b_start = self.stream_pos;
+            self.stream_pos += b.len() as u64;
+            if let Some(content) = &mut self.content {
+                content.fill(&b, self.metadata.len);
+            }
+            if self.stream_pos > self.pos {
+                return Ok(b.slice((self.pos - b_start) as usize..));
+            }
+        }
+    }
+
+    /// Files larger than a segment are fetched with read-ahead if it's enabled
+    fn open_stream(&self, start: u64) -> ByteStream {
+        if self.read_ahead > 0 && self.metadata.len.saturating_sub(start) > READ_AHEAD_SEGMENT_SIZE
+        {
+            return Box::new(ReadAhead::spawn(self, start));
+        }
+        let offset = (start > 0).then(|| i64::try_from(start).unwrap());
+        (self.stream_supplier)(&self.ipfs, &self.metadata, &self.path, offset, None)
+    }
+}
+
+/// Fetches a file from `start` to the end in `READ_AHEAD_SEGMENT_SIZE` ranged
+/// requests, keeping up to `read_ahead` of them in flight in a background
+/// task, so the next segments are being downloaded while the client receives
+/// the current one. The task is aborted when the stream is dropped (e.g. on
+/// seek). Its errors are converted by the reader, in the request's scope of
+/// `handle_unavailable`
+struct ReadAhead {
+    segments: mpsc::Receiver<Result<Bytes, Error>>,
+    task: JoinHandle<()>,
+}
+
+impl ReadAhead {
+    fn spawn(file: &ReadOnlyDavFile, start: u64) -> ReadAhead {
+        let ipfs = file.ipfs.clone();
+        let metadata = file.metadata.clone();
+        let path = file.path.clone();
+        let stream_supplier = file.stream_supplier;
+        let read_ahead = file.read_ahead;
+        let (tx, segments) = mpsc::channel(1);
+        let task = tokio::spawn(async move {
+            let len = metadata.len;
+            let mut fetched = stream::iter((start..len).step_by(READ_AHEAD_SEGMENT_SIZE as usize))
+                .map(|offset| {
+                    let length = READ_AHEAD_SEGMENT_SIZE.min(len - offset);
+                    let (o, l) = (Some(offset as i64), Some(length as i64));
+                    stream_supplier(&ipfs, &metadata, &path, o, l)
+                        .try_fold(BytesMut::new(), |mut segment, b| {
+                            segment.extend_from_slice(&b);
+                            future::ready(Ok(segment))
+                        })
+                        .map(move |segment| match segment {
+                            Ok(segment) if segment.len() as u64 == length => Ok(segment.freeze()),
+                            // E.g. an MFS file was truncated meanwhile
+                            Ok(_) => Err(Error::IpfsClientError(
+                                io::Error::from(io::ErrorKind::UnexpectedEof).into(),
+                            )),
+                            Err(e) => Err(e),
+                        })
+                })
+                .buffered(read_ahead);
+            while let Some(segment) = fetched.next().await {
+                let failed = segment.is_err();
+                if tx.send(segment).await.is_err() || failed {
+                    break;
+                }
             }
-        })
+        });
+        ReadAhead { segments, task }
+    }
+}
+
+impl Stream for ReadAhead {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.segments.poll_recv(cx)
+    }
+}
+
+impl Drop for ReadAhead {
+    fn drop(&mut self) {
+        self.task.abort();
     }
 }
 
@@ -76,7 +301,7 @@ impl Debug for ReadOnlyDavFile {
 impl DavFile for ReadOnlyDavFile {
     fn metadata<'a>(&'a mut self) -> FsFuture<Box<dyn DavMetaData>> {
         Box::pin(future::ready(Ok(
-            Box::new(self.metadata) as Box<dyn DavMetaData>
+            Box::new(self.metadata.clone()) as Box<dyn DavMetaData>
         )))
     }
 
@@ -90,40 +315,34 @@ impl DavFile for ReadOnlyDavFile {
 
     fn read_bytes(&mut self, count: usize) -> FsFuture<Bytes> {
         async move {
-            if let Some(mut b) = self.rest.take() {
-                if count < b.len() {
-                    self.rest = Some(b.split_off(count));
-                }
-                return Ok(b);
-            }
-            let stream = self.stream.get_mut().get_or_insert_with(|| {
-                (self.stream_supplier)(&self.ipfs, &self.metadata, &self.path, self.seek)
-            });
-            let next = match stream.next().await {
-                Some(next) => next.map_err(handle_error),
-                None => Err(FsError::GeneralFailure),
+            let mut b = match self.rest.take() {
+                Some(b) => b,
+                None => match self.read_cached() {
+                    Some(b) => b,
+                    None => self.read_stream().await?,
+                },
             };
-            match next {
-                Ok(mut b) => {
-                    if count < b.len() {
-                        self.rest = Some(b.split_off(count));
-                    }
-                    Ok(b)
-                }
-                Err(e) => Err(e),
+            if count < b.len() {
+                self.rest = Some(b.split_off(count));
             }
+            self.pos += b.len() as u64;
+            METRICS.on_read(b.len());
+            Ok(b)
         }
         .boxed()
     }
 
     fn seek(&mut self, pos: SeekFrom) -> FsFuture<u64> {
-        let start = match pos {
-            SeekFrom::Start(start) => start,
-            _ => panic!("seek must be SeekFrom::Start"),
+        let start = match seek_position(pos, self.pos, self.metadata.len) {
+            Ok(start) => start,
+            Err(e) => return Box::pin(future::ready(Err(e))),
         };
-        self.seek = Some(i64::try_from(start).unwrap());
-        *self.stream.get_mut() = None;
-        self.rest = None;
+        // `SeekFrom::Current(0)` only queries the position
+        if start != self.pos {
+            self.pos = start;
+            *self.stream.get_mut() = None;
+            self.rest = None;
+        }
         Box::pin(future::ready(Ok(start)))
     }
 
@@ -131,3 +350,17 @@ impl DavFile for ReadOnlyDavFile {
         Box::pin(future::ready(Ok(())))
     }
 }
+
+/// Resolves the target of `seek` like `lseek` does: positions past the end
+/// are allowed, negative ones (and ones not representable as an IPFS API
+/// offset) are not
+pub fn seek_position(pos: SeekFrom, current: u64, len: u64) -> Result<u64, FsError> {
+    let (base, offset) = match pos {
+        SeekFrom::Start(start) => (start, 0),
+        SeekFrom::Current(offset) => (current, offset),
+        SeekFrom::End(offset) => (len, offset),
+    };
+    base.checked_add_signed(offset)
+        .filter(|pos| i64::try_from(*pos).is_ok())
+        .ok_or(FsError::GeneralFailure)
+}
@@ -0,0 +1,370 @@
+use crate::copymove::{forbidden, fs_error_status, status_response};
+use crate::ipfs_api_ext::{IpfsClientExt, RemotePin};
+use crate::ipfs_client::IpfsClient;
+use crate::metadata::{StaticLsEntry, StaticMetadata};
+use crate::pins::destination;
+use crate::props::{forbid_patch, to_dav_prop, to_xml, NS_IPFS};
+use crate::rootfs::RootFs;
+use crate::{handle_error, map_path};
+use cid::Cid;
+use futures::{future, stream, FutureExt};
+use hyper::header::LOCATION;
+use hyper::{Request, Response, StatusCode};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use webdav_handler::body::Body;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{
+    DavDirEntry, DavFile, DavFileSystem, DavMetaData, DavProp, FsError, FsFuture, FsStream,
+    OpenOptions, ReadDirMeta,
+};
+
+/// The directory of remote pins in `/pins`
+pub const REMOTE: &str = "remote";
+
+/// Remote pins of the pinning services configured in the IPFS node
+/// (`ipfs pin remote service add`). `/<service>/<cid>` is an empty file for
+/// every CID pinned by the service, whatever the state of the pin is. The
+/// state and the name of the pin are its `ipfs:remote-pin-status` and
+/// `ipfs:remote-pin-name` properties.
+///
+/// The content is not served, the node may not have it. The filesystem is
+/// read-only, remote pins are added and removed by `handle`
+#[derive(Clone)]
+pub struct RemotePinsFs {
+    pub ipfs: IpfsClient,
+}
+
+enum RemotePath {
+    Root,
+    Service(String),
+    Pin(String, String),
+}
+
+impl DavFileSystem for RemotePinsFs {
+    fn open<'a>(&'a self, _: &'a DavPath, _: OpenOptions) -> FsFuture<Box<dyn DavFile>> {
+        Box::pin(future::ready(Err(FsError::Forbidden)))
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        _: ReadDirMeta,
+    ) -> FsFuture<FsStream<Box<dyn DavDirEntry>>> {
+        async move {
+            let entries: Vec<_> = match parse(path)? {
+                RemotePath::Root => self
+                    .services()
+                    .await?
+                    .into_iter()
+                    .map(|service| {
+                        Box::new(StaticLsEntry::new_dir(service)) as Box<dyn DavDirEntry>
+                    })
+                    .collect(),
+                RemotePath::Service(service) => {
+                    self.check_service(&service).await?;
+                    self.remote_pins(&service, None)
+                        .await?
+                        .into_values()
+                        .map(|pin| {
+                            let metadata = pin_metadata(&pin);
+                            Box::new(StaticLsEntry::new(pin.cid, metadata)) as Box<dyn DavDirEntry>
+                        })
+                        .collect()
+                }
+                RemotePath::Pin(..) => return Err(FsError::Forbidden),
+            };
+            Ok(Box::pin(stream::iter(entries)) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<Box<dyn DavMetaData>> {
+        async move {
+            let metadata = match parse(path)? {
+                RemotePath::Root => StaticMetadata::new_dir(),
+                RemotePath::Service(service) => {
+                    self.check_service(&service).await?;
+                    StaticMetadata::new_dir()
+                }
+                RemotePath::Pin(service, cid) => {
+                    pin_metadata(&self.remote_pin(&service, &cid).await?)
+                }
+            };
+            Ok(Box::new(metadata) as Box<dyn DavMetaData>)
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, _: &'a DavPath) -> FsFuture<()> {
+        Box::pin(future::ready(Err(FsError::Forbidden)))
+    }
+
+    fn have_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+    ) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        Box::pin(future::ready(matches!(
+            parse(path),
+            Ok(RemotePath::Pin(..))
+        )))
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        _: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<Vec<(StatusCode, DavProp)>> {
+        Box::pin(future::ready(Ok(forbid_patch(patch))))
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<Vec<DavProp>> {
+        async move {
+            match parse(path)? {
+                RemotePath::Pin(service, cid) => {
+                    let pin = self.remote_pin(&service, &cid).await?;
+                    Ok(pin_props(&pin)
+                        .into_iter()
+                        .map(|(name, value)| to_dav_prop(name, value, do_content))
+                        .collect())
+                }
+                _ => Err(FsError::Forbidden),
+            }
+        }
+        .boxed()
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<Vec<u8>> {
+        async move {
+            let (service, cid) = match parse(path)? {
+                RemotePath::Pin(service, cid) => (service, cid),
+                _ => return Err(FsError::NotFound),
+            };
+            if prop.namespace.as_deref() != Some(NS_IPFS) {
+                return Err(FsError::NotFound);
+            }
+            let pin = self.remote_pin(&service, &cid).await?;
+            pin_props(&pin)
+                .into_iter()
+                .find(|(name, _)| *name == prop.name)
+                .map(|(name, value)| to_xml(name, value))
+                .ok_or(FsError::NotFound)
+        }
+        .boxed()
+    }
+}
+
+impl RemotePinsFs {
+    /// Asks the `service` to pin `cid` as `name`. Returns `false` if the
+    /// service already has a pin of `cid` that hasn't failed
+    pub async fn pin(&self, service: &str, cid: &str, name: &str) -> Result<bool, FsError> {
+        self.check_service(service).await?;
+        if let Some(pin) = self.remote_pins(service, Some(cid)).await?.remove(cid) {
+            if pin.status != "failed" {
+                return Ok(false);
+            }
+        }
+        let ipfs_path = ["/ipfs/", cid].concat();
+        log::info!("Pinning {} remotely ({}) as `{}`", ipfs_path, service, name);
+        self.ipfs
+            .pin_remote_add(&ipfs_path, service, name)
+            .await
+            .map_err(handle_error)?;
+        Ok(true)
+    }
+
+    pub async fn unpin(&self, service: &str, cid: &str) -> Result<(), FsError> {
+        self.remote_pin(service, cid).await?;
+        log::info!("Unpinning /ipfs/{} remotely ({})", cid, service);
+        self.ipfs
+            .pin_remote_rm(service, cid)
+            .await
+            .map_err(handle_error)
+    }
+
+    async fn services(&self) -> Result<Vec<String>, FsError> {
+        let services = self
+            .ipfs
+            .pin_remote_service_ls()
+            .await
+            .map_err(handle_error)?;
+        Ok(services
+            .remote_services
+            .into_iter()
+            .map(|s| s.service)
+            .collect())
+    }
+
+    async fn check_service(&self, service: &str) -> Result<(), FsError> {
+        match self.services().await?.iter().any(|s| s == service) {
+            true => Ok(()),
+            false => Err(FsError::NotFound),
+        }
+    }
+
+    async fn remote_pin(&self, service: &str, cid: &str) -> Result<RemotePin, FsError> {
+        self.check_service(service).await?;
+        self.remote_pins(service, Some(cid))
+            .await?
+            .remove(cid)
+            .ok_or(FsError::NotFound)
+    }
+
+    /// A service may have several pins of a CID (e.g. with different names),
+    /// the most advanced one is kept
+    async fn remote_pins(
+        &self,
+        service: &str,
+        cid: Option<&str>,
+    ) -> Result<BTreeMap<String, RemotePin>, FsError> {
+        let pins = self
+            .ipfs
+            .pin_remote_ls(service, cid)
+            .await
+            .map_err(handle_error)?;
+        let mut by_cid = BTreeMap::new();
+        for pin in pins {
+            match by_cid.get(&pin.cid) {
+                Some(other) if progress(other) >= progress(&pin) => {}
+                _ => {
+                    by_cid.insert(pin.cid.clone(), pin);
+                }
+            }
+        }
+        Ok(by_cid)
+    }
+}
+
+fn progress(pin: &RemotePin) -> u8 {
+    match pin.status.as_str() {
+        "pinned" => 3,
+        "pinning" => 2,
+        "queued" => 1,
+        _ => 0,
+    }
+}
+
+fn pin_metadata(pin: &RemotePin) -> StaticMetadata {
+    let mut metadata = StaticMetadata::new_file(0);
+    metadata.cid = Some(pin.cid.clone());
+    metadata
+}
+
+fn pin_props(pin: &RemotePin) -> Vec<(&'static str, &str)> {
+    vec![
+        ("cid", &pin.cid),
+        ("remote-pin-status", &pin.status),
+        ("remote-pin-name", &pin.name),
+    ]
+}
+
+fn parse(path: &DavPath) -> Result<RemotePath, FsError> {
+    let mut segments = map_path(path)?.split('/').filter(|s| !s.is_empty());
+    let path = match (segments.next(), segments.next()) {
+        (None, _) => RemotePath::Root,
+        (Some(service), None) => RemotePath::Service(service.to_string()),
+        (Some(service), Some(cid)) => RemotePath::Pin(service.to_string(), cid.to_string()),
+    };
+    match segments.next() {
+        Some(_) => Err(FsError::NotFound),
+        None => Ok(path),
+    }
+}
+
+/// Extracts the service and the name from `/pins/remote/<service>/<name>`
+pub fn remote_entry(path: &DavPath) -> Option<(String, String)> {
+    let path = std::str::from_utf8(path.as_bytes()).ok()?;
+    let rest = path.strip_prefix("/pins/remote/")?;
+    let (service, name) = rest.strip_suffix('/').unwrap_or(rest).split_once('/')?;
+    if service.is_empty() || name.is_empty() || name.contains('/') {
+        return None;
+    }
+    Some((service.to_string(), name.to_string()))
+}
+
+/// Remote pinning under `/pins/remote`, which webdav-handler can't express:
+///
+/// - PUT of `/pins/remote/<service>/<cid>` (the body is ignored) asks the
+///   service to pin `<cid>`
+/// - COPY to `/pins/remote/<service>/<name>` asks the service to pin the CID
+///   of the source, naming the pin `<name>`
+/// - DELETE of `/pins/remote/<service>/<cid>` removes the remote pins of
+///   `<cid>`
+///
+/// The pin is reported in `Location`. MOVE from or into `/pins/remote` is
+/// refused with `403 Forbidden`. Other requests are returned back to be
+/// passed to `pins::handle` and webdav-handler. Paths are checked as the
+/// mount table maps them.
+pub async fn handle(
+    fs: &RootFs,
+    req: Request<hyper::Body>,
+) -> Result<Response<Body>, Request<hyper::Body>> {
+    let path = match DavPath::new(req.uri().path()) {
+        Ok(path) => path,
+        Err(_) => return Err(req),
+    };
+    let mounted = fs.mount_path(&path);
+    let in_remote = mounted.as_ref().is_some_and(is_in_remote);
+    let entry = mounted.as_ref().and_then(remote_entry);
+    let to = destination(&req).and_then(|to| Some((fs.mount_path(&to)?, to)));
+    let resp = match (req.method().as_str(), to) {
+        ("PUT", _) if in_remote => match entry {
+            Some((_, cid)) if Cid::try_from(cid.as_str()).is_err() => {
+                forbidden("The name of a remote pin created by PUT must be a CID\n")
+            }
+            Some(_) => add_remote_pin(fs, &path, None).await,
+            None => {
+                forbidden("Only /pins/remote/<service>/<cid> can be created, which pins <cid>\n")
+            }
+        },
+        ("DELETE", _) if in_remote => match entry {
+            Some(_) => match fs.remove_remote_pin(&path).await {
+                Ok(()) => status_response(StatusCode::NO_CONTENT),
+                Err(e) => status_response(fs_error_status(e)),
+            },
+            None => forbidden("Only remote pins (/pins/remote/<service>/<cid>) can be deleted\n"),
+        },
+        ("COPY", Some((to_mounted, to))) if is_in_remote(&to_mounted) => {
+            match remote_entry(&to_mounted) {
+                Some(_) => add_remote_pin(fs, &to, Some(&path)).await,
+                None => forbidden(
+                    "Only /pins/remote/<service>/<name> can be a COPY destination, \
+                which pins the source remotely\n",
+                ),
+            }
+        }
+        ("MOVE", Some((to_mounted, _))) if in_remote || is_in_remote(&to_mounted) => forbidden(
+            "Remote pins can't be moved. COPY into /pins/remote/<service>/<name> \
+            pins the source remotely\n",
+        ),
+        _ => return Err(req),
+    };
+    Ok(resp)
+}
+
+async fn add_remote_pin(fs: &RootFs, to: &DavPath, from: Option<&DavPath>) -> Response<Body> {
+    match fs.add_remote_pin(to, from).await {
+        Ok((cid, created)) => {
+            let status = if created {
+                StatusCode::CREATED
+            } else {
+                StatusCode::NO_CONTENT
+            };
+            // The name of a COPY destination is replaced with the CID
+            let url = to.as_url_string();
+            let service = url.trim_end_matches('/').rsplit_once('/').unwrap().0;
+            Response::builder()
+                .status(status)
+                .header(LOCATION, [service, "/", &cid].concat())
+                .body(Body::empty())
+                .unwrap()
+        }
+        Err(e) => status_response(fs_error_status(e)),
+    }
+}
+
+fn is_in_remote(path: &DavPath) -> bool {
+    let path = path.as_bytes();
+    path == b"/pins/remote" || path.starts_with(b"/pins/remote/")
+}
@@ -1,15 +1,27 @@
+use crate::access_log::{AccessLog, AccessLogEntry, LoggedBody};
+use crate::metrics::METRICS;
 use hyper::{Method, Request, Response, Uri};
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use webdav_handler::body::Body;
 
 pub struct RequestLog {
     method: Method,
     uri: Uri,
+    /// See `metrics::namespace_label`
+    namespace: &'static str,
     time_start: SystemTime,
+    access_log: Option<AccessLogEntry>,
 }
 
 impl RequestLog {
-    pub fn on_request<T>(req: &Request<T>) -> RequestLog {
+    pub fn on_request<T>(
+        req: &Request<T>,
+        remote_addr: Option<SocketAddr>,
+        access_log: Option<&Arc<AccessLog>>,
+        namespace: &'static str,
+    ) -> RequestLog {
         let method = req.method().clone();
         let uri = req.uri().clone();
         let time_start = SystemTime::now();
@@ -18,11 +30,20 @@ impl RequestLog {
         RequestLog {
             method,
             uri,
+            namespace,
             time_start,
+            access_log: access_log.map(|log| log.entry(req, remote_addr)),
         }
     }
 
-    pub fn on_response(self, resp: &Response<Body>) {
+    pub fn on_authenticated(&mut self, user: &str) {
+        if let Some(entry) = &mut self.access_log {
+            entry.set_user(user);
+        }
+    }
+
+    /// The access log entry is written when the returned body is sent
+    pub fn on_response(self, resp: Response<Body>) -> Response<LoggedBody> {
         let elapsed = match self.time_start.elapsed() {
             Ok(elapsed) => elapsed,
             Err(_) => {
@@ -37,5 +58,11 @@ impl RequestLog {
             resp.status(),
             elapsed
         );
+        METRICS.on_request(&self.method, self.namespace, resp.status(), elapsed);
+        let mut access_log = self.access_log;
+        if let Some(entry) = &mut access_log {
+            entry.set_status(resp.status().as_u16());
+        }
+        resp.map(|body| LoggedBody::new(body, access_log))
     }
 }
@@ -1,10 +1,17 @@
+use crate::aliases::Aliases;
+use crate::auth::Access;
 use crate::ipfs_fs::IpfsFs;
-use crate::metadata::{StaticLsEntry, StaticMetadata};
-use crate::mfs::MfsFs;
-use futures::{stream, FutureExt};
+use crate::metadata::StaticMetadata;
+use crate::mfs::{self, MfsFs};
+use crate::mounts::{Mounted, Mounts};
+use crate::pins::{self, PinsFs};
+use crate::remote_pins;
+use futures::{stream, FutureExt, StreamExt};
 use hyper::StatusCode;
+use ipfs_api_backend_hyper::IpfsApi;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::time::SystemTime;
 use webdav_handler::davpath::DavPath;
 use webdav_handler::fs::{
@@ -17,41 +24,335 @@ pub struct RootFs {
     pub mfs: MfsFs,
     pub ipfs: IpfsFs,
     pub ipns: IpfsFs,
+    pub pins: PinsFs,
+    pub mounts: Mounts,
+    /// Permissions of the authenticated user. `None` means full access
+    pub access: Option<Arc<Access>>,
 }
 
 enum FsKind<'a> {
-    Root,
+    /// A directory above mount points, see `Mounted::Dir`
+    Dir(Vec<(String, DavPath)>),
     Provided(&'a dyn DavFileSystem, DavPath),
     Unknown,
 }
 
 impl RootFs {
-    fn lookup_fs(&self, path: &DavPath) -> FsKind {
-        if path.as_bytes() == &[b'/'] {
-            FsKind::Root
-        } else if path.as_bytes().starts_with(b"/mfs") {
-            let mut next_path = path.clone();
-            next_path.set_prefix("/mfs").unwrap();
-            FsKind::Provided(&self.mfs, next_path)
-        } else if path.as_bytes().starts_with(b"/ipfs") {
-            let mut next_path = path.clone();
-            next_path.set_prefix("/ipfs").unwrap();
-            FsKind::Provided(&self.ipfs, next_path)
-        } else if path.as_bytes().starts_with(b"/ipns") {
-            let mut next_path = path.clone();
-            next_path.set_prefix("/ipns").unwrap();
-            FsKind::Provided(&self.ipns, next_path)
+    pub fn with_access(&self, access: Arc<Access>) -> RootFs {
+        RootFs {
+            access: Some(access),
+            ..self.clone()
+        }
+    }
+
+    fn check_read(&self, path: &DavPath) -> Result<(), FsError> {
+        match &self.access {
+            Some(access) if !access.can_read(path.as_bytes()) => Err(FsError::Forbidden),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_write(&self, path: &DavPath) -> Result<(), FsError> {
+        if let Some(path) = self.mount_path(path) {
+            if self.aliases().any(|aliases| aliases.forbids_write(&path)) {
+                return Err(FsError::Forbidden);
+            }
+        }
+        match &self.access {
+            Some(access) if !access.can_write(path.as_bytes()) => Err(FsError::Forbidden),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_traverse(&self, path: &DavPath) -> Result<(), FsError> {
+        match &self.access {
+            Some(access) if !access.can_traverse(path.as_bytes()) => Err(FsError::Forbidden),
+            _ => Ok(()),
+        }
+    }
+
+    /// Hides entries the user can't see if the directory itself is not readable
+    fn filter_entries(
+        &self,
+        path: &DavPath,
+        entries: FsStream<Box<dyn DavDirEntry>>,
+    ) -> FsStream<Box<dyn DavDirEntry>> {
+        let access = match &self.access {
+            Some(access) if !access.can_read(path.as_bytes()) => access.clone(),
+            _ => return entries,
+        };
+        let mut dir = path.as_bytes().to_vec();
+        if !dir.ends_with(b"/") {
+            dir.push(b'/');
+        }
+        Box::pin(entries.filter(move |entry| {
+            let mut entry_path = dir.clone();
+            entry_path.extend_from_slice(&entry.name());
+            futures::future::ready(access.can_traverse(&entry_path))
+        }))
+    }
+
+    /// Changes the Apache `executable` property. This is not a part of
+    /// `DavFileSystem`, so only the writable namespaces are looked up
+    pub async fn set_executable(&self, path: &DavPath, executable: bool) -> Result<(), FsError> {
+        self.check_write(path)?;
+        let path = &self.resolve_path(path).await?;
+        if let Some(next_path) = in_namespace(path, "/mfs") {
+            self.mfs.set_executable(&next_path, executable).await
+        } else if let Some(next_path) = in_namespace(path, "/ipns") {
+            self.ipns.set_executable(&next_path, executable).await
+        } else if let Some(next_path) = in_namespace(path, "/ipfs") {
+            self.ipfs.set_executable(&next_path, executable).await
         } else {
-            FsKind::Unknown
+            Err(FsError::NotFound)
+        }
+    }
+
+    /// Drops the cached metadata of `path`, see `MfsFs::forget`
+    pub async fn forget(&self, path: &DavPath) {
+        let path = match self.resolve_path(path).await {
+            Ok(path) => path,
+            Err(_) => return,
+        };
+        if let Some(next_path) = in_namespace(&path, "/mfs") {
+            self.mfs.forget(&next_path);
+        } else if let Some(next_path) = in_namespace(&path, "/ipns") {
+            self.ipns.forget(&next_path).await;
+        } else if let Some(next_path) = in_namespace(&path, "/ipfs") {
+            self.ipfs.forget(&next_path).await;
+        }
+    }
+
+    /// Pins the node `from` refers to, for a COPY into `/ipfs/<name>`. Returns
+    /// its CID and `false` if it was already pinned recursively
+    pub async fn pin(&self, from: &DavPath, to: &DavPath) -> Result<(String, bool), FsError> {
+        self.check_read(from)?;
+        self.check_write(to)?;
+        // The CID is the ETag. `None` for the namespace roots, which are not nodes
+        let cid = self
+            .metadata(from)
+            .await?
+            .etag()
+            .ok_or(FsError::Forbidden)?;
+        let ipfs_path = ["/ipfs/", &cid].concat();
+        let ipfs = &self.ipfs.ipfs;
+        if let Ok(pins) = ipfs.pin_ls(Some(&ipfs_path), Some("recursive")).await {
+            if !pins.keys.is_empty() {
+                return Ok((cid, false));
+            }
+        }
+        log::info!("Pinning {} ({})", ipfs_path, from.as_url_string());
+        pins::pin_add(ipfs, &ipfs_path, true).await?;
+        Ok((cid, true))
+    }
+
+    /// Pins `/pins/<type>/<cid>`, for a PUT or a COPY there. Returns `false`
+    /// if it was already pinned with this type
+    pub async fn add_pin(&self, path: &DavPath) -> Result<bool, FsError> {
+        self.check_write(path)?;
+        let path = &self.mount_path(path).ok_or(FsError::NotFound)?;
+        let (ty, cid) = pins::pin_entry(path).ok_or(FsError::Forbidden)?;
+        self.pins.pin(ty, &cid).await
+    }
+
+    /// Unpins `/pins/<type>/<cid>`, for a DELETE of it
+    pub async fn remove_pin(&self, path: &DavPath) -> Result<(), FsError> {
+        self.check_write(path)?;
+        let path = &self.mount_path(path).ok_or(FsError::NotFound)?;
+        let (ty, cid) = pins::pin_entry(path).ok_or(FsError::Forbidden)?;
+        self.pins.unpin(ty, &cid).await
+    }
+
+    /// Changes the type of a pin, for a MOVE between `/pins/<type>/<cid>`
+    pub async fn move_pin(&self, from: &DavPath, to: &DavPath) -> Result<(), FsError> {
+        self.check_write(from)?;
+        self.check_write(to)?;
+        let from = &self.mount_path(from).ok_or(FsError::NotFound)?;
+        let to = &self.mount_path(to).ok_or(FsError::NotFound)?;
+        let (from_ty, cid) = pins::pin_entry(from).ok_or(FsError::Forbidden)?;
+        let (to_ty, _) = pins::pin_entry(to).ok_or(FsError::Forbidden)?;
+        self.pins.change_type(&cid, from_ty, to_ty).await
+    }
+
+    /// Asks a remote pinning service to pin `<cid>` for a PUT of
+    /// `/pins/remote/<service>/<cid>`, or the node `from` refers to for a COPY
+    /// to `/pins/remote/<service>/<name>`. Returns the CID and `false` if the
+    /// service already pins it
+    pub async fn add_remote_pin(
+        &self,
+        to: &DavPath,
+        from: Option<&DavPath>,
+    ) -> Result<(String, bool), FsError> {
+        self.check_write(to)?;
+        let to = &self.mount_path(to).ok_or(FsError::NotFound)?;
+        let (service, name) = remote_pins::remote_entry(to).ok_or(FsError::Forbidden)?;
+        let cid = match from {
+            Some(from) => {
+                self.check_read(from)?;
+                // The CID is the ETag. `None` for the namespace roots
+                self.metadata(from)
+                    .await?
+                    .etag()
+                    .ok_or(FsError::Forbidden)?
+            }
+            None => name.clone(),
+        };
+        let created = self.pins.remote.pin(&service, &cid, &name).await?;
+        Ok((cid, created))
+    }
+
+    /// Removes the remote pins of `/pins/remote/<service>/<cid>`, for a
+    /// DELETE of it
+    pub async fn remove_remote_pin(&self, path: &DavPath) -> Result<(), FsError> {
+        self.check_write(path)?;
+        let path = &self.mount_path(path).ok_or(FsError::NotFound)?;
+        let (service, cid) = remote_pins::remote_entry(path).ok_or(FsError::Forbidden)?;
+        self.pins.remote.unpin(&service, &cid).await
+    }
+
+    /// `true` if `path` is in an upload to `/ipfs` that is not pinned yet,
+    /// see `Uploads`
+    pub fn is_uploading(&self, path: &DavPath) -> bool {
+        match (&self.ipfs.uploads, self.mount_path(path)) {
+            (Some(uploads), Some(path)) => uploads.contains(&path),
+            _ => false,
         }
     }
+
+    /// The CID the upload `path` belongs to is going to be pinned as, if
+    /// `path` is in `/ipfs/<name>`
+    pub async fn upload_cid(&self, path: &DavPath) -> Option<String> {
+        let uploads = self.ipfs.uploads.as_ref()?;
+        let next_path = in_namespace(&self.mount_path(path)?, "/ipfs")?;
+        uploads.cid(&next_path).await
+    }
+
+    /// The path `path` is served as by a namespace, see `Mounts`. `None` for
+    /// paths outside of the mount points and the directories above them
+    pub fn mount_path(&self, path: &DavPath) -> Option<DavPath> {
+        match self.mounts.resolve(path) {
+            Some(Mounted::Path(path)) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// The URL a namespace path such as `/ipfs/<cid>` is served at. The path
+    /// itself if it is not mounted
+    pub fn url(&self, path: &str) -> String {
+        self.mounts.url(path).unwrap_or_else(|| path.to_string())
+    }
+
+    /// Unlike `MfsFs::copy`, reads staged IPNS names and uploads to `/ipfs`
+    /// from MFS, so unpublished changes are copied as well
+    async fn transfer_to_mfs(
+        &self,
+        from: &DavPath,
+        to: &DavPath,
+        is_move: bool,
+    ) -> Result<(), FsError> {
+        let upload = match &self.ipfs.uploads {
+            Some(uploads) => uploads.source_path(from)?,
+            None => None,
+        };
+        let (from, from_mfs) = match (upload, &self.ipns.staging) {
+            (Some(source), _) => source,
+            (None, Some(staging)) => staging.source_path(from).await?,
+            (None, None) => mfs::source_path(from)?,
+        };
+        self.mfs.transfer(&from, from_mfs, to, is_move).await
+    }
+
+    /// The path is mapped with the mount table, then `/ipfs` and `/ipns`
+    /// aliases are resolved, so the namespace serves the path they refer to
+    async fn lookup_fs(&self, path: &DavPath) -> Result<FsKind<'_>, FsError> {
+        let path = match self.mounts.resolve(path) {
+            Some(Mounted::Path(path)) => self.resolve_alias(&path).await?,
+            Some(Mounted::Dir(entries)) => return Ok(FsKind::Dir(entries)),
+            None => return Ok(FsKind::Unknown),
+        };
+        if let Some(aliases) = self.aliases().find(|aliases| aliases.is_dir(&path)) {
+            return Ok(FsKind::Provided(aliases, path));
+        }
+        let namespaces: [(&str, &dyn DavFileSystem); 4] = [
+            ("/mfs", &self.mfs),
+            ("/ipfs", &self.ipfs),
+            ("/ipns", &self.ipns),
+            ("/pins", &self.pins),
+        ];
+        Ok(namespaces
+            .into_iter()
+            .find_map(|(name, fs)| Some(FsKind::Provided(fs, in_namespace(&path, name)?)))
+            .unwrap_or(FsKind::Unknown))
+    }
+
+    /// The namespace path `path` refers to, with the mount table and the
+    /// aliases applied
+    async fn resolve_path(&self, path: &DavPath) -> Result<DavPath, FsError> {
+        let path = self.mount_path(path).ok_or(FsError::NotFound)?;
+        self.resolve_alias(&path).await
+    }
+
+    /// `true` if `path` is served by `/mfs`
+    fn is_in_mfs(&self, path: &DavPath) -> bool {
+        self.mount_path(path)
+            .is_some_and(|path| in_namespace(&path, "/mfs").is_some())
+    }
+
+    /// Replaces an `/ipfs` or `/ipns` alias with the path it refers to,
+    /// see `Aliases`
+    async fn resolve_alias(&self, path: &DavPath) -> Result<DavPath, FsError> {
+        for aliases in self.aliases() {
+            if let Some(resolved) = aliases.resolve(path).await? {
+                return Ok(resolved);
+            }
+        }
+        Ok(path.clone())
+    }
+
+    fn aliases(&self) -> impl Iterator<Item = &Aliases> {
+        self.ipfs.aliases.iter().chain(self.ipns.aliases.iter())
+    }
+}
+
+/// An entry of a directory above mount points. A mount point has the
+/// metadata of the path it refers to
+struct MountLsEntry {
+    fs: RootFs,
+    name: String,
+    path: DavPath,
+}
+
+impl DavDirEntry for MountLsEntry {
+    fn name(&self) -> Vec<u8> {
+        self.name.clone().into_bytes()
+    }
+
+    fn metadata<'a>(&'a self) -> FsFuture<Box<dyn DavMetaData>> {
+        self.fs.metadata(&self.path)
+    }
+}
+
+/// `path` relative to the namespace root `name` (e.g. `/mfs`), if it is there
+pub fn in_namespace(path: &DavPath, name: &str) -> Option<DavPath> {
+    let rest = path.as_bytes().strip_prefix(name.as_bytes())?;
+    if !rest.is_empty() && !rest.starts_with(b"/") {
+        return None;
+    }
+    let mut next_path = path.clone();
+    next_path.set_prefix(name).ok()?;
+    Some(next_path)
 }
 
 impl DavFileSystem for RootFs {
     fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<Box<dyn DavFile>> {
         async move {
-            match self.lookup_fs(path) {
-                FsKind::Root => Err(FsError::Forbidden),
+            if options.write {
+                self.check_write(path)?;
+            } else {
+                self.check_read(path)?;
+            }
+            match self.lookup_fs(path).await? {
+                FsKind::Dir(_) => Err(FsError::Forbidden),
                 FsKind::Provided(fs, next_path) => fs.open(&next_path, options).await,
                 FsKind::Unknown => Err(FsError::NotFound),
             }
@@ -65,14 +366,24 @@ impl DavFileSystem for RootFs {
         meta: ReadDirMeta,
     ) -> FsFuture<FsStream<Box<dyn DavDirEntry>>> {
         async move {
-            match self.lookup_fs(path) {
-                FsKind::Root => {
-                    let dirs = ["mfs", "ipfs", "ipns"].into_iter().map(|name| {
-                        Box::new(StaticLsEntry::new_dir(name.to_string())) as Box<dyn DavDirEntry>
+            self.check_traverse(path)?;
+            match self.lookup_fs(path).await? {
+                FsKind::Dir(entries) => {
+                    let fs = self.clone();
+                    let dirs = entries.into_iter().map(move |(name, path)| {
+                        Box::new(MountLsEntry {
+                            fs: fs.clone(),
+                            name,
+                            path,
+                        }) as Box<dyn DavDirEntry>
                     });
-                    Ok(Box::pin(stream::iter(dirs)) as FsStream<Box<dyn DavDirEntry>>)
+                    let dirs = Box::pin(stream::iter(dirs)) as FsStream<Box<dyn DavDirEntry>>;
+                    Ok(self.filter_entries(path, dirs))
+                }
+                FsKind::Provided(fs, next_path) => {
+                    let entries = fs.read_dir(&next_path, meta).await?;
+                    Ok(self.filter_entries(path, entries))
                 }
-                FsKind::Provided(fs, next_path) => fs.read_dir(&next_path, meta).await,
                 FsKind::Unknown => Err(FsError::NotFound),
             }
         }
@@ -81,8 +392,9 @@ impl DavFileSystem for RootFs {
 
     fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<Box<dyn DavMetaData>> {
         async move {
-            match self.lookup_fs(path) {
-                FsKind::Root => Ok(Box::new(StaticMetadata::new_dir()) as Box<dyn DavMetaData>),
+            self.check_traverse(path)?;
+            match self.lookup_fs(path).await? {
+                FsKind::Dir(_) => Ok(Box::new(StaticMetadata::new_dir()) as Box<dyn DavMetaData>),
                 FsKind::Provided(fs, next_path) => fs.metadata(&next_path).await,
                 FsKind::Unknown => Err(FsError::NotFound),
             }
@@ -92,8 +404,9 @@ impl DavFileSystem for RootFs {
 
     fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<Box<dyn DavMetaData>> {
         async move {
-            match self.lookup_fs(path) {
-                FsKind::Root => self.metadata(path).await,
+            self.check_traverse(path)?;
+            match self.lookup_fs(path).await? {
+                FsKind::Dir(_) => self.metadata(path).await,
                 FsKind::Provided(fs, next_path) => fs.symlink_metadata(&next_path).await,
                 FsKind::Unknown => Err(FsError::NotFound),
             }
@@ -103,9 +416,10 @@ impl DavFileSystem for RootFs {
 
     fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<()> {
         async move {
-            match self.lookup_fs(path) {
+            self.check_write(path)?;
+            match self.lookup_fs(path).await? {
                 FsKind::Provided(fs, next_path) => fs.create_dir(&next_path).await,
-                FsKind::Root | FsKind::Unknown => Err(FsError::Forbidden),
+                FsKind::Dir(_) | FsKind::Unknown => Err(FsError::Forbidden),
             }
         }
         .boxed()
@@ -113,9 +427,10 @@ impl DavFileSystem for RootFs {
 
     fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<()> {
         async move {
-            match self.lookup_fs(path) {
+            self.check_write(path)?;
+            match self.lookup_fs(path).await? {
                 FsKind::Provided(fs, next_path) => fs.remove_dir(&next_path).await,
-                FsKind::Root | FsKind::Unknown => Err(FsError::Forbidden),
+                FsKind::Dir(_) | FsKind::Unknown => Err(FsError::Forbidden),
             }
         }
         .boxed()
@@ -123,9 +438,10 @@ impl DavFileSystem for RootFs {
 
     fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<()> {
         async move {
-            match self.lookup_fs(path) {
+            self.check_write(path)?;
+            match self.lookup_fs(path).await? {
                 FsKind::Provided(fs, next_path) => fs.remove_file(&next_path).await,
-                FsKind::Root | FsKind::Unknown => Err(FsError::Forbidden),
+                FsKind::Dir(_) | FsKind::Unknown => Err(FsError::Forbidden),
             }
         }
         .boxed()
@@ -133,9 +449,15 @@ impl DavFileSystem for RootFs {
 
     fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<()> {
         async move {
-            match self.lookup_fs(to) {
+            self.check_write(from)?;
+            self.check_write(to)?;
+            let from = &self.resolve_path(from).await?;
+            match self.lookup_fs(to).await? {
+                FsKind::Provided(_, next_path) if self.is_in_mfs(to) => {
+                    self.transfer_to_mfs(from, &next_path, true).await
+                }
                 FsKind::Provided(fs, next_path) => fs.rename(from, &next_path).await,
-                FsKind::Root | FsKind::Unknown => Err(FsError::Forbidden),
+                FsKind::Dir(_) | FsKind::Unknown => Err(FsError::Forbidden),
             }
         }
         .boxed()
@@ -143,9 +465,15 @@ impl DavFileSystem for RootFs {
 
     fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<()> {
         async move {
-            match self.lookup_fs(to) {
+            self.check_read(from)?;
+            self.check_write(to)?;
+            let from = &self.resolve_path(from).await?;
+            match self.lookup_fs(to).await? {
+                FsKind::Provided(_, next_path) if self.is_in_mfs(to) => {
+                    self.transfer_to_mfs(from, &next_path, false).await
+                }
                 FsKind::Provided(fs, next_path) => fs.copy(from, &next_path).await,
-                FsKind::Root | FsKind::Unknown => Err(FsError::Forbidden),
+                FsKind::Dir(_) | FsKind::Unknown => Err(FsError::Forbidden),
             }
         }
         .boxed()
@@ -153,9 +481,10 @@ impl DavFileSystem for RootFs {
 
     fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<()> {
         async move {
-            match self.lookup_fs(path) {
+            self.check_write(path)?;
+            match self.lookup_fs(path).await? {
                 FsKind::Provided(fs, next_path) => fs.set_accessed(&next_path, tm).await,
-                FsKind::Root | FsKind::Unknown => Err(FsError::Forbidden),
+                FsKind::Dir(_) | FsKind::Unknown => Err(FsError::Forbidden),
             }
         }
         .boxed()
@@ -163,9 +492,10 @@ impl DavFileSystem for RootFs {
 
     fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<()> {
         async move {
-            match self.lookup_fs(path) {
+            self.check_write(path)?;
+            match self.lookup_fs(path).await? {
                 FsKind::Provided(fs, next_path) => fs.set_modified(&next_path, tm).await,
-                FsKind::Root | FsKind::Unknown => Err(FsError::Forbidden),
+                FsKind::Dir(_) | FsKind::Unknown => Err(FsError::Forbidden),
             }
         }
         .boxed()
@@ -176,9 +506,12 @@ impl DavFileSystem for RootFs {
         path: &'a DavPath,
     ) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
         async move {
-            match self.lookup_fs(path) {
-                FsKind::Provided(fs, next_path) => fs.have_props(&next_path).await,
-                FsKind::Root | FsKind::Unknown => false,
+            if self.check_read(path).is_err() {
+                return false;
+            }
+            match self.lookup_fs(path).await {
+                Ok(FsKind::Provided(fs, next_path)) => fs.have_props(&next_path).await,
+                _ => false,
             }
         }
         .boxed()
@@ -190,9 +523,10 @@ impl DavFileSystem for RootFs {
         patch: Vec<(bool, DavProp)>,
     ) -> FsFuture<Vec<(StatusCode, DavProp)>> {
         async move {
-            match self.lookup_fs(path) {
+            self.check_write(path)?;
+            match self.lookup_fs(path).await? {
                 FsKind::Provided(fs, next_path) => fs.patch_props(&next_path, patch).await,
-                FsKind::Root | FsKind::Unknown => Err(FsError::Forbidden),
+                FsKind::Dir(_) | FsKind::Unknown => Err(FsError::Forbidden),
             }
         }
         .boxed()
@@ -200,9 +534,10 @@ impl DavFileSystem for RootFs {
 
     fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<Vec<DavProp>> {
         async move {
-            match self.lookup_fs(path) {
+            self.check_read(path)?;
+            match self.lookup_fs(path).await? {
                 FsKind::Provided(fs, next_path) => fs.get_props(&next_path, do_content).await,
-                FsKind::Root | FsKind::Unknown => Err(FsError::Forbidden),
+                FsKind::Dir(_) | FsKind::Unknown => Err(FsError::Forbidden),
             }
         }
         .boxed()
@@ -210,9 +545,10 @@ impl DavFileSystem for RootFs {
 
     fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<Vec<u8>> {
         async move {
-            match self.lookup_fs(path) {
+            self.check_read(path)?;
+            match self.lookup_fs(path).await? {
                 FsKind::Provided(fs, next_path) => fs.get_prop(&next_path, prop).await,
-                FsKind::Root | FsKind::Unknown => Err(FsError::Forbidden),
+                FsKind::Dir(_) | FsKind::Unknown => Err(FsError::Forbidden),
             }
         }
         .boxed()
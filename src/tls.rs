@@ -0,0 +1,200 @@
+use futures::Stream;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// How often the certificate files are checked for modifications
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+/// A TLS handshake taking longer than this is aborted
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Provides the current certificate to new TLS connections. The certificate is
+/// reloaded on SIGHUP or when the files are modified; already established
+/// connections are not affected
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+    modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+impl CertResolver {
+    pub fn load(cert_path: PathBuf, key_path: PathBuf) -> Result<Arc<CertResolver>, String> {
+        let modified = (modified_time(&cert_path), modified_time(&key_path));
+        let key = load_certified_key(&cert_path, &key_path)?;
+        Ok(Arc::new(CertResolver {
+            cert_path,
+            key_path,
+            current: RwLock::new(Arc::new(key)),
+            modified: Mutex::new(modified),
+        }))
+    }
+
+    pub fn acceptor(self: &Arc<Self>) -> TlsAcceptor {
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        TlsAcceptor::from(Arc::new(config))
+    }
+
+    /// Spawns a task that reloads the certificate on SIGHUP or when the files change
+    pub fn watch(self: &Arc<Self>) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut sighup = Sighup::new();
+            let mut interval = tokio::time::interval(WATCH_INTERVAL);
+            loop {
+                let force = tokio::select! {
+                    _ = sighup.recv() => true,
+                    _ = interval.tick() => false,
+                };
+                this.reload(force);
+            }
+        });
+    }
+
+    fn reload(&self, force: bool) {
+        let modified = (
+            modified_time(&self.cert_path),
+            modified_time(&self.key_path),
+        );
+        {
+            let mut last_modified = self.modified.lock().unwrap();
+            if !force && *last_modified == modified {
+                return;
+            }
+            *last_modified = modified;
+        }
+        match load_certified_key(&self.cert_path, &self.key_path) {
+            Ok(key) => {
+                *self.current.write().unwrap() = Arc::new(key);
+                log::info!("Reloaded TLS certificate `{}`", self.cert_path.display());
+            }
+            Err(e) => log::error!(
+                "Failed to reload TLS certificate, keeping the old one: {}",
+                e
+            ),
+        }
+    }
+}
+
+struct Sighup {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Sighup {
+    fn new() -> Sighup {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let signal = signal(SignalKind::hangup())
+                .map_err(|e| log::warn!("Failed to subscribe to SIGHUP: {}", e))
+                .ok();
+            Sighup { signal }
+        }
+        #[cfg(not(unix))]
+        Sighup {}
+    }
+
+    /// Never completes if signals are not supported
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            signal.recv().await;
+            return;
+        }
+        futures::future::pending::<()>().await
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, String> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("Failed to read `{}`: {}", path.display(), e))
+    };
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .map_err(|e| format!("Failed to parse `{}`: {}", cert_path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!(
+            "No certificates found in `{}`",
+            cert_path.display()
+        ));
+    }
+    let key = rustls_pemfile::read_all(&mut open(key_path)?)
+        .map_err(|e| format!("Failed to parse `{}`: {}", key_path.display(), e))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| format!("No private key found in `{}`", key_path.display()))?;
+    let key = tokio_rustls::rustls::sign::any_supported_type(&PrivateKey(key))
+        .map_err(|e| format!("Unsupported private key `{}`: {}", key_path.display(), e))?;
+    Ok(CertifiedKey::new(
+        certs.into_iter().map(Certificate).collect(),
+        key,
+    ))
+}
+
+/// Accepts TCP connections and performs TLS handshakes concurrently. Failed
+/// handshakes are logged and skipped
+pub async fn incoming(
+    addr: SocketAddr,
+    acceptor: TlsAcceptor,
+) -> io::Result<impl Stream<Item = io::Result<TlsStream<TcpStream>>>> {
+    let listener = TcpListener::bind(addr).await?;
+    let (tx, rx) = tokio::sync::mpsc::channel(32);
+    tokio::spawn(async move {
+        loop {
+            let (stream, remote_addr) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    log::error!("Failed to accept a connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream));
+                match handshake.await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx.send(stream).await;
+                    }
+                    Ok(Err(e)) => log::debug!("TLS handshake with {} failed: {}", remote_addr, e),
+                    Err(_) => log::debug!("TLS handshake with {} timed out", remote_addr),
+                }
+            });
+        }
+    });
+    Ok(futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|stream| (Ok(stream), rx))
+    }))
+}
@@ -0,0 +1,449 @@
+use crate::ipfs_client::IpfsClient;
+use crate::mfs::MfsFs;
+use crate::rootfs::in_namespace;
+use crate::{handle_error, map_path};
+use cid::Cid;
+use futures::future::BoxFuture;
+use futures::stream;
+use hyper::body::{Buf, Bytes};
+use ipfs_api_backend_hyper::IpfsApi;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::io::SeekFrom;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{
+    DavDirEntry, DavFile, DavFileSystem, DavMetaData, FsError, FsFuture, FsStream, OpenOptions,
+    ReadDirMeta,
+};
+
+/// Content dropped into `/ipfs`.
+///
+/// WebDAV clients upload a folder with MKCOL followed by PUTs of its content,
+/// without telling when they are done. So PUT or MKCOL of `/ipfs/<name>`
+/// starts an "upload": the MFS entry `<root>/<name>` that receives the rest
+/// of the content. Until the upload is pinned, `/ipfs/<name>` is listed and
+/// served from that entry. After `pin_delay` passes without modifications
+/// and with no file of the upload open for writing, the entry is pinned
+/// recursively and removed from MFS, so its CID is listed instead.
+#[derive(Clone)]
+pub struct Uploads {
+    ipfs: IpfsClient,
+    mfs: MfsFs,
+    root: String,
+    pin_delay: Duration,
+    active: Arc<Mutex<HashMap<String, Upload>>>,
+}
+
+/// An upload that is not pinned yet
+#[derive(Default)]
+struct Upload {
+    /// Files of the upload open for writing and operations in progress.
+    /// Pinning waits for them
+    writers: usize,
+    /// The scheduled pinning, `None` while there are writers or once pinning
+    /// has started
+    task: Option<JoinHandle<()>>,
+    /// Incremented on every scheduling, so a task pins only its own upload
+    generation: u64,
+    /// Set while the upload is being pinned and removed from MFS. New writers
+    /// wait until the sender is dropped
+    pinning: Option<watch::Receiver<()>>,
+}
+
+impl Uploads {
+    pub fn new(mfs: MfsFs, root: String, pin_delay: Duration) -> Self {
+        Uploads {
+            ipfs: mfs.ipfs.clone(),
+            mfs,
+            root,
+            pin_delay,
+            active: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn mfs(&self) -> &MfsFs {
+        &self.mfs
+    }
+
+    /// Schedules pinning of the uploads left unpinned by a previous run
+    pub async fn recover(&self) {
+        let ls = match self.ipfs.files_ls(Some(&self.root)).await {
+            Ok(ls) => ls,
+            Err(e) => {
+                if handle_error(e) != FsError::NotFound {
+                    log::error!("Failed to list unpinned uploads in `{}`", self.root);
+                }
+                return;
+            }
+        };
+        let mut active = self.active.lock().unwrap();
+        for entry in ls.entries {
+            log::info!("Resuming the upload `/ipfs/{}`", entry.name);
+            let upload = active.entry(entry.name.clone()).or_default();
+            self.schedule_pin(&entry.name, upload);
+        }
+    }
+
+    /// Returns the path inside the upload if `path` (relative to `/ipfs`)
+    /// belongs to an upload that is not pinned yet
+    pub fn lookup(&self, path: &DavPath) -> Result<Option<DavPath>, FsError> {
+        match split_name(path)? {
+            Some((name, _)) if self.is_active(name) => Ok(Some(self.to_upload_path(path)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Lists the uploads that are not pinned yet
+    pub async fn read_dir(&self, meta: ReadDirMeta) -> FsStream<Box<dyn DavDirEntry>> {
+        let entries = match DavPath::new(&self.root) {
+            Ok(root) => self.mfs.read_dir(&root, meta).await,
+            Err(_) => Err(FsError::GeneralFailure),
+        };
+        entries.unwrap_or_else(|_| Box::pin(stream::empty()))
+    }
+
+    /// Opens a file of an upload for writing, starting the upload if `path`
+    /// is `/<name>`. Pinning is postponed until the file is closed
+    pub async fn open(
+        &self,
+        path: &DavPath,
+        options: OpenOptions,
+    ) -> Result<Box<dyn DavFile>, FsError> {
+        let name = self.begin_write(path, true).await?;
+        match self.mfs.open(&self.to_upload_path(path)?, options).await {
+            Ok(inner) => Ok(Box::new(UploadDavFile {
+                inner,
+                uploads: self.clone(),
+                name,
+            }) as Box<dyn DavFile>),
+            Err(e) => {
+                self.end_write(&name);
+                Err(e)
+            }
+        }
+    }
+
+    /// Runs a mutating MFS operation inside an upload, starting the upload if
+    /// `path` is `/<name>`, and postpones pinning if the operation succeeds
+    pub async fn mutate<'a, F>(&'a self, path: &'a DavPath, op: F) -> Result<(), FsError>
+    where
+        F: FnOnce(&'a MfsFs, DavPath) -> BoxFuture<'a, Result<(), FsError>>,
+    {
+        let name = self.begin_write(path, true).await?;
+        let result = match self.to_upload_path(path) {
+            Ok(upload_path) => op(&self.mfs, upload_path).await,
+            Err(e) => Err(e),
+        };
+        self.end_write(&name);
+        result
+    }
+
+    /// Runs a removing MFS operation on `/<name>`, cancelling the upload.
+    /// Pinned content can't be removed
+    pub async fn remove<'a, F>(&'a self, path: &'a DavPath, op: F) -> Result<(), FsError>
+    where
+        F: FnOnce(&'a MfsFs, DavPath) -> BoxFuture<'a, Result<(), FsError>>,
+    {
+        let name = self.begin_write(path, false).await?;
+        let result = match self.to_upload_path(path) {
+            Ok(upload_path) => op(&self.mfs, upload_path).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => self.cancel(&name),
+            Err(_) => self.end_write(&name),
+        }
+        result
+    }
+
+    /// Like `IpnsStaging::source_path`, but for uploads. `from` is a full
+    /// WebDAV path, `None` means it's not in an upload
+    pub fn source_path(&self, from: &DavPath) -> Result<Option<(String, bool)>, FsError> {
+        let path = match ipfs_relative(from) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        match self.lookup(&path)? {
+            Some(upload_path) => {
+                let upload_path = map_path(&upload_path)?.trim_end_matches('/');
+                Ok(Some((upload_path.to_string(), true)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Must be called after `from` (a full WebDAV path) is moved away
+    pub fn on_moved(&self, from: &DavPath) {
+        let path = match ipfs_relative(from) {
+            Some(path) => path,
+            None => return,
+        };
+        match split_name(&path) {
+            Ok(Some((name, true))) if self.is_active(name) => self.cancel(name),
+            Ok(Some((name, false))) if self.is_active(name) => self.touch(name),
+            _ => {}
+        }
+    }
+
+    /// `true` if `path` (a full WebDAV path) is in an upload that is not
+    /// pinned yet
+    pub fn contains(&self, path: &DavPath) -> bool {
+        match ipfs_relative(path).as_ref().map(split_name) {
+            Some(Ok(Some((name, _)))) => self.is_active(name),
+            _ => false,
+        }
+    }
+
+    /// The current CID of the upload `path` belongs to (`/<name>` itself, a
+    /// folder created by MKCOL or a file in it), which is pinned unless it's
+    /// modified before `pin_delay` passes
+    pub async fn cid(&self, path: &DavPath) -> Option<String> {
+        match split_name(path) {
+            Ok(Some((name, _))) if self.is_active(name) => {
+                let stat = self.ipfs.files_stat(&self.upload_root(name)).await.ok()?;
+                Some(stat.hash)
+            }
+            _ => None,
+        }
+    }
+
+    /// Registers a writer of the upload `path` belongs to and returns its
+    /// name. The upload isn't pinned until `end_write`. If it is being pinned,
+    /// waits for that to finish. If `start`, a new upload can be started by
+    /// `/<name>` itself, the rest of `/ipfs` is immutable. A `<name>` that is a
+    /// pinned CID already exists
+    async fn begin_write(&self, path: &DavPath, start: bool) -> Result<String, FsError> {
+        let (name, is_root) = split_name(path)?.ok_or(FsError::Forbidden)?;
+        let can_start = is_root && start;
+        if !self.is_active(name) {
+            if !can_start {
+                return Err(FsError::Forbidden);
+            }
+            if self.is_pinned(name).await? {
+                return Err(FsError::Exists);
+            }
+            self.ipfs
+                .files_mkdir(&self.root, true)
+                .await
+                .map_err(handle_error)?;
+        }
+        loop {
+            let mut pinning = {
+                let mut active = self.active.lock().unwrap();
+                // Pinned meanwhile
+                if !can_start && !active.contains_key(name) {
+                    return Err(FsError::Forbidden);
+                }
+                let upload = active.entry(name.to_string()).or_default();
+                match &upload.pinning {
+                    Some(pinning) => pinning.clone(),
+                    None => {
+                        upload.writers += 1;
+                        if let Some(task) = upload.task.take() {
+                            task.abort();
+                        }
+                        return Ok(name.to_string());
+                    }
+                }
+            };
+            // Returns an error once the pinning is finished
+            while pinning.changed().await.is_ok() {}
+        }
+    }
+
+    async fn is_pinned(&self, name: &str) -> Result<bool, FsError> {
+        if Cid::try_from(name).is_err() {
+            return Ok(false);
+        }
+        let ipfs_path = ["/ipfs/", name].concat();
+        match self.ipfs.pin_ls(Some(&ipfs_path), None).await {
+            Ok(_) => Ok(true),
+            Err(e) => match handle_error(e) {
+                FsError::NotFound => Ok(false),
+                e => Err(e),
+            },
+        }
+    }
+
+    fn is_active(&self, name: &str) -> bool {
+        self.active.lock().unwrap().contains_key(name)
+    }
+
+    /// Starts the upload if needed and postpones pinning for `pin_delay`
+    fn touch(&self, name: &str) {
+        let mut active = self.active.lock().unwrap();
+        let upload = active.entry(name.to_string()).or_default();
+        if upload.writers == 0 && upload.pinning.is_none() {
+            self.schedule_pin(name, upload);
+        }
+    }
+
+    fn end_write(&self, name: &str) {
+        let mut active = self.active.lock().unwrap();
+        // `None` if the upload was cancelled meanwhile
+        if let Some(upload) = active.get_mut(name) {
+            upload.writers -= 1;
+            if upload.writers == 0 {
+                self.schedule_pin(name, upload);
+            }
+        }
+    }
+
+    fn cancel(&self, name: &str) {
+        if let Some(upload) = self.active.lock().unwrap().remove(name) {
+            if let Some(task) = upload.task {
+                task.abort();
+            }
+        }
+    }
+
+    fn schedule_pin(&self, name: &str, upload: &mut Upload) {
+        if let Some(task) = upload.task.take() {
+            task.abort();
+        }
+        upload.generation += 1;
+        let generation = upload.generation;
+        let this = self.clone();
+        let name = name.to_string();
+        upload.task = Some(tokio::spawn(async move {
+            tokio::time::sleep(this.pin_delay).await;
+            if let Err(e) = this.pin(&name, generation).await {
+                log::error!("Failed to pin the upload `/ipfs/{}`: {:?}", name, e);
+            }
+        }));
+    }
+
+    /// Pins the upload and removes it from MFS. A modification of the upload
+    /// aborts the task before it starts pinning, the new content is pinned by
+    /// the next one. Once it starts, new writers wait for it to finish, so
+    /// nothing is written between pinning and removal
+    async fn pin(&self, name: &str, generation: u64) -> Result<(), FsError> {
+        let _pinning = {
+            let mut active = self.active.lock().unwrap();
+            match active.get_mut(name) {
+                Some(upload) if upload.generation == generation && upload.writers == 0 => {
+                    // The task can't be aborted from now on
+                    upload.task = None;
+                    let (pinning, waiting) = watch::channel(());
+                    upload.pinning = Some(waiting);
+                    pinning
+                }
+                _ => return Ok(()),
+            }
+        };
+        let result = self.pin_and_remove(name).await;
+        let mut active = self.active.lock().unwrap();
+        match result {
+            Ok(()) => {
+                active.remove(name);
+            }
+            Err(_) => {
+                if let Some(upload) = active.get_mut(name) {
+                    upload.pinning = None;
+                }
+            }
+        }
+        result
+    }
+
+    async fn pin_and_remove(&self, name: &str) -> Result<(), FsError> {
+        let upload_root = self.upload_root(name);
+        let stat = match self.ipfs.files_stat(&upload_root).await {
+            Ok(stat) => stat,
+            Err(e) => {
+                return match handle_error(e) {
+                    // Removed bypassing the server
+                    FsError::NotFound => Ok(()),
+                    e => Err(e),
+                };
+            }
+        };
+        let ipfs_path = ["/ipfs/", &stat.hash].concat();
+        log::info!("Pinning {} (the upload `/ipfs/{}`)", ipfs_path, name);
+        self.ipfs
+            .pin_add(&ipfs_path, true)
+            .await
+            .map_err(handle_error)?;
+        let upload_root = DavPath::new(&upload_root).map_err(|_| FsError::GeneralFailure)?;
+        match self.mfs.remove_dir(&upload_root).await {
+            // Moved away meanwhile
+            Ok(()) | Err(FsError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+        log::info!("Pinned the upload `/ipfs/{}` as {}", name, ipfs_path);
+        Ok(())
+    }
+
+    fn upload_root(&self, name: &str) -> String {
+        [&self.root, "/", name].concat()
+    }
+
+    fn to_upload_path(&self, path: &DavPath) -> Result<DavPath, FsError> {
+        let mut upload_path = self.root.clone();
+        upload_path += &path.as_url_string();
+        DavPath::new(&upload_path).map_err(|_| FsError::GeneralFailure)
+    }
+}
+
+/// Strips `/ipfs` from a full WebDAV path
+fn ipfs_relative(path: &DavPath) -> Option<DavPath> {
+    in_namespace(path, "/ipfs")
+}
+
+/// Extracts `<name>` from `/<name>/rest`. The boolean is `true` if there is
+/// no `rest`
+fn split_name(path: &DavPath) -> Result<Option<(&str, bool)>, FsError> {
+    let mut segments = map_path(path)?.split('/').filter(|s| !s.is_empty());
+    Ok(segments
+        .next()
+        .map(|name| (name, segments.next().is_none())))
+}
+
+/// A file of an upload open for writing. Schedules pinning when closed
+struct UploadDavFile {
+    inner: Box<dyn DavFile>,
+    uploads: Uploads,
+    name: String,
+}
+
+impl Debug for UploadDavFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("UploadDavFile")
+    }
+}
+
+impl Drop for UploadDavFile {
+    fn drop(&mut self) {
+        self.uploads.end_write(&self.name);
+    }
+}
+
+impl DavFile for UploadDavFile {
+    fn metadata<'a>(&'a mut self) -> FsFuture<Box<dyn DavMetaData>> {
+        self.inner.metadata()
+    }
+
+    fn write_buf<'a>(&'a mut self, buf: Box<dyn Buf + Send>) -> FsFuture<()> {
+        self.inner.write_buf(buf)
+    }
+
+    fn write_bytes(&mut self, buf: Bytes) -> FsFuture<()> {
+        self.inner.write_bytes(buf)
+    }
+
+    fn read_bytes(&mut self, count: usize) -> FsFuture<Bytes> {
+        self.inner.read_bytes(count)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<u64> {
+        self.inner.seek(pos)
+    }
+
+    fn flush(&mut self) -> FsFuture<()> {
+        self.inner.flush()
+    }
+}
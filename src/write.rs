@@ -1,9 +1,14 @@
+use crate::cache::MetadataCache;
+use crate::ipfs_api_ext::IpfsClientExt;
+use crate::ipfs_client::IpfsClient;
+use crate::metrics::METRICS;
+use crate::read::seek_position;
 use crate::{handle_error, StaticMetadata};
 use common_multipart_rfc7578::client::multipart;
 use futures::channel::mpsc::Sender;
 use futures::{future, FutureExt, SinkExt, TryStreamExt};
 use hyper::body::{Buf, Bytes};
-use ipfs_api_backend_hyper::{Error, IpfsApi, IpfsClient};
+use ipfs_api_backend_hyper::{Error, IpfsApi};
 use ipfs_api_prelude::request::FilesWrite;
 use ipfs_api_prelude::Backend;
 use std::fmt::{Debug, Formatter};
@@ -19,12 +24,25 @@ pub struct WriteOnlyDavFile {
     truncate: bool,
     stream: Option<Sender<io::Result<Bytes>>>,
     task: Option<JoinHandle<Result<(), Error>>>,
+    /// How many bytes are written
     len: u64,
-    seek: Option<i64>,
+    /// The offset of the next `write_bytes`
+    pos: u64,
+    flushed: bool,
+    cache: MetadataCache,
+    /// The key of the file in `cache`
+    cache_key: String,
 }
 
 impl WriteOnlyDavFile {
-    pub fn new(ipfs: IpfsClient, path: String, create: bool, truncate: bool) -> Self {
+    pub fn new(
+        ipfs: IpfsClient,
+        path: String,
+        create: bool,
+        truncate: bool,
+        cache: MetadataCache,
+        cache_key: String,
+    ) -> Self {
         WriteOnlyDavFile {
             ipfs,
             path,
@@ -33,7 +51,10 @@ impl WriteOnlyDavFile {
             stream: None,
             task: None,
             len: 0,
-            seek: None,
+            pos: 0,
+            flushed: false,
+            cache,
+            cache_key,
         }
     }
 }
@@ -46,9 +67,21 @@ impl Debug for WriteOnlyDavFile {
 
 impl DavFile for WriteOnlyDavFile {
     fn metadata<'a>(&'a mut self) -> FsFuture<Box<dyn DavMetaData>> {
-        Box::pin(future::ready(Ok(
-            Box::new(StaticMetadata::new_file(self.len)) as Box<dyn DavMetaData>,
-        )))
+        async move {
+            // After flushing, the file is stat'ed to report the new CID as the ETag
+            let metadata = if self.flushed {
+                let stat = self
+                    .ipfs
+                    .files_stat_full(&self.path)
+                    .await
+                    .map_err(handle_error)?;
+                StaticMetadata::from_files_stat_response(stat)
+            } else {
+                StaticMetadata::new_file(self.len)
+            };
+            Ok(Box::new(metadata) as Box<dyn DavMetaData>)
+        }
+        .boxed()
     }
 
     fn write_buf<'a>(&'a mut self, _: Box<dyn Buf + Send>) -> FsFuture<()> {
@@ -59,6 +92,8 @@ impl DavFile for WriteOnlyDavFile {
     fn write_bytes(&mut self, buf: Bytes) -> FsFuture<()> {
         async move {
             self.len += buf.len() as u64;
+            let write_pos = self.pos;
+            self.pos += buf.len() as u64;
             let stream = self.stream.get_or_insert_with(|| {
                 let (tx, rx) = futures::channel::mpsc::channel::<io::Result<Bytes>>(1);
                 let mut form = multipart::Form::default();
@@ -67,21 +102,24 @@ impl DavFile for WriteOnlyDavFile {
                 let path = self.path.clone();
                 let create = self.create;
                 let truncate = self.truncate;
-                let seek = self.seek.clone();
+                let offset = i64::try_from(write_pos).unwrap();
+                // Writes after `seek` must not truncate what is already written
+                self.truncate = false;
                 self.task = Some(tokio::spawn(async move {
                     let req = FilesWrite {
                         path: &path,
                         create: Some(create),
                         truncate: Some(truncate),
-                        offset: seek,
+                        offset: Some(offset),
                         ..Default::default()
                     };
                     ipfs.request_empty(req, Some(form)).await
                 }));
                 tx
             });
+            let len = buf.len();
             match stream.send(Ok(buf)).await {
-                Ok(_) => {}
+                Ok(_) => METRICS.on_write(len),
                 Err(e) => {
                     log::error!("A `write` aborted with error: {}", e);
                     return Err(FsError::GeneralFailure);
@@ -97,44 +135,82 @@ impl DavFile for WriteOnlyDavFile {
     }
 
     fn seek(&mut self, pos: SeekFrom) -> FsFuture<u64> {
-        // The library actually calls `seek` only once during PUT
-        assert!(self.seek.is_none());
-        let start = match pos {
-            SeekFrom::Start(start) => start,
-            _ => panic!("seek must be SeekFrom::Start"),
-        };
-        self.seek = Some(i64::try_from(start).unwrap());
-        Box::pin(future::ready(Ok(start)))
+        async move {
+            let len = match pos {
+                SeekFrom::End(_) => self.file_len().await?,
+                _ => 0,
+            };
+            let start = seek_position(pos, self.pos, len)?;
+            // A `files/write` can't jump, so the in-flight one is finished,
+            // and the next `write_bytes` starts a new one at `start`
+            if start != self.pos {
+                self.finish_stream().await?;
+                self.pos = start;
+            }
+            Ok(start)
+        }
+        .boxed()
     }
 
     fn flush(&mut self) -> FsFuture<()> {
         async move {
-            if self.stream.is_none() && self.len == 0 {
-                self.ipfs
-                    .files_write(&self.path, self.create, self.truncate, &[] as &[u8])
-                    .await
-                    .map_err(handle_error)?;
-            }
-            match self.stream.take() {
-                Some(mut s) => s.close().await.expect(
-                    "This error must not happen because \
-                    `Sender::close` seems infallible",
-                ),
-                None => {}
-            }
-            match self.task.take() {
-                Some(t) => t
-                    .await
-                    .map_err(handle_join_error)
-                    .and_then(|ok| ok.map_err(handle_error))?,
-                None => {}
-            }
+            let result = self.finish_write().await;
+            self.cache.invalidate(&self.cache_key);
+            result?;
+            self.flushed = true;
             Ok(())
         }
         .boxed()
     }
 }
 
+impl WriteOnlyDavFile {
+    async fn finish_write(&mut self) -> Result<(), FsError> {
+        if self.stream.is_none() && self.len == 0 {
+            self.ipfs
+                .files_write(&self.path, self.create, self.truncate, &[] as &[u8])
+                .await
+                .map_err(handle_error)?;
+        }
+        self.finish_stream().await
+    }
+
+    /// Waits for the in-flight `files/write` to complete
+    async fn finish_stream(&mut self) -> Result<(), FsError> {
+        match self.stream.take() {
+            Some(mut s) => s.close().await.expect(
+                "This error must not happen because \
+                `Sender::close` seems infallible",
+            ),
+            None => {}
+        }
+        match self.task.take() {
+            Some(t) => t
+                .await
+                .map_err(handle_join_error)
+                .and_then(|ok| ok.map_err(handle_error))?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// The current length of the file, including the bytes written so far
+    async fn file_len(&mut self) -> Result<u64, FsError> {
+        if self.truncate {
+            // Nothing is written yet, and the first write truncates the file
+            return Ok(0);
+        }
+        self.finish_stream().await?;
+        match self.ipfs.files_stat_full(&self.path).await {
+            Ok(stat) => Ok(stat.size),
+            Err(e) => match handle_error(e) {
+                FsError::NotFound => Ok(0),
+                e => Err(e),
+            },
+        }
+    }
+}
+
 fn handle_join_error(e: JoinError) -> FsError {
     if e.is_cancelled() {
         log::error!("A `write` task was canceled unexpectedly")